@@ -0,0 +1,305 @@
+// Just enough ACPI to find the interrupt controllers: the RSDP the firmware leaves in low
+// memory, the root table (RSDT or XSDT) it points to, and the MADT listing the local APICs,
+// I/O APICs and how the legacy ISA IRQs are wired to them.
+//
+// The tables are read straight from the physical memory mapping. Their fields are often
+// unaligned, so everything is decoded from byte slices instead of packed structs.
+
+use crate::memory::phys_to_virt;
+use core::slice;
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_LENGTH: usize = 20;
+const RSDP_V2_LENGTH: usize = 36;
+
+// The RSDP is in the first KiB of the Extended BIOS Data Area, whose segment is stored at
+// 0x40e, or in the BIOS area below 1 MiB. Either way it is aligned to 16 bytes.
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+const SDT_HEADER_LENGTH: usize = 36;
+
+pub const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+// local APIC address and flags precede the entries
+const MADT_ENTRIES_OFFSET: usize = 8;
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+// All ACPI structures are valid only if their bytes sum up to zero
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+// Physical memory is mapped as a whole and the firmware tables are never written, so handing
+// out shared slices of them is fine.
+fn physical_bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    unsafe { slice::from_raw_parts(phys_to_virt(addr).as_ptr(), len) }
+}
+
+fn find_rsdp_in(start: u64, end: u64) -> Option<PhysAddr> {
+    (start..end).step_by(16).map(PhysAddr::new).find(|&addr| {
+        let candidate = physical_bytes(addr, RSDP_V1_LENGTH);
+        &candidate[..8] == RSDP_SIGNATURE && checksum_ok(candidate)
+    })
+}
+
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(u16_at(
+        physical_bytes(PhysAddr::new(EBDA_SEGMENT_POINTER), 2),
+        0,
+    )) << 4;
+    let in_ebda = if ebda != 0 {
+        find_rsdp_in(ebda, ebda + 1024)
+    } else {
+        None
+    };
+    in_ebda.or_else(|| find_rsdp_in(BIOS_AREA_START, BIOS_AREA_END))
+}
+
+/// A System Description Table, i.e. a header followed by table specific data.
+#[derive(Clone, Copy)]
+pub struct Sdt {
+    bytes: &'static [u8],
+}
+
+impl Sdt {
+    // Maps the table at the given address, checking its length and checksum
+    fn at(addr: PhysAddr) -> Option<Sdt> {
+        let length = u32_at(physical_bytes(addr, SDT_HEADER_LENGTH), 4) as usize;
+        if length < SDT_HEADER_LENGTH {
+            return None;
+        }
+        let bytes = physical_bytes(addr, length);
+        if checksum_ok(bytes) {
+            Some(Sdt { bytes })
+        } else {
+            None
+        }
+    }
+
+    pub fn signature(&self) -> &'static [u8] {
+        &self.bytes[..4]
+    }
+
+    /// The table contents after the header.
+    pub fn data(&self) -> &'static [u8] {
+        &self.bytes[SDT_HEADER_LENGTH..]
+    }
+}
+
+// The XSDT, which holds 64 bit table pointers, exists since ACPI 2.0. Before that there is
+// only the RSDT with 32 bit pointers.
+fn root_table() -> Option<(Sdt, usize)> {
+    let rsdp_addr = find_rsdp()?;
+    let rsdp = physical_bytes(rsdp_addr, RSDP_V1_LENGTH);
+    if rsdp[15] >= 2 {
+        let rsdp = physical_bytes(rsdp_addr, RSDP_V2_LENGTH);
+        let xsdt = u64_at(rsdp, 24);
+        if checksum_ok(rsdp) && xsdt != 0 {
+            return Sdt::at(PhysAddr::new(xsdt)).map(|table| (table, 8));
+        }
+    }
+    Sdt::at(PhysAddr::new(u64::from(u32_at(rsdp, 16)))).map(|table| (table, 4))
+}
+
+/// Finds the table with the given signature, if the firmware provides it.
+pub fn find_table(signature: &[u8; 4]) -> Option<Sdt> {
+    let (root, pointer_size) = root_table()?;
+    root.data()
+        .chunks_exact(pointer_size)
+        .map(|pointer| match pointer_size {
+            8 => u64_at(pointer, 0),
+            _ => u64::from(u32_at(pointer, 0)),
+        })
+        .filter_map(|addr| Sdt::at(PhysAddr::new(addr)))
+        .find(|table| table.signature() == signature)
+}
+
+/// The Multiple APIC Description Table.
+#[derive(Clone, Copy)]
+pub struct Madt {
+    data: &'static [u8],
+}
+
+/// Polarity and trigger mode of an interrupt line, from the MPS INTI flags.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IntiFlags(u16);
+
+impl IntiFlags {
+    /// Whether the line is active low. "Conforms to the bus" means active high for ISA.
+    pub fn active_low(self) -> bool {
+        self.0 & 0b11 == 0b11
+    }
+
+    /// Whether the line is level triggered. "Conforms to the bus" means edge triggered for ISA.
+    pub fn level_triggered(self) -> bool {
+        (self.0 >> 2) & 0b11 == 0b11
+    }
+}
+
+/// One entry of the MADT.
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_uid: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: u32,
+        gsi_base: u32,
+    },
+    /// An ISA IRQ that isn't identity mapped to a global system interrupt, or that
+    /// has a non-standard polarity or trigger mode.
+    InterruptSourceOverride {
+        source: u8,
+        gsi: u32,
+        flags: IntiFlags,
+    },
+    /// The local APIC input the NMI is connected to. A `processor_uid` of 0xff means all.
+    LocalApicNmi {
+        processor_uid: u8,
+        flags: IntiFlags,
+        lint: u8,
+    },
+    LocalApicAddressOverride {
+        address: u64,
+    },
+    LocalX2Apic {
+        x2apic_id: u32,
+        flags: u32,
+        processor_uid: u32,
+    },
+    Unknown {
+        entry_type: u8,
+    },
+}
+
+impl Madt {
+    pub fn find() -> Option<Madt> {
+        let table = find_table(MADT_SIGNATURE)?;
+        if table.data().len() < MADT_ENTRIES_OFFSET {
+            return None;
+        }
+        Some(Madt { data: table.data() })
+    }
+
+    /// The physical address of the local APICs, unless an entry overrides it.
+    pub fn local_apic_address(&self) -> PhysAddr {
+        let address = self.entries().find_map(|entry| match entry {
+            MadtEntry::LocalApicAddressOverride { address } => Some(address),
+            _ => None,
+        });
+        PhysAddr::new(address.unwrap_or_else(|| u64::from(u32_at(self.data, 0))))
+    }
+
+    pub fn has_io_apic(&self) -> bool {
+        self.entries()
+            .any(|entry| matches!(entry, MadtEntry::IoApic { .. }))
+    }
+
+    pub fn entries(&self) -> MadtEntries {
+        MadtEntries {
+            data: &self.data[MADT_ENTRIES_OFFSET..],
+        }
+    }
+}
+
+/// Iterator over the entries of the MADT.
+pub struct MadtEntries {
+    data: &'static [u8],
+}
+
+impl Iterator for MadtEntries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        if self.data.len() < 2 {
+            return None;
+        }
+        let entry_type = self.data[0];
+        let length = usize::from(self.data[1]);
+        // a truncated or zero length entry means the table is broken, stop there
+        if length < 2 || length > self.data.len() {
+            self.data = &[];
+            return None;
+        }
+        let entry = &self.data[..length];
+        self.data = &self.data[length..];
+
+        let parsed = match (entry_type, length) {
+            (0, 8..) => MadtEntry::LocalApic {
+                processor_uid: entry[2],
+                apic_id: entry[3],
+                flags: u32_at(entry, 4),
+            },
+            (1, 12..) => MadtEntry::IoApic {
+                id: entry[2],
+                address: u32_at(entry, 4),
+                gsi_base: u32_at(entry, 8),
+            },
+            (2, 10..) => MadtEntry::InterruptSourceOverride {
+                source: entry[3],
+                gsi: u32_at(entry, 4),
+                flags: IntiFlags(u16_at(entry, 8)),
+            },
+            (4, 6..) => MadtEntry::LocalApicNmi {
+                processor_uid: entry[2],
+                flags: IntiFlags(u16_at(entry, 3)),
+                lint: entry[5],
+            },
+            (5, 12..) => MadtEntry::LocalApicAddressOverride {
+                address: u64_at(entry, 4),
+            },
+            (9, 16..) => MadtEntry::LocalX2Apic {
+                x2apic_id: u32_at(entry, 4),
+                flags: u32_at(entry, 8),
+                processor_uid: u32_at(entry, 12),
+            },
+            (entry_type, _) => MadtEntry::Unknown { entry_type },
+        };
+        Some(parsed)
+    }
+}
+
+#[test_case]
+fn test_inti_flags() {
+    assert!(!IntiFlags(0).active_low());
+    assert!(!IntiFlags(0).level_triggered());
+    assert!(IntiFlags(0b1111).active_low());
+    assert!(IntiFlags(0b1111).level_triggered());
+    assert!(!IntiFlags(0b0101).active_low());
+    assert!(!IntiFlags(0b0101).level_triggered());
+}
+
+#[test_case]
+fn test_madt_entries_stop_at_broken_entry() {
+    static DATA: [u8; 14] = [0, 8, 1, 2, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0];
+    let mut entries = MadtEntries { data: &DATA };
+    assert!(matches!(
+        entries.next(),
+        Some(MadtEntry::LocalApic {
+            processor_uid: 1,
+            apic_id: 2,
+            flags: 1
+        })
+    ));
+    assert!(entries.next().is_none());
+}
@@ -0,0 +1,81 @@
+// The simplest allocator there is: hand out memory from the front of the heap and never reuse
+// it, except that the whole heap starts over once every allocation has been freed. Fast and
+// predictable, but only suitable for allocations that die together.
+
+use super::{align_up, FreeMemory, HeapAllocator};
+use core::alloc::Layout;
+use core::ptr;
+
+pub struct BumpAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    next: usize,
+    allocations: usize,
+}
+
+impl BumpAllocator {
+    pub const fn new() -> BumpAllocator {
+        BumpAllocator {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+}
+
+impl Default for BumpAllocator {
+    fn default() -> BumpAllocator {
+        BumpAllocator::new()
+    }
+}
+
+impl HeapAllocator for BumpAllocator {
+    // A region right after the heap extends it. Anything else can only replace it, which
+    // gives up whatever was left of the old one.
+    unsafe fn add_region(&mut self, start: usize, size: usize) {
+        if start == self.heap_end && self.heap_end != 0 {
+            self.heap_end += size;
+        } else {
+            self.heap_start = start;
+            self.heap_end = start + size;
+            self.next = start;
+        }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let start = align_up(self.next, layout.align());
+        match start.checked_add(layout.size()) {
+            Some(end) if end <= self.heap_end => {
+                self.next = end;
+                self.allocations += 1;
+                start as *mut u8
+            }
+            _ => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, _ptr: *mut u8, _layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+
+    fn free_memory(&self) -> FreeMemory {
+        let rest = self.heap_end - self.next;
+        FreeMemory {
+            bytes: rest,
+            largest: rest,
+        }
+    }
+}
+
+#[test_case]
+fn test_bump_stress() {
+    super::stress_test(BumpAllocator::new(), |allocator, arena| {
+        // with everything freed the heap starts over
+        let layout = Layout::from_size_align(arena.len(), 1).unwrap();
+        assert_eq!(allocator.allocate(layout), arena.as_mut_ptr());
+    });
+}
@@ -0,0 +1,109 @@
+// Allocations up to 2 KiB are rounded up to one of a few block sizes, and every block size
+// keeps a list of freed blocks to hand out again. That makes most allocations a single list
+// operation. Blocks never go back to the linked list allocator behind it, which serves the
+// larger allocations and the blocks for lists that are empty.
+
+use super::linked_list::LinkedListAllocator;
+use super::{FreeMemory, HeapAllocator};
+use core::alloc::Layout;
+use core::ptr;
+
+// Block sizes are powers of two, so a block is also aligned to its size
+const BLOCK_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct BlockNode {
+    next: *mut BlockNode,
+}
+
+fn block_size_index(layout: Layout) -> Option<usize> {
+    let required = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&size| size >= required)
+}
+
+pub struct FixedSizeBlockAllocator {
+    heads: [*mut BlockNode; BLOCK_SIZES.len()],
+    fallback: LinkedListAllocator,
+}
+
+// The allocator owns the memory its nodes live in
+unsafe impl Send for FixedSizeBlockAllocator {}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> FixedSizeBlockAllocator {
+        FixedSizeBlockAllocator {
+            heads: [ptr::null_mut(); BLOCK_SIZES.len()],
+            fallback: LinkedListAllocator::new(),
+        }
+    }
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> FixedSizeBlockAllocator {
+        FixedSizeBlockAllocator::new()
+    }
+}
+
+impl HeapAllocator for FixedSizeBlockAllocator {
+    unsafe fn add_region(&mut self, start: usize, size: usize) {
+        unsafe { self.fallback.add_region(start, size) }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match block_size_index(layout) {
+            Some(index) => {
+                let head = self.heads[index];
+                if head.is_null() {
+                    let size = BLOCK_SIZES[index];
+                    let block = Layout::from_size_align(size, size).unwrap();
+                    self.fallback.allocate(block)
+                } else {
+                    // the block is free, so it holds the next node
+                    self.heads[index] = unsafe { (*head).next };
+                    head as *mut u8
+                }
+            }
+            None => self.fallback.allocate(layout),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match block_size_index(layout) {
+            Some(index) => {
+                let node = ptr as *mut BlockNode;
+                unsafe {
+                    node.write(BlockNode {
+                        next: self.heads[index],
+                    })
+                };
+                self.heads[index] = node;
+            }
+            None => unsafe { self.fallback.deallocate(ptr, layout) },
+        }
+    }
+
+    fn free_memory(&self) -> FreeMemory {
+        let mut free = self.fallback.free_memory();
+        for (&head, &size) in self.heads.iter().zip(BLOCK_SIZES.iter()) {
+            let mut block = head;
+            while !block.is_null() {
+                free = free.add(FreeMemory {
+                    bytes: size,
+                    largest: size,
+                });
+                block = unsafe { (*block).next };
+            }
+        }
+        free
+    }
+}
+
+#[test_case]
+fn test_fixed_size_block_stress() {
+    super::stress_test(FixedSizeBlockAllocator::new(), |allocator, _| {
+        // a freed block is the next one handed out for its size
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let block = allocator.allocate(layout);
+        unsafe { allocator.deallocate(block, layout) };
+        assert_eq!(allocator.allocate(layout), block);
+    });
+}
@@ -0,0 +1,219 @@
+// A first-fit allocator that keeps the free regions in a linked list, with the list nodes
+// stored in the free memory itself. The list is sorted by address, so a freed region can be
+// merged with its neighbours right away and the heap doesn't crumble into small pieces.
+
+use super::{align_up, FreeMemory, HeapAllocator};
+use core::alloc::Layout;
+use core::mem;
+use core::ptr;
+
+struct ListNode {
+    size: usize,
+    next: *mut ListNode,
+}
+
+const NODE_SIZE: usize = mem::size_of::<ListNode>();
+const NODE_ALIGN: usize = mem::align_of::<ListNode>();
+
+pub struct LinkedListAllocator {
+    head: *mut ListNode,
+}
+
+// The allocator owns the memory its nodes live in
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator {
+    pub const fn new() -> LinkedListAllocator {
+        LinkedListAllocator {
+            head: ptr::null_mut(),
+        }
+    }
+
+    // Every allocation has to be able to hold a node once it is freed again
+    fn adjust(layout: Layout) -> (usize, usize) {
+        let size = align_up(layout.size().max(NODE_SIZE), NODE_ALIGN);
+        (size, layout.align().max(NODE_ALIGN))
+    }
+
+    // Where an allocation would go in the given free region. The parts of the region before
+    // and after it have to be able to stay free regions of their own.
+    fn fit(region: *mut ListNode, region_size: usize, size: usize, align: usize) -> Option<usize> {
+        let region_start = region as usize;
+        let region_end = region_start + region_size;
+        let mut start = align_up(region_start, align);
+        if start != region_start && start - region_start < NODE_SIZE {
+            start = align_up(region_start + NODE_SIZE, align);
+        }
+        let end = start.checked_add(size)?;
+        if end > region_end || (end != region_end && region_end - end < NODE_SIZE) {
+            return None;
+        }
+        Some(start)
+    }
+
+    /// The number of free regions and the bytes they hold.
+    pub fn free_regions(&self) -> (usize, usize) {
+        let mut count = 0;
+        let mut bytes = 0;
+        let mut region = self.head;
+        while !region.is_null() {
+            unsafe {
+                count += 1;
+                bytes += (*region).size;
+                region = (*region).next;
+            }
+        }
+        (count, bytes)
+    }
+}
+
+impl Default for LinkedListAllocator {
+    fn default() -> LinkedListAllocator {
+        LinkedListAllocator::new()
+    }
+}
+
+impl HeapAllocator for LinkedListAllocator {
+    unsafe fn add_region(&mut self, start: usize, size: usize) {
+        // every free region has to hold a node, which the allocations are rounded up for
+        let aligned_start = align_up(start, NODE_ALIGN);
+        let size = (start + size).saturating_sub(aligned_start) & !(NODE_ALIGN - 1);
+        if size < NODE_SIZE {
+            return;
+        }
+        let start = aligned_start;
+
+        let mut prev: *mut ListNode = ptr::null_mut();
+        let mut next = self.head;
+        unsafe {
+            while !next.is_null() && (next as usize) < start {
+                prev = next;
+                next = (*next).next;
+            }
+
+            let mut size = size;
+            if !next.is_null() && start + size == next as usize {
+                size += (*next).size;
+                next = (*next).next;
+            }
+            if !prev.is_null() && prev as usize + (*prev).size == start {
+                (*prev).size += size;
+                (*prev).next = next;
+                return;
+            }
+
+            let node = start as *mut ListNode;
+            node.write(ListNode { size, next });
+            if prev.is_null() {
+                self.head = node;
+            } else {
+                (*prev).next = node;
+            }
+        }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::adjust(layout);
+        let mut prev: *mut ListNode = ptr::null_mut();
+        let mut region = self.head;
+        unsafe {
+            while !region.is_null() {
+                let region_size = (*region).size;
+                let next = (*region).next;
+                if let Some(start) = Self::fit(region, region_size, size, align) {
+                    if prev.is_null() {
+                        self.head = next;
+                    } else {
+                        (*prev).next = next;
+                    }
+                    // hand back what is left on either side
+                    let region_start = region as usize;
+                    let region_end = region_start + region_size;
+                    if start != region_start {
+                        self.add_region(region_start, start - region_start);
+                    }
+                    if start + size != region_end {
+                        self.add_region(start + size, region_end - (start + size));
+                    }
+                    return start as *mut u8;
+                }
+                prev = region;
+                region = next;
+            }
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::adjust(layout);
+        unsafe { self.add_region(ptr as usize, size) }
+    }
+
+    fn free_memory(&self) -> FreeMemory {
+        let mut free = FreeMemory::default();
+        let mut region = self.head;
+        while !region.is_null() {
+            unsafe {
+                free = free.add(FreeMemory {
+                    bytes: (*region).size,
+                    largest: (*region).size,
+                });
+                region = (*region).next;
+            }
+        }
+        free
+    }
+}
+
+#[test_case]
+fn test_free_regions_coalesce() {
+    #[repr(align(4096))]
+    struct Memory([u8; 4096]);
+    let mut memory = Memory([0; 4096]);
+
+    let mut allocator = LinkedListAllocator::new();
+    unsafe { allocator.add_region(memory.0.as_mut_ptr() as usize, 4096) };
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    let a = allocator.allocate(layout);
+    let b = allocator.allocate(layout);
+    let c = allocator.allocate(layout);
+    assert!(!a.is_null() && !b.is_null() && !c.is_null());
+
+    // freeing the middle one can't merge with anything yet
+    unsafe { allocator.deallocate(b, layout) };
+    assert_eq!(allocator.free_regions().0, 2);
+    unsafe {
+        allocator.deallocate(a, layout);
+        allocator.deallocate(c, layout);
+    }
+    assert_eq!(allocator.free_regions(), (1, 4096));
+}
+
+#[test_case]
+fn test_alignment_and_exhaustion() {
+    #[repr(align(4096))]
+    struct Memory([u8; 4096]);
+    let mut memory = Memory([0; 4096]);
+
+    let mut allocator = LinkedListAllocator::new();
+    unsafe { allocator.add_region(memory.0.as_mut_ptr() as usize, 4096) };
+    let small = allocator.allocate(Layout::from_size_align(8, 8).unwrap());
+    let aligned = allocator.allocate(Layout::from_size_align(64, 1024).unwrap());
+    assert_eq!(aligned as usize % 1024, 0);
+    assert!(allocator
+        .allocate(Layout::from_size_align(4096, 8).unwrap())
+        .is_null());
+    unsafe {
+        allocator.deallocate(small, Layout::from_size_align(8, 8).unwrap());
+        allocator.deallocate(aligned, Layout::from_size_align(64, 1024).unwrap());
+    }
+    assert_eq!(allocator.free_regions(), (1, 4096));
+}
+
+#[test_case]
+fn test_linked_list_stress() {
+    super::stress_test(LinkedListAllocator::new(), |allocator, arena| {
+        // everything freed merges back into the region we started with
+        assert_eq!(allocator.free_regions(), (1, arena.len()));
+    });
+}
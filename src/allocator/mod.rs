@@ -0,0 +1,459 @@
+// The kernel heap, which backs `Box`, `Vec` and the rest of the `alloc` crate.
+//
+// The heap is a range of virtual memory that gets mapped to frames from the frame allocator,
+// a part of it at boot and more whenever the allocator runs out. The allocator handing out
+// pieces of it sits behind a spinlock, since `GlobalAlloc` only gets a shared reference.
+//
+// There are several allocator designs to pick from with cargo features, see `Cargo.toml`.
+// All of them are always built and tested, the feature only decides which one is the heap.
+
+pub mod bump;
+pub mod fixed_size_block;
+pub mod linked_list;
+pub mod slab;
+
+use crate::memory::frame::frame_allocator;
+use crate::memory::paging::{kernel_page_tables, MapError};
+use crate::sync::{SpinLock, SpinLockGuard};
+use crate::{serial_print, serial_println};
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Where the heap starts. Far away from everything the bootloader maps, so it's easy to
+/// recognize in page fault addresses.
+pub const HEAP_START: u64 = 0x4444_4444_0000;
+/// How much of the heap is mapped at boot.
+pub const HEAP_SIZE: u64 = 1024 * 1024;
+/// How far the heap may grow.
+pub const HEAP_MAX_SIZE: u64 = 64 * 1024 * 1024;
+// The least the heap grows by, so that a series of small allocations doesn't map page by page
+const HEAP_GROWTH: u64 = 64 * 1024;
+const PAGE_SIZE: u64 = 4096;
+
+// With more than one feature enabled only the first `Heap` is defined, so that the
+// `compile_error!` below is the only error
+#[cfg(feature = "bump-allocator")]
+type Heap = bump::BumpAllocator;
+#[cfg(all(feature = "linked-list-allocator", not(feature = "bump-allocator")))]
+type Heap = linked_list::LinkedListAllocator;
+#[cfg(all(
+    feature = "fixed-size-block-allocator",
+    not(any(feature = "bump-allocator", feature = "linked-list-allocator"))
+))]
+type Heap = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(all(
+    feature = "slab-allocator",
+    not(any(
+        feature = "bump-allocator",
+        feature = "linked-list-allocator",
+        feature = "fixed-size-block-allocator"
+    ))
+))]
+type Heap = slab::SlabAllocator;
+
+#[cfg(not(any(
+    feature = "bump-allocator",
+    feature = "linked-list-allocator",
+    feature = "fixed-size-block-allocator",
+    feature = "slab-allocator"
+)))]
+compile_error!("exactly one allocator feature has to be enabled, but none is");
+
+// Features are additive, so enabling another one doesn't switch away from the default
+#[cfg(any(
+    all(feature = "bump-allocator", feature = "linked-list-allocator"),
+    all(feature = "bump-allocator", feature = "fixed-size-block-allocator"),
+    all(feature = "bump-allocator", feature = "slab-allocator"),
+    all(
+        feature = "linked-list-allocator",
+        feature = "fixed-size-block-allocator"
+    ),
+    all(feature = "linked-list-allocator", feature = "slab-allocator"),
+    all(feature = "fixed-size-block-allocator", feature = "slab-allocator")
+))]
+compile_error!(
+    "exactly one allocator feature has to be enabled, use `--no-default-features` when \
+     picking one other than the default"
+);
+
+/// The free memory an allocator holds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FreeMemory {
+    pub bytes: usize,
+    /// The largest allocation that could still succeed, ignoring alignment.
+    pub largest: usize,
+}
+
+impl FreeMemory {
+    fn add(self, other: FreeMemory) -> FreeMemory {
+        FreeMemory {
+            bytes: self.bytes + other.bytes,
+            largest: self.largest.max(other.largest),
+        }
+    }
+}
+
+/// A heap allocator design. Allocators get their memory handed to them, they don't map it.
+pub trait HeapAllocator: Send {
+    /// Hands the memory from `start` to `start + size` to the allocator.
+    ///
+    /// # Safety
+    ///
+    /// The memory must be unused and stay valid for as long as the allocator is used.
+    unsafe fn add_region(&mut self, start: usize, size: usize);
+
+    /// Returns a null pointer if the allocator is out of memory.
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    /// # Safety
+    ///
+    /// The pointer must come from `allocate` on this allocator, with the same layout.
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+
+    fn free_memory(&self) -> FreeMemory;
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Wraps an allocator in a spinlock, so it can implement `GlobalAlloc`.
+///
+/// Interrupts stay disabled while the lock is held, like with every `SpinLock`. Otherwise a
+/// thread could be preempted holding it, and code that allocates with interrupts disabled,
+/// like the scheduler, would spin forever.
+pub struct Locked<A> {
+    inner: SpinLock<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Locked<A> {
+        Locked {
+            // the lock order checks run inside the allocator
+            inner: SpinLock::new_unchecked(inner),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+/// Allocations are counted by size, in powers of two from 8 bytes to 4 KiB and then everything
+/// larger.
+pub const SIZE_CLASSES: usize = 11;
+
+fn size_class(size: usize) -> usize {
+    (size.max(8).next_power_of_two().trailing_zeros() as usize - 3).min(SIZE_CLASSES - 1)
+}
+
+/// The allocations of one size class.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SizeClassUsage {
+    /// Allocations that haven't been freed yet, and the bytes they asked for.
+    pub live: usize,
+    pub live_bytes: usize,
+    /// All allocations ever made.
+    pub total: usize,
+}
+
+/// A snapshot of the kernel heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The mapped part of the heap.
+    pub size: usize,
+    /// Bytes asked for by live allocations. The allocator needs some more for rounding and
+    /// its own bookkeeping.
+    pub used: usize,
+    pub allocations: usize,
+    pub free: FreeMemory,
+    pub failures: usize,
+}
+
+impl HeapStats {
+    /// How much of the free memory is unusable for an allocation of all of it, in percent.
+    pub fn fragmentation(&self) -> usize {
+        match self.free.bytes {
+            0 => 0,
+            free => 100 - self.free.largest * 100 / free,
+        }
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} KiB mapped, {} bytes used in {} allocations, {} bytes free, largest free block \
+             {} bytes, {}% fragmented, {} failed allocations",
+            self.size / 1024,
+            self.used,
+            self.allocations,
+            self.free.bytes,
+            self.free.largest,
+            self.fragmentation(),
+            self.failures
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrowError {
+    /// The heap already has its maximum size.
+    LimitReached,
+    Map(MapError),
+}
+
+impl From<MapError> for GrowError {
+    fn from(error: MapError) -> GrowError {
+        GrowError::Map(error)
+    }
+}
+
+/// The heap allocator along with the heap it manages. Maps more of the heap when the
+/// allocator runs out, and keeps statistics.
+pub struct KernelHeap<A> {
+    allocator: A,
+    end: u64,
+    size_classes: [SizeClassUsage; SIZE_CLASSES],
+    failures: usize,
+}
+
+impl<A: HeapAllocator> KernelHeap<A> {
+    pub const fn new(allocator: A) -> KernelHeap<A> {
+        KernelHeap {
+            allocator,
+            end: HEAP_START,
+            size_classes: [SizeClassUsage {
+                live: 0,
+                live_bytes: 0,
+                total: 0,
+            }; SIZE_CLASSES],
+            failures: 0,
+        }
+    }
+
+    /// Maps at least `bytes` more of the heap and hands them to the allocator.
+    ///
+    /// Takes the page table and frame allocator locks, so nothing holding those may allocate.
+    pub fn grow(&mut self, bytes: u64) -> Result<(), GrowError> {
+        let limit = HEAP_START + HEAP_MAX_SIZE;
+        let start = self.end;
+        let end = (start + bytes.next_multiple_of(PAGE_SIZE)).min(limit);
+        if end == start {
+            return Err(GrowError::LimitReached);
+        }
+
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let mut tables = kernel_page_tables();
+        let mut frames = frame_allocator();
+        let mut result = Ok(());
+        for addr in (start..end).step_by(PAGE_SIZE as usize) {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+            let mapped = match frames.allocate() {
+                // nothing else uses the heap range or the fresh frame
+                Some(frame) => unsafe { tables.map(page, frame, flags, &mut *frames) },
+                None => Err(MapError::FrameAllocationFailed),
+            };
+            if let Err(error) = mapped {
+                result = Err(error.into());
+                break;
+            }
+            self.end = addr + PAGE_SIZE;
+        }
+
+        // whatever got mapped is usable, even if not all of it could be
+        if self.end > start {
+            unsafe {
+                self.allocator
+                    .add_region(start as usize, (self.end - start) as usize)
+            };
+        }
+        result
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let mut ptr = self.allocator.allocate(layout);
+        if ptr.is_null() {
+            // room for the allocation wherever the alignment puts it, and for the allocator's
+            // bookkeeping around it
+            let needed = (layout.size() + layout.align()) as u64 + PAGE_SIZE;
+            if self.grow(needed.max(HEAP_GROWTH)).is_ok() {
+                ptr = self.allocator.allocate(layout);
+            }
+        }
+        if ptr.is_null() {
+            self.failures += 1;
+        } else {
+            let class = &mut self.size_classes[size_class(layout.size())];
+            class.live += 1;
+            class.live_bytes += layout.size();
+            class.total += 1;
+        }
+        ptr
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.allocator.deallocate(ptr, layout) };
+        let class = &mut self.size_classes[size_class(layout.size())];
+        class.live -= 1;
+        class.live_bytes -= layout.size();
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            size: (self.end - HEAP_START) as usize,
+            used: self.size_classes.iter().map(|class| class.live_bytes).sum(),
+            allocations: self.size_classes.iter().map(|class| class.live).sum(),
+            free: self.allocator.free_memory(),
+            failures: self.failures,
+        }
+    }
+
+    pub fn size_classes(&self) -> [SizeClassUsage; SIZE_CLASSES] {
+        self.size_classes
+    }
+}
+
+unsafe impl<A: HeapAllocator> GlobalAlloc for Locked<KernelHeap<A>> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.lock().deallocate(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Locked<KernelHeap<Heap>> = Locked::new(KernelHeap::new(Heap::new()));
+
+/// Maps the first part of the heap.
+pub fn init_heap() -> Result<(), GrowError> {
+    ALLOCATOR.lock().grow(HEAP_SIZE)
+}
+
+pub fn stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
+pub fn size_classes() -> [SizeClassUsage; SIZE_CLASSES] {
+    ALLOCATOR.lock().size_classes()
+}
+
+/// Prints the heap statistics and the allocations by size class over serial.
+pub fn dump() {
+    let (stats, size_classes) = {
+        let heap = ALLOCATOR.lock();
+        (heap.stats(), heap.size_classes())
+    };
+    serial_println!("heap: {}", stats);
+    for (class, usage) in size_classes.iter().enumerate() {
+        let size = 8 << class;
+        if class == SIZE_CLASSES - 1 {
+            serial_print!("  >  {:>4} B:", size / 2);
+        } else {
+            serial_print!("  <= {:>4} B:", size);
+        }
+        serial_println!(
+            " {} live ({} bytes), {} total",
+            usage.live,
+            usage.live_bytes,
+            usage.total
+        );
+    }
+}
+
+// Allocations from the `alloc` crate that can't handle failure end up here
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!(
+        "heap allocation of {} bytes with alignment {} failed\nheap: {}",
+        layout.size(),
+        layout.align(),
+        stats()
+    );
+}
+
+// Allocates and frees random sizes and alignments on an allocator of its own, checking that
+// no allocation overlaps another one, then hands the allocator and its memory to `check`
+#[cfg(test)]
+fn stress_test<A: HeapAllocator>(mut allocator: A, check: impl FnOnce(&mut A, &mut [u8])) {
+    const ARENA_SIZE: usize = 64 * 1024;
+    const SLOTS: usize = 64;
+    const ROUNDS: u32 = 10_000;
+
+    // the arena comes from the kernel heap, aligned well enough for any allocation here
+    let mut arena = alloc::vec![0u64; ARENA_SIZE / 8];
+    let arena =
+        unsafe { core::slice::from_raw_parts_mut(arena.as_mut_ptr() as *mut u8, ARENA_SIZE) };
+    unsafe { allocator.add_region(arena.as_mut_ptr() as usize, ARENA_SIZE) };
+
+    let mut live: [Option<(*mut u8, Layout, u8)>; SLOTS] = [None; SLOTS];
+    let mut random = 0x2545_f491_4f6c_dd1d_u64;
+    let mut successes = 0;
+    for round in 0..ROUNDS {
+        // xorshift is plenty random for picking sizes
+        random ^= random << 13;
+        random ^= random >> 7;
+        random ^= random << 17;
+        let slot = &mut live[random as usize % SLOTS];
+        match slot.take() {
+            Some((ptr, layout, pattern)) => unsafe {
+                let bytes = core::slice::from_raw_parts(ptr, layout.size());
+                assert!(
+                    bytes.iter().all(|&byte| byte == pattern),
+                    "allocation overwritten"
+                );
+                allocator.deallocate(ptr, layout);
+            },
+            None => {
+                let size = 1 + (random >> 16) as usize % 512;
+                let align = 1 << ((random >> 32) % 7);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = allocator.allocate(layout);
+                // running out is fine, some designs only reuse memory in special cases
+                if ptr.is_null() {
+                    continue;
+                }
+                assert_eq!(ptr as usize % align, 0);
+                let pattern = round as u8;
+                unsafe { core::ptr::write_bytes(ptr, pattern, size) };
+                *slot = Some((ptr, layout, pattern));
+                successes += 1;
+            }
+        }
+    }
+    assert!(successes > 0);
+    for (ptr, layout, _) in live.iter().flatten() {
+        unsafe { allocator.deallocate(*ptr, *layout) };
+    }
+    check(&mut allocator, arena);
+}
+
+#[test_case]
+fn test_heap_grows() {
+    let before = stats();
+    // can't fit into what is mapped, however the allocator splits it up
+    let vec = alloc::vec![1u8; before.size];
+    assert!(stats().size > before.size);
+    assert_eq!(
+        vec.iter().map(|&byte| byte as usize).sum::<usize>(),
+        before.size
+    );
+}
+
+#[test_case]
+fn test_size_class_accounting() {
+    let class = size_class(100);
+    let before = size_classes()[class];
+    let boxed = alloc::boxed::Box::new([0u8; 100]);
+    let during = size_classes()[class];
+    assert_eq!(during.live, before.live + 1);
+    assert_eq!(during.live_bytes, before.live_bytes + 100);
+    assert_eq!(during.total, before.total + 1);
+    drop(boxed);
+    assert_eq!(size_classes()[class].live, before.live);
+}
@@ -0,0 +1,209 @@
+// A slab allocator: every size class carves page sized slabs into equal objects. The slab
+// header sits at the start of the page, so freeing finds it by rounding the pointer down.
+// Unlike the fixed-size blocks, memory goes back to the linked list allocator behind it once
+// a slab is completely free, so a burst of small allocations doesn't pin memory forever.
+
+use super::linked_list::LinkedListAllocator;
+use super::{align_up, FreeMemory, HeapAllocator};
+use core::alloc::Layout;
+use core::mem;
+use core::ptr;
+
+const SLAB_SIZE: usize = 4096;
+// Object sizes are powers of two, so objects are aligned to their size. Larger allocations
+// would leave too few objects per slab, they go to the fallback directly.
+const OBJECT_SIZES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct Slab {
+    // the next slab of the same size class with free objects
+    next: *mut Slab,
+    free: *mut FreeObject,
+    free_count: usize,
+    capacity: usize,
+}
+
+fn size_class(layout: Layout) -> Option<usize> {
+    let required = layout.size().max(layout.align());
+    OBJECT_SIZES.iter().position(|&size| size >= required)
+}
+
+fn slab_layout() -> Layout {
+    Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+}
+
+pub struct SlabAllocator {
+    // the slabs with free objects, per size class
+    partial: [*mut Slab; OBJECT_SIZES.len()],
+    fallback: LinkedListAllocator,
+}
+
+// The allocator owns the memory its slabs live in
+unsafe impl Send for SlabAllocator {}
+
+impl SlabAllocator {
+    pub const fn new() -> SlabAllocator {
+        SlabAllocator {
+            partial: [ptr::null_mut(); OBJECT_SIZES.len()],
+            fallback: LinkedListAllocator::new(),
+        }
+    }
+
+    // Takes a page from the fallback and threads all its objects into the free list
+    fn new_slab(&mut self, class: usize) -> *mut Slab {
+        let slab = self.fallback.allocate(slab_layout()) as *mut Slab;
+        if slab.is_null() {
+            return slab;
+        }
+        let size = OBJECT_SIZES[class];
+        let first = align_up(mem::size_of::<Slab>(), size);
+        let capacity = (SLAB_SIZE - first) / size;
+        let mut free = ptr::null_mut();
+        // build the list backwards, so objects are handed out in address order
+        for index in (0..capacity).rev() {
+            let object = (slab as usize + first + index * size) as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free }) };
+            free = object;
+        }
+        unsafe {
+            slab.write(Slab {
+                next: ptr::null_mut(),
+                free,
+                free_count: capacity,
+                capacity,
+            })
+        };
+        slab
+    }
+
+    // Unlinks a slab from the list of partial slabs of its class
+    unsafe fn remove_partial(&mut self, class: usize, slab: *mut Slab) {
+        let mut link: *mut *mut Slab = &mut self.partial[class];
+        unsafe {
+            while !(*link).is_null() {
+                if *link == slab {
+                    *link = (*slab).next;
+                    return;
+                }
+                link = &mut (**link).next;
+            }
+        }
+    }
+}
+
+impl Default for SlabAllocator {
+    fn default() -> SlabAllocator {
+        SlabAllocator::new()
+    }
+}
+
+impl HeapAllocator for SlabAllocator {
+    unsafe fn add_region(&mut self, start: usize, size: usize) {
+        unsafe { self.fallback.add_region(start, size) }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let class = match size_class(layout) {
+            Some(class) => class,
+            None => return self.fallback.allocate(layout),
+        };
+        let mut slab = self.partial[class];
+        if slab.is_null() {
+            slab = self.new_slab(class);
+            if slab.is_null() {
+                return ptr::null_mut();
+            }
+            self.partial[class] = slab;
+        }
+        unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).free_count -= 1;
+            // a full slab is found again through the pointers into it
+            if (*slab).free_count == 0 {
+                self.partial[class] = (*slab).next;
+                (*slab).next = ptr::null_mut();
+            }
+            object as *mut u8
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let class = match size_class(layout) {
+            Some(class) => class,
+            None => return unsafe { self.fallback.deallocate(ptr, layout) },
+        };
+        let slab = (ptr as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+        unsafe {
+            let object = ptr as *mut FreeObject;
+            object.write(FreeObject { next: (*slab).free });
+            (*slab).free = object;
+            (*slab).free_count += 1;
+
+            if (*slab).free_count == 1 {
+                (*slab).next = self.partial[class];
+                self.partial[class] = slab;
+            } else if (*slab).free_count == (*slab).capacity
+                && !(self.partial[class] == slab && (*slab).next.is_null())
+            {
+                // keep the last slab of a class around, so a single object being allocated
+                // and freed over and over doesn't take a page from the fallback every time
+                self.remove_partial(class, slab);
+                self.fallback.deallocate(slab as *mut u8, slab_layout());
+            }
+        }
+    }
+
+    // Free objects only serve their own size class, but they are free memory all the same
+    fn free_memory(&self) -> FreeMemory {
+        let mut free = self.fallback.free_memory();
+        for (&head, &size) in self.partial.iter().zip(OBJECT_SIZES.iter()) {
+            let mut slab = head;
+            while !slab.is_null() {
+                unsafe {
+                    free = free.add(FreeMemory {
+                        bytes: (*slab).free_count * size,
+                        largest: size,
+                    });
+                    slab = (*slab).next;
+                }
+            }
+        }
+        free
+    }
+}
+
+#[test_case]
+fn test_slab_stress() {
+    super::stress_test(SlabAllocator::new(), |allocator, _| {
+        let layout = Layout::from_size_align(100, 4).unwrap();
+        let object = allocator.allocate(layout);
+        assert_eq!(object as usize % 128, 0);
+        unsafe { allocator.deallocate(object, layout) };
+    });
+}
+
+#[test_case]
+fn test_empty_slabs_go_back() {
+    let mut arena = alloc::vec![0u8; 4 * SLAB_SIZE];
+    let mut allocator = SlabAllocator::new();
+    unsafe { allocator.add_region(arena.as_mut_ptr() as usize, arena.len()) };
+    let (_, free_before) = allocator.fallback.free_regions();
+
+    // more objects than fit into one slab, then free them all again
+    let layout = Layout::from_size_align(512, 8).unwrap();
+    let mut objects = [ptr::null_mut(); 12];
+    for object in objects.iter_mut() {
+        *object = allocator.allocate(layout);
+        assert!(!object.is_null());
+    }
+    for &object in objects.iter() {
+        unsafe { allocator.deallocate(object, layout) };
+    }
+    // only the one slab kept for the class is still taken
+    let (_, free_after) = allocator.fallback.free_regions();
+    assert_eq!(free_after, free_before - SLAB_SIZE);
+}
@@ -0,0 +1,381 @@
+// Local APIC and I/O APIC support. The local APIC receives the interrupts of its CPU and has
+// a built-in timer, the I/O APICs collect the external interrupt lines and route them to the
+// local APICs. Where they sit and how the ISA IRQs are wired to them comes from the ACPI MADT.
+//
+// The local APIC is used in x2APIC mode, where its registers are MSRs, if the CPU supports
+// it, and in xAPIC mode through its memory mapped registers otherwise.
+
+use crate::acpi::{IntiFlags, Madt, MadtEntry};
+use crate::memory::mmio;
+use crate::{pit, time};
+use core::arch::x86_64::__cpuid;
+use spin::{Mutex, Once};
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Vector of the local APIC timer.
+pub const TIMER_VECTOR: u8 = 48;
+/// Vector the local APIC delivers spurious interrupts on. Its low four bits must be set.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const CPUID_FEATURE_EDX_APIC: u32 = 1 << 9;
+const CPUID_FEATURE_ECX_X2APIC: u32 = 1 << 21;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// In x2APIC mode the register at memory offset `n` is MSR 0x800 + n / 16
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// The local APIC's registers take up one page
+const LOCAL_APIC_MMIO_SIZE: u64 = 4096;
+
+// Local APIC register offsets
+const REG_ID: u32 = 0x20;
+const REG_TASK_PRIORITY: u32 = 0x80;
+const REG_END_OF_INTERRUPT: u32 = 0xb0;
+const REG_SPURIOUS_VECTOR: u32 = 0xf0;
+const REG_ERROR_STATUS: u32 = 0x280;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_LVT_LINT0: u32 = 0x350;
+const REG_LVT_LINT1: u32 = 0x360;
+const REG_LVT_ERROR: u32 = 0x370;
+const REG_TIMER_INITIAL_COUNT: u32 = 0x380;
+const REG_TIMER_CURRENT_COUNT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3e0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL_TRIGGERED: u32 = 1 << 15;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// How long the APIC timer is measured against the PIT
+const CALIBRATION_MS: u32 = 10;
+
+/// Which local APIC interface the CPU offers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicSupport {
+    None,
+    XApic,
+    X2Apic,
+}
+
+/// Checks CPUID for a local APIC.
+pub fn detect() -> ApicSupport {
+    // leaf 1 exists on every x86_64 CPU
+    let features = __cpuid(1);
+    if features.ecx & CPUID_FEATURE_ECX_X2APIC != 0 {
+        ApicSupport::X2Apic
+    } else if features.edx & CPUID_FEATURE_EDX_APIC != 0 {
+        ApicSupport::XApic
+    } else {
+        ApicSupport::None
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Registers {
+    Mmio(VirtAddr),
+    Msr,
+}
+
+/// The local APIC of the current CPU.
+#[derive(Debug)]
+pub struct LocalApic {
+    registers: Registers,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, reg: u32) -> u32 {
+        match self.registers {
+            Registers::Mmio(base) => (base + u64::from(reg)).as_ptr::<u32>().read_volatile(),
+            Registers::Msr => Msr::new(X2APIC_MSR_BASE + (reg >> 4)).read() as u32,
+        }
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        match self.registers {
+            Registers::Mmio(base) => (base + u64::from(reg))
+                .as_mut_ptr::<u32>()
+                .write_volatile(value),
+            Registers::Msr => Msr::new(X2APIC_MSR_BASE + (reg >> 4)).write(u64::from(value)),
+        }
+    }
+
+    pub fn is_x2apic(&self) -> bool {
+        matches!(self.registers, Registers::Msr)
+    }
+
+    pub fn id(&self) -> u32 {
+        let id = unsafe { self.read(REG_ID) };
+        match self.registers {
+            // the xAPIC ID is only the top byte
+            Registers::Mmio(_) => id >> 24,
+            Registers::Msr => id,
+        }
+    }
+
+    /// Signals the end of the current interrupt.
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.write(REG_END_OF_INTERRUPT, 0) }
+    }
+
+    // Software enables the APIC, takes over LINT0/LINT1 from the firmware's virtual wire
+    // setup and accepts all interrupt priorities
+    unsafe fn enable(&self, madt: &Madt) {
+        self.write(
+            REG_SPURIOUS_VECTOR,
+            SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+        self.write(REG_TASK_PRIORITY, 0);
+
+        // LINT0 carries the 8259's interrupts, which are masked from now on
+        self.write(REG_LVT_LINT0, LVT_MASKED);
+        self.write(REG_LVT_LINT1, LVT_MASKED);
+        let processor_uid = self.processor_uid(madt);
+        for entry in madt.entries() {
+            if let MadtEntry::LocalApicNmi {
+                processor_uid: uid,
+                flags,
+                lint,
+            } = entry
+            {
+                if uid == 0xff || Some(uid) == processor_uid {
+                    let polarity = if flags.active_low() {
+                        LVT_ACTIVE_LOW
+                    } else {
+                        0
+                    };
+                    let reg = if lint == 0 {
+                        REG_LVT_LINT0
+                    } else {
+                        REG_LVT_LINT1
+                    };
+                    self.write(reg, LVT_DELIVERY_NMI | polarity);
+                }
+            }
+        }
+
+        // errors are not reported through an interrupt, clear any that are pending
+        self.write(REG_LVT_ERROR, LVT_MASKED);
+        self.write(REG_ERROR_STATUS, 0);
+        self.end_of_interrupt();
+    }
+
+    // The ACPI processor UID of this CPU, which the MADT NMI entries refer to
+    fn processor_uid(&self, madt: &Madt) -> Option<u8> {
+        let id = self.id();
+        madt.entries().find_map(|entry| match entry {
+            MadtEntry::LocalApic {
+                processor_uid,
+                apic_id,
+                ..
+            } if u32::from(apic_id) == id => Some(processor_uid),
+            _ => None,
+        })
+    }
+
+    // Counts down from the maximum for a while and returns the timer ticks per millisecond
+    unsafe fn calibrate_timer(&self) -> u32 {
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_TIMER_INITIAL_COUNT, u32::MAX);
+        pit::poll_wait_ms(CALIBRATION_MS);
+        let elapsed = u32::MAX - self.read(REG_TIMER_CURRENT_COUNT);
+        self.write(REG_TIMER_INITIAL_COUNT, 0);
+        elapsed / CALIBRATION_MS
+    }
+
+    unsafe fn start_periodic_timer(&self, ticks_per_ms: u32) {
+        let initial_count = ticks_per_ms * 1000 / time::TIMER_FREQUENCY_HZ;
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | u32::from(TIMER_VECTOR));
+        self.write(REG_TIMER_INITIAL_COUNT, initial_count.max(1));
+    }
+}
+
+// I/O APIC registers, accessed indirectly through a select and a window register
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+const IOAPIC_REG_VERSION: u32 = 0x01;
+const IOAPIC_REG_REDIRECTION_TABLE: u32 = 0x10;
+
+const MAX_IO_APICS: usize = 8;
+const ISA_IRQ_COUNT: usize = 16;
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+
+impl IoApic {
+    unsafe fn new(address: PhysAddr, gsi_base: u32) -> IoApic {
+        let mut io_apic = IoApic {
+            base: mmio::map(address, IOWIN + 4).expect("failed to map an I/O APIC"),
+            gsi_base,
+            redirection_entries: 0,
+        };
+        io_apic.redirection_entries = ((io_apic.read(IOAPIC_REG_VERSION) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        (self.base + IOREGSEL)
+            .as_mut_ptr::<u32>()
+            .write_volatile(reg);
+        (self.base + IOWIN).as_ptr::<u32>().read_volatile()
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        (self.base + IOREGSEL)
+            .as_mut_ptr::<u32>()
+            .write_volatile(reg);
+        (self.base + IOWIN)
+            .as_mut_ptr::<u32>()
+            .write_volatile(value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.redirection_entries).contains(&gsi)
+    }
+
+    unsafe fn write_redirection(&self, gsi: u32, low: u32, high: u32) {
+        let reg = IOAPIC_REG_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        // mask the entry while it's half written
+        self.write(reg, LVT_MASKED);
+        self.write(reg + 1, high);
+        self.write(reg, low);
+    }
+
+    unsafe fn mask_all(&self) {
+        for gsi in self.gsi_base..self.gsi_base + self.redirection_entries {
+            self.write_redirection(gsi, LVT_MASKED, 0);
+        }
+    }
+}
+
+// Where an ISA IRQ ends up. Without an override it's the global system interrupt with the same
+// number, active high and edge triggered.
+#[derive(Clone, Copy)]
+struct IsaRoute {
+    gsi: u32,
+    flags: IntiFlags,
+}
+
+struct IoApics {
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    isa_routes: [Option<IsaRoute>; ISA_IRQ_COUNT],
+}
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+static TIMER_TICKS_PER_MS: Once<u32> = Once::new();
+static IO_APICS: Mutex<IoApics> = Mutex::new(IoApics {
+    io_apics: [None, None, None, None, None, None, None, None],
+    isa_routes: [None; ISA_IRQ_COUNT],
+});
+
+/// Enables the local APIC of this CPU and its timer, and masks every I/O APIC input.
+///
+/// # Safety
+/// Interrupts must be disabled, and the 8259 PICs masked, since this takes over from them.
+pub unsafe fn init(madt: &Madt) {
+    let mut apic_base = Msr::new(IA32_APIC_BASE_MSR);
+    let base = apic_base.read();
+    let local_apic = if detect() == ApicSupport::X2Apic {
+        apic_base.write(base | APIC_BASE_GLOBAL_ENABLE | APIC_BASE_X2APIC_ENABLE);
+        LocalApic {
+            registers: Registers::Msr,
+        }
+    } else {
+        // the MADT is authoritative, but the MSR has to agree with it
+        let address = madt.local_apic_address();
+        apic_base
+            .write((base & !APIC_BASE_ADDRESS_MASK) | address.as_u64() | APIC_BASE_GLOBAL_ENABLE);
+        LocalApic {
+            registers: Registers::Mmio(
+                mmio::map(address, LOCAL_APIC_MMIO_SIZE).expect("failed to map the local APIC"),
+            ),
+        }
+    };
+    local_apic.enable(madt);
+
+    let ticks_per_ms = local_apic.calibrate_timer();
+    TIMER_TICKS_PER_MS.call_once(|| ticks_per_ms);
+    local_apic.start_periodic_timer(ticks_per_ms);
+    LOCAL_APIC.call_once(|| local_apic);
+
+    let mut io_apics = IO_APICS.lock();
+    let mut slots = 0;
+    for entry in madt.entries() {
+        match entry {
+            MadtEntry::IoApic {
+                address, gsi_base, ..
+            } if slots < MAX_IO_APICS => {
+                let io_apic = IoApic::new(PhysAddr::new(u64::from(address)), gsi_base);
+                io_apic.mask_all();
+                io_apics.io_apics[slots] = Some(io_apic);
+                slots += 1;
+            }
+            MadtEntry::InterruptSourceOverride { source, gsi, flags }
+                if usize::from(source) < ISA_IRQ_COUNT =>
+            {
+                io_apics.isa_routes[usize::from(source)] = Some(IsaRoute { gsi, flags });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The local APIC, once `init` ran.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.get()
+}
+
+/// The calibrated local APIC timer rate, in ticks per millisecond at a divisor of 16.
+pub fn timer_ticks_per_ms() -> Option<u32> {
+    TIMER_TICKS_PER_MS.get().copied()
+}
+
+/// Signals the end of the current interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    if let Some(local_apic) = local_apic() {
+        local_apic.end_of_interrupt();
+    }
+}
+
+/// Routes the given ISA IRQ to `vector` on this CPU, following the MADT's overrides.
+///
+/// # Safety
+/// A handler for `vector` must be installed.
+pub unsafe fn enable_isa_irq(irq: u8, vector: u8) {
+    let destination = local_apic().expect("local APIC is not initialized").id();
+    let io_apics = IO_APICS.lock();
+    let route = io_apics.isa_routes[usize::from(irq)].unwrap_or(IsaRoute {
+        gsi: u32::from(irq),
+        flags: IntiFlags::default(),
+    });
+
+    let mut low = u32::from(vector);
+    if route.flags.active_low() {
+        low |= LVT_ACTIVE_LOW;
+    }
+    if route.flags.level_triggered() {
+        low |= LVT_LEVEL_TRIGGERED;
+    }
+    // physical destination mode, where the top byte is the target's APIC ID
+    let high = destination << 24;
+
+    let io_apic = io_apics
+        .io_apics
+        .iter()
+        .flatten()
+        .find(|io_apic| io_apic.handles(route.gsi))
+        .expect("no I/O APIC handles the IRQ");
+    io_apic.write_redirection(route.gsi, low, high);
+}
@@ -0,0 +1,149 @@
+// Global Descriptor Table and Task State Segment. In long mode segmentation is mostly
+// gone, but the GDT still holds the privilege level of the code and data segments and
+// points the CPU at the TSS, whose Interrupt Stack Table gives exceptions like the double
+// fault a known good stack to run on. The TSS also holds the stack the CPU switches to
+// when an interrupt arrives in user mode, which is the running thread's kernel stack.
+
+use core::cell::UnsafeCell;
+use core::mem::offset_of;
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// IST slot of the stack the double fault handler runs on.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// IST slot of the stack the non-maskable interrupt handler runs on.
+pub const NMI_IST_INDEX: u16 = 1;
+
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+// There is no memory management yet, so the IST stacks are plain statics. They are only
+// ever touched by the CPU when it switches stacks.
+static mut DOUBLE_FAULT_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+static mut NMI_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+// Stacks grow downwards, so the CPU needs the address one past their end
+fn stack_top(stack: *const [u8; IST_STACK_SIZE]) -> VirtAddr {
+    VirtAddr::from_ptr(stack) + IST_STACK_SIZE as u64
+}
+
+// The kernel stack entry changes on every thread switch, so the TSS can't be immutable like
+// the GDT. Apart from the CPU, only `init` and `set_kernel_stack` touch it.
+#[repr(transparent)]
+pub(crate) struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
+
+pub(crate) static TSS: Tss = Tss(UnsafeCell::new(TaskStateSegment::new()));
+
+/// Offset of the ring 0 stack pointer in the TSS, for the `syscall` entry which has to load
+/// it by hand.
+pub(crate) const TSS_KERNEL_STACK_OFFSET: usize =
+    offset_of!(TaskStateSegment, privilege_stack_table);
+
+/// The segment selectors of our GDT.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+lazy_static! {
+    // The order of the segments is fixed by `syscall`/`sysret`: they expect the kernel data
+    // segment right after the kernel code segment, and the user code segment right after the
+    // user data segment.
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let kernel_code = gdt.append(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.append(Descriptor::kernel_data_segment());
+        let user_data = gdt.append(Descriptor::user_data_segment());
+        let user_code = gdt.append(Descriptor::user_code_segment());
+        // the TSS is a static, and only ever modified in place
+        let tss = gdt.append(unsafe { Descriptor::tss_segment_unchecked(TSS.0.get()) });
+        (
+            gdt,
+            Selectors {
+                kernel_code,
+                kernel_data,
+                user_data,
+                user_code,
+                tss,
+            },
+        )
+    };
+}
+
+/// Loads our GDT and TSS, replacing the ones the bootloader set up.
+pub fn init() {
+    // the CPU doesn't use the TSS before it's loaded below
+    unsafe {
+        let tss = &mut *TSS.0.get();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            stack_top(&raw const DOUBLE_FAULT_STACK);
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = stack_top(&raw const NMI_STACK);
+    }
+    GDT.0.load();
+    let selectors = &GDT.1;
+    // the selectors point into the GDT we just loaded
+    unsafe {
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
+    }
+}
+
+/// The selectors of the loaded GDT.
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// Sets the stack the CPU switches to when an interrupt or system call comes from user
+/// mode. The thread scheduler points it at the top of the running thread's kernel stack.
+pub fn set_kernel_stack(top: VirtAddr) {
+    // the CPU only reads the entry when entering the kernel, which can't happen while
+    // we're in here with interrupts disabled
+    interrupts::without_interrupts(|| unsafe {
+        (*TSS.0.get()).privilege_stack_table[0] = top;
+    });
+}
+
+/// The stack set with `set_kernel_stack`.
+pub fn kernel_stack() -> VirtAddr {
+    unsafe { (*TSS.0.get()).privilege_stack_table[0] }
+}
+
+#[test_case]
+fn test_segments_reloaded() {
+    assert_eq!(CS::get_reg(), selectors().kernel_code);
+    assert_eq!(SS::get_reg(), selectors().kernel_data);
+}
+
+#[test_case]
+fn test_syscall_segment_layout() {
+    let selectors = selectors();
+    assert_eq!(
+        selectors.kernel_data.index(),
+        selectors.kernel_code.index() + 1
+    );
+    assert_eq!(selectors.user_code.index(), selectors.user_data.index() + 1);
+}
+
+#[test_case]
+fn test_set_kernel_stack() {
+    // a thread switch in between would set it too
+    interrupts::without_interrupts(|| {
+        let previous = kernel_stack();
+        set_kernel_stack(VirtAddr::new(0x1234_5000));
+        assert_eq!(kernel_stack(), VirtAddr::new(0x1234_5000));
+        set_kernel_stack(previous);
+    });
+}
@@ -0,0 +1,457 @@
+// Interrupt Descriptor Table, CPU exception handling and hardware interrupt dispatch.
+//
+// The interrupt entry points are small naked stubs that push the vector number (and a dummy
+// error code where the CPU doesn't push one), then jump to a common routine that saves all
+// general purpose registers. That way every handler sees the complete register state as a
+// `TrapFrame`, which is what the register dumps are printed from. The handler can also
+// return a different frame to resume, which is how threads are switched.
+
+use crate::acpi::Madt;
+use crate::pic::{self, PICS, PIC_1_OFFSET};
+use crate::process::{self, ExitStatus};
+use crate::{apic, gdt, keyboard, mouse, pit, println, serial_println, thread, time, user};
+use core::arch::naked_asm;
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Once;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+// Exception vectors, fixed by the CPU
+const NMI_VECTOR: u64 = 2;
+const BREAKPOINT_VECTOR: u64 = 3;
+const INVALID_OPCODE_VECTOR: u64 = 6;
+const DOUBLE_FAULT_VECTOR: u64 = 8;
+const GENERAL_PROTECTION_FAULT_VECTOR: u64 = 13;
+const PAGE_FAULT_VECTOR: u64 = 14;
+
+// Hardware interrupts. The ISA IRQs keep the vectors they have on the remapped PICs when
+// they are routed through the I/O APIC instead.
+const IRQ_BASE_VECTOR: u64 = PIC_1_OFFSET as u64;
+const IRQ_COUNT: u64 = 16;
+const IRQ_LAST_VECTOR: u64 = IRQ_BASE_VECTOR + IRQ_COUNT - 1;
+const APIC_TIMER_VECTOR: u64 = apic::TIMER_VECTOR as u64;
+const APIC_SPURIOUS_VECTOR: u64 = apic::SPURIOUS_VECTOR as u64;
+const YIELD_VECTOR: u64 = thread::YIELD_VECTOR as u64;
+
+/// The register state of the interrupted code, as saved by the interrupt entry stubs.
+///
+/// The field order mirrors the stack layout: general purpose registers pushed by
+/// `interrupt_common`, then the vector and error code pushed by the stub, then the
+/// frame the CPU pushed itself.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// Three registers per line, so the whole dump fits on the 80 column VGA screen
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "RIP={:016x} CS={:04x} RFLAGS={:016x}",
+            self.rip, self.cs, self.rflags
+        )?;
+        writeln!(
+            f,
+            "RSP={:016x} SS={:04x} ERR={:016x}",
+            self.rsp, self.ss, self.error_code
+        )?;
+        let registers = [
+            ("RAX", self.rax),
+            ("RBX", self.rbx),
+            ("RCX", self.rcx),
+            ("RDX", self.rdx),
+            ("RSI", self.rsi),
+            ("RDI", self.rdi),
+            ("RBP", self.rbp),
+            ("R8 ", self.r8),
+            ("R9 ", self.r9),
+            ("R10", self.r10),
+            ("R11", self.r11),
+            ("R12", self.r12),
+            ("R13", self.r13),
+            ("R14", self.r14),
+            ("R15", self.r15),
+        ];
+        for line in registers.chunks(3) {
+            for (i, (name, value)) in line.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " ")?;
+                }
+                write!(f, "{}={:016x}", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// Generates the entry stub for one interrupt vector. Interrupts that don't come with an
+// error code get a zero pushed instead, so the `TrapFrame` layout is the same for all.
+macro_rules! interrupt_entry {
+    ($name:ident, $vector:expr) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push 0",
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym interrupt_common,
+            );
+        }
+    };
+    ($name:ident, $vector:expr, error_code) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym interrupt_common,
+            );
+        }
+    };
+}
+
+interrupt_entry!(nmi_entry, NMI_VECTOR);
+interrupt_entry!(breakpoint_entry, BREAKPOINT_VECTOR);
+interrupt_entry!(invalid_opcode_entry, INVALID_OPCODE_VECTOR);
+interrupt_entry!(double_fault_entry, DOUBLE_FAULT_VECTOR, error_code);
+interrupt_entry!(
+    general_protection_fault_entry,
+    GENERAL_PROTECTION_FAULT_VECTOR,
+    error_code
+);
+interrupt_entry!(page_fault_entry, PAGE_FAULT_VECTOR, error_code);
+
+interrupt_entry!(irq0_entry, IRQ_BASE_VECTOR);
+interrupt_entry!(irq1_entry, IRQ_BASE_VECTOR + 1);
+interrupt_entry!(irq2_entry, IRQ_BASE_VECTOR + 2);
+interrupt_entry!(irq3_entry, IRQ_BASE_VECTOR + 3);
+interrupt_entry!(irq4_entry, IRQ_BASE_VECTOR + 4);
+interrupt_entry!(irq5_entry, IRQ_BASE_VECTOR + 5);
+interrupt_entry!(irq6_entry, IRQ_BASE_VECTOR + 6);
+interrupt_entry!(irq7_entry, IRQ_BASE_VECTOR + 7);
+interrupt_entry!(irq8_entry, IRQ_BASE_VECTOR + 8);
+interrupt_entry!(irq9_entry, IRQ_BASE_VECTOR + 9);
+interrupt_entry!(irq10_entry, IRQ_BASE_VECTOR + 10);
+interrupt_entry!(irq11_entry, IRQ_BASE_VECTOR + 11);
+interrupt_entry!(irq12_entry, IRQ_BASE_VECTOR + 12);
+interrupt_entry!(irq13_entry, IRQ_BASE_VECTOR + 13);
+interrupt_entry!(irq14_entry, IRQ_BASE_VECTOR + 14);
+interrupt_entry!(irq15_entry, IRQ_BASE_VECTOR + 15);
+
+interrupt_entry!(apic_timer_entry, APIC_TIMER_VECTOR);
+interrupt_entry!(apic_spurious_entry, APIC_SPURIOUS_VECTOR);
+interrupt_entry!(yield_entry, YIELD_VECTOR);
+
+const IRQ_ENTRIES: [extern "C" fn(); IRQ_COUNT as usize] = [
+    irq0_entry,
+    irq1_entry,
+    irq2_entry,
+    irq3_entry,
+    irq4_entry,
+    irq5_entry,
+    irq6_entry,
+    irq7_entry,
+    irq8_entry,
+    irq9_entry,
+    irq10_entry,
+    irq11_entry,
+    irq12_entry,
+    irq13_entry,
+    irq14_entry,
+    irq15_entry,
+];
+
+// Saves the registers, hands the frame to `handle_interrupt` and restores them from the frame
+// it returns, which may be on another thread's stack. The CPU aligns the stack to 16 bytes
+// before pushing its frame, and the 22 quadwords of the `TrapFrame` keep it that way, as the
+// C calling convention requires.
+#[unsafe(naked)]
+extern "C" fn interrupt_common() {
+    naked_asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "cld",
+        "call {handler}",
+        "mov rsp, rax",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        // drop the vector and error code
+        "add rsp, 16",
+        "iretq",
+        handler = sym handle_interrupt,
+    );
+}
+
+// Hardware interrupts go to their driver. Of the exceptions, breakpoints are meant to be
+// continued from, everything else is fatal and ends in the panic handler with the register
+// dump as part of the message, unless it came from user mode. The timer and yield
+// interrupts may switch to another thread, which is why the frame to resume is returned.
+extern "C" fn handle_interrupt(frame: &mut TrapFrame) -> *mut TrapFrame {
+    // the double fault and NMI handlers run on their own stacks, which must not be left
+    // behind by a switch
+    if frame.vector < IRQ_BASE_VECTOR
+        && user::from_user_mode(frame)
+        && !matches!(frame.vector, NMI_VECTOR | DOUBLE_FAULT_VECTOR)
+    {
+        end_user_thread(frame);
+    }
+    match frame.vector {
+        IRQ_BASE_VECTOR..=IRQ_LAST_VECTOR => {
+            let irq = (frame.vector - IRQ_BASE_VECTOR) as u8;
+            handle_irq(irq);
+            if irq == pic::TIMER_IRQ {
+                return thread::tick(frame);
+            }
+        }
+        // acknowledged before switching, the next thread won't come back here for a while
+        APIC_TIMER_VECTOR => {
+            time::tick();
+            apic::end_of_interrupt();
+            return thread::tick(frame);
+        }
+        YIELD_VECTOR => return thread::switch(frame),
+        // spurious interrupts from the local APIC must not be acknowledged
+        APIC_SPURIOUS_VECTOR => {}
+        BREAKPOINT_VECTOR => {
+            println!("EXCEPTION: BREAKPOINT\n{}", frame);
+            serial_println!("EXCEPTION: BREAKPOINT\n{}", frame);
+        }
+        PAGE_FAULT_VECTOR => panic!(
+            "EXCEPTION: PAGE FAULT\nAccessed address: {:#x}\nError code: {:?}\n{}",
+            Cr2::read_raw(),
+            PageFaultErrorCode::from_bits_truncate(frame.error_code),
+            frame
+        ),
+        GENERAL_PROTECTION_FAULT_VECTOR => panic!(
+            "EXCEPTION: GENERAL PROTECTION FAULT\nSelector error code: {:#x}\n{}",
+            frame.error_code, frame
+        ),
+        INVALID_OPCODE_VECTOR => panic!("EXCEPTION: INVALID OPCODE\n{}", frame),
+        DOUBLE_FAULT_VECTOR => panic!("EXCEPTION: DOUBLE FAULT\n{}", frame),
+        NMI_VECTOR => panic!("EXCEPTION: NON-MASKABLE INTERRUPT\n{}", frame),
+        vector => panic!("EXCEPTION: unexpected vector {}\n{}", vector, frame),
+    }
+    frame
+}
+
+// An exception in user mode is the process's problem. It is killed, and the kernel carries on.
+fn end_user_thread(frame: &TrapFrame) -> ! {
+    let id = thread::current().as_u64();
+    if frame.vector == PAGE_FAULT_VECTOR {
+        serial_println!(
+            "thread {} ended by page fault at {:#x}\n{}",
+            id,
+            Cr2::read_raw(),
+            frame
+        );
+    } else {
+        serial_println!(
+            "thread {} ended by exception {}\n{}",
+            id,
+            frame.vector,
+            frame
+        );
+    }
+    process::exit(ExitStatus::Killed(frame.vector as u8));
+}
+
+fn handle_irq(irq: u8) {
+    let vector = PIC_1_OFFSET + irq;
+    // we are handling exactly this interrupt right now
+    if controller() == InterruptController::Pic && unsafe { PICS.lock().check_spurious(vector) } {
+        return;
+    }
+
+    match irq {
+        pic::TIMER_IRQ => time::tick(),
+        pic::KEYBOARD_IRQ => keyboard::handle_interrupt(),
+        pic::MOUSE_IRQ => mouse::handle_interrupt(),
+        _ => {}
+    }
+
+    end_of_interrupt(vector);
+}
+
+fn entry_address(entry: extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(entry as usize as u64)
+}
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        // all entry stubs follow the interrupt calling convention, and the IST indices are
+        // set up in the TSS
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_addr(entry_address(nmi_entry))
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.breakpoint
+                .set_handler_addr(entry_address(breakpoint_entry));
+            idt.invalid_opcode
+                .set_handler_addr(entry_address(invalid_opcode_entry));
+            // the double fault gets its own stack, so that a kernel stack overflow, which page
+            // faults while pushing the page fault frame, doesn't turn into a triple fault
+            idt.double_fault
+                .set_handler_addr(entry_address(double_fault_entry))
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.general_protection_fault
+                .set_handler_addr(entry_address(general_protection_fault_entry));
+            idt.page_fault
+                .set_handler_addr(entry_address(page_fault_entry));
+            for (irq, &entry) in IRQ_ENTRIES.iter().enumerate() {
+                idt[PIC_1_OFFSET + irq as u8].set_handler_addr(entry_address(entry));
+            }
+            idt[apic::TIMER_VECTOR].set_handler_addr(entry_address(apic_timer_entry));
+            idt[apic::SPURIOUS_VECTOR].set_handler_addr(entry_address(apic_spurious_entry));
+            idt[thread::YIELD_VECTOR].set_handler_addr(entry_address(yield_entry));
+        }
+        idt
+    };
+}
+
+/// Loads the IDT. Must run after `gdt::init`, since the entries capture the code segment.
+pub fn init_idt() {
+    IDT.load();
+}
+
+/// The interrupt controller that delivers hardware interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    /// The legacy 8259 PICs, with the PIT as timer.
+    Pic,
+    /// The local and I/O APICs, with the local APIC timer.
+    Apic,
+}
+
+static CONTROLLER: Once<InterruptController> = Once::new();
+
+/// The interrupt controller picked by `init_hardware_interrupts`.
+pub fn controller() -> InterruptController {
+    *CONTROLLER.get().unwrap_or(&InterruptController::Pic)
+}
+
+/// Sets up the interrupt controller and the timer, then enables interrupts.
+///
+/// The APICs are used when the CPU has a local APIC and the firmware describes the I/O APICs
+/// in its MADT. Otherwise the kernel falls back to the 8259 PICs and the PIT.
+pub fn init_hardware_interrupts() {
+    // Either way the PICs are remapped first, so that a stray legacy interrupt can't be
+    // mistaken for a CPU exception. Interrupts are still disabled at this point.
+    unsafe {
+        PICS.lock().initialize();
+    }
+
+    let madt =
+        Madt::find().filter(|madt| madt.has_io_apic() && apic::detect() != apic::ApicSupport::None);
+    let controller = match madt {
+        Some(madt) => {
+            // the PICs stay masked, and the IDT has entries for the APIC vectors
+            unsafe {
+                PICS.lock().disable();
+                apic::init(&madt);
+            }
+            InterruptController::Apic
+        }
+        None => {
+            pit::init();
+            InterruptController::Pic
+        }
+    };
+    CONTROLLER.call_once(|| controller);
+
+    if controller == InterruptController::Pic {
+        enable_irq(pic::TIMER_IRQ);
+    }
+    enable_irq(pic::KEYBOARD_IRQ);
+    enable_irq(pic::MOUSE_IRQ);
+    x86_64::instructions::interrupts::enable();
+}
+
+/// Lets the given ISA IRQ through on the active interrupt controller.
+pub fn enable_irq(irq: u8) {
+    assert!(u64::from(irq) < IRQ_COUNT, "no ISA IRQ {}", irq);
+    // the IDT has entries for all ISA IRQ vectors
+    unsafe {
+        match controller() {
+            InterruptController::Pic => PICS.lock().unmask(irq),
+            InterruptController::Apic => apic::enable_isa_irq(irq, PIC_1_OFFSET + irq),
+        }
+    }
+}
+
+// Acknowledges the interrupt on whichever controller delivered it
+fn end_of_interrupt(vector: u8) {
+    match controller() {
+        InterruptController::Pic => unsafe { PICS.lock().notify_end_of_interrupt(vector) },
+        InterruptController::Apic => apic::end_of_interrupt(),
+    }
+}
+
+#[test_case]
+fn test_timer_interrupt_fires() {
+    let start = time::ticks();
+    while time::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_breakpoint_exception() {
+    // the handler returns, so execution just continues
+    x86_64::instructions::interrupts::int3();
+}
@@ -0,0 +1,222 @@
+// Keymaps for the layouts we support. Key codes name the physical keys after their position on
+// a US keyboard, a layout decides which characters those positions produce.
+
+use super::{KeyCode, Keymap, Modifiers};
+
+// The characters of one key: without and with shift, and with AltGr where the layout puts a
+// third character on it. Letters are affected by Caps Lock, other keys aren't.
+struct Key {
+    normal: char,
+    shifted: char,
+    altgr: Option<char>,
+    letter: bool,
+}
+
+const fn letter(normal: char, shifted: char) -> Option<Key> {
+    Some(Key {
+        normal,
+        shifted,
+        altgr: None,
+        letter: true,
+    })
+}
+
+const fn symbol(normal: char, shifted: char) -> Option<Key> {
+    Some(Key {
+        normal,
+        shifted,
+        altgr: None,
+        letter: false,
+    })
+}
+
+const fn with_altgr(key: Option<Key>, altgr: char) -> Option<Key> {
+    match key {
+        Some(Key {
+            normal,
+            shifted,
+            letter,
+            ..
+        }) => Some(Key {
+            normal,
+            shifted,
+            altgr: Some(altgr),
+            letter,
+        }),
+        None => None,
+    }
+}
+
+fn resolve(key: Option<Key>, modifiers: &Modifiers) -> Option<char> {
+    let key = key?;
+    if modifiers.altgr() {
+        return key.altgr;
+    }
+    // Caps Lock works like a held shift for letters, so shift undoes it
+    let shifted = modifiers.shift() ^ (key.letter && modifiers.caps_lock);
+    Some(if shifted { key.shifted } else { key.normal })
+}
+
+// The letter keys in their US positions, shared by the QWERTY layouts
+fn qwerty_letter(code: KeyCode) -> Option<Key> {
+    use KeyCode::*;
+    match code {
+        Q => letter('q', 'Q'),
+        W => letter('w', 'W'),
+        E => letter('e', 'E'),
+        R => letter('r', 'R'),
+        T => letter('t', 'T'),
+        Y => letter('y', 'Y'),
+        U => letter('u', 'U'),
+        I => letter('i', 'I'),
+        O => letter('o', 'O'),
+        P => letter('p', 'P'),
+        A => letter('a', 'A'),
+        S => letter('s', 'S'),
+        D => letter('d', 'D'),
+        F => letter('f', 'F'),
+        G => letter('g', 'G'),
+        H => letter('h', 'H'),
+        J => letter('j', 'J'),
+        K => letter('k', 'K'),
+        L => letter('l', 'L'),
+        Z => letter('z', 'Z'),
+        X => letter('x', 'X'),
+        C => letter('c', 'C'),
+        V => letter('v', 'V'),
+        B => letter('b', 'B'),
+        N => letter('n', 'N'),
+        M => letter('m', 'M'),
+        _ => None,
+    }
+}
+
+/// The US layout (ANSI, 104 keys).
+pub struct Us104;
+
+impl Keymap for Us104 {
+    fn name(&self) -> &'static str {
+        "us"
+    }
+
+    fn map(&self, code: KeyCode, modifiers: &Modifiers) -> Option<char> {
+        use KeyCode::*;
+        let key = match code {
+            Backtick => symbol('`', '~'),
+            Key1 => symbol('1', '!'),
+            Key2 => symbol('2', '@'),
+            Key3 => symbol('3', '#'),
+            Key4 => symbol('4', '$'),
+            Key5 => symbol('5', '%'),
+            Key6 => symbol('6', '^'),
+            Key7 => symbol('7', '&'),
+            Key8 => symbol('8', '*'),
+            Key9 => symbol('9', '('),
+            Key0 => symbol('0', ')'),
+            Minus => symbol('-', '_'),
+            Equals => symbol('=', '+'),
+            LeftBracket => symbol('[', '{'),
+            RightBracket => symbol(']', '}'),
+            Backslash => symbol('\\', '|'),
+            SemiColon => symbol(';', ':'),
+            Quote => symbol('\'', '"'),
+            Comma => symbol(',', '<'),
+            Period => symbol('.', '>'),
+            Slash => symbol('/', '?'),
+            // ANSI keyboards don't have this key, but the code exists
+            NonUsBackslash => symbol('\\', '|'),
+            _ => qwerty_letter(code),
+        };
+        resolve(key, modifiers)
+    }
+}
+
+/// The UK layout (ISO, 105 keys).
+pub struct Uk105;
+
+impl Keymap for Uk105 {
+    fn name(&self) -> &'static str {
+        "uk"
+    }
+
+    fn map(&self, code: KeyCode, modifiers: &Modifiers) -> Option<char> {
+        use KeyCode::*;
+        let key = match code {
+            Backtick => with_altgr(symbol('`', '¬'), '¦'),
+            Key1 => symbol('1', '!'),
+            Key2 => symbol('2', '"'),
+            Key3 => symbol('3', '£'),
+            Key4 => with_altgr(symbol('4', '$'), '€'),
+            Key5 => symbol('5', '%'),
+            Key6 => symbol('6', '^'),
+            Key7 => symbol('7', '&'),
+            Key8 => symbol('8', '*'),
+            Key9 => symbol('9', '('),
+            Key0 => symbol('0', ')'),
+            Minus => symbol('-', '_'),
+            Equals => symbol('=', '+'),
+            LeftBracket => symbol('[', '{'),
+            RightBracket => symbol(']', '}'),
+            // the key left of Enter on ISO keyboards
+            Backslash => symbol('#', '~'),
+            SemiColon => symbol(';', ':'),
+            Quote => symbol('\'', '@'),
+            Comma => symbol(',', '<'),
+            Period => symbol('.', '>'),
+            Slash => symbol('/', '?'),
+            NonUsBackslash => symbol('\\', '|'),
+            A => with_altgr(letter('a', 'A'), 'á'),
+            E => with_altgr(letter('e', 'E'), 'é'),
+            I => with_altgr(letter('i', 'I'), 'í'),
+            O => with_altgr(letter('o', 'O'), 'ó'),
+            U => with_altgr(letter('u', 'U'), 'ú'),
+            _ => qwerty_letter(code),
+        };
+        resolve(key, modifiers)
+    }
+}
+
+/// The German layout (QWERTZ, ISO, 105 keys).
+pub struct De105;
+
+impl Keymap for De105 {
+    fn name(&self) -> &'static str {
+        "de"
+    }
+
+    fn map(&self, code: KeyCode, modifiers: &Modifiers) -> Option<char> {
+        use KeyCode::*;
+        let key = match code {
+            Backtick => symbol('^', '°'),
+            Key1 => symbol('1', '!'),
+            Key2 => with_altgr(symbol('2', '"'), '²'),
+            Key3 => with_altgr(symbol('3', '§'), '³'),
+            Key4 => symbol('4', '$'),
+            Key5 => symbol('5', '%'),
+            Key6 => symbol('6', '&'),
+            Key7 => with_altgr(symbol('7', '/'), '{'),
+            Key8 => with_altgr(symbol('8', '('), '['),
+            Key9 => with_altgr(symbol('9', ')'), ']'),
+            Key0 => with_altgr(symbol('0', '='), '}'),
+            Minus => with_altgr(symbol('ß', '?'), '\\'),
+            Equals => symbol('´', '`'),
+            LeftBracket => letter('ü', 'Ü'),
+            RightBracket => with_altgr(symbol('+', '*'), '~'),
+            Backslash => symbol('#', '\''),
+            SemiColon => letter('ö', 'Ö'),
+            Quote => letter('ä', 'Ä'),
+            Comma => symbol(',', ';'),
+            Period => symbol('.', ':'),
+            Slash => symbol('-', '_'),
+            NonUsBackslash => with_altgr(symbol('<', '>'), '|'),
+            Q => with_altgr(letter('q', 'Q'), '@'),
+            E => with_altgr(letter('e', 'E'), '€'),
+            M => with_altgr(letter('m', 'M'), 'µ'),
+            // Y and Z swap places compared to QWERTY
+            Y => letter('z', 'Z'),
+            Z => letter('y', 'Y'),
+            _ => qwerty_letter(code),
+        };
+        resolve(key, modifiers)
+    }
+}
@@ -0,0 +1,561 @@
+// PS/2 keyboard driver.
+//
+// The interrupt handler only reads the scancode bytes and assembles them into key events,
+// which it pushes into a lock-free queue. Everything else happens when the kernel takes the
+// events out again: tracking the modifier and lock keys and translating keys to characters
+// with the active keymap.
+
+mod layouts;
+mod scancode;
+
+pub use layouts::{De105, Uk105, Us104};
+pub use scancode::{ScancodeDecoder, ScancodeSet};
+
+use crate::ps2;
+use crate::queue::ArrayQueue;
+use crate::serial_println;
+use crate::task::{InterruptWaker, Stream};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use spin::Mutex;
+
+/// A physical key, named after what it says on a US keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Backtick,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    /// The key above Enter on ANSI keyboards, left of it on ISO ones.
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    SemiColon,
+    Quote,
+    Enter,
+    LShift,
+    /// The extra key right of the left shift on ISO keyboards.
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RShift,
+    LControl,
+    LWin,
+    LAlt,
+    Spacebar,
+    RAltGr,
+    RWin,
+    Apps,
+    RControl,
+    Insert,
+    Home,
+    PageUp,
+    Delete,
+    End,
+    PageDown,
+    ArrowUp,
+    ArrowLeft,
+    ArrowDown,
+    ArrowRight,
+    NumLock,
+    NumpadDivide,
+    NumpadMultiply,
+    NumpadSubtract,
+    NumpadAdd,
+    NumpadEnter,
+    NumpadPeriod,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Down,
+    Up,
+}
+
+/// A key being pressed or released. Holding a key down repeats the `Down` event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+}
+
+impl KeyEvent {
+    pub const fn new(code: KeyCode, state: KeyState) -> KeyEvent {
+        KeyEvent { code, state }
+    }
+}
+
+/// Which modifier keys are held and which locks are on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub lshift: bool,
+    pub rshift: bool,
+    pub lctrl: bool,
+    pub rctrl: bool,
+    pub lalt: bool,
+    pub altgr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    // Num Lock starts out on, as the BIOS leaves it
+    const fn new() -> Modifiers {
+        Modifiers {
+            lshift: false,
+            rshift: false,
+            lctrl: false,
+            rctrl: false,
+            lalt: false,
+            altgr: false,
+            caps_lock: false,
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+
+    pub fn shift(&self) -> bool {
+        self.lshift || self.rshift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.lctrl || self.rctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.lalt
+    }
+
+    pub fn altgr(&self) -> bool {
+        self.altgr
+    }
+
+    // Applies a modifier or lock key, returning whether the event was one
+    fn update(&mut self, event: KeyEvent) -> bool {
+        let down = event.state == KeyState::Down;
+        match event.code {
+            KeyCode::LShift => self.lshift = down,
+            KeyCode::RShift => self.rshift = down,
+            KeyCode::LControl => self.lctrl = down,
+            KeyCode::RControl => self.rctrl = down,
+            KeyCode::LAlt => self.lalt = down,
+            KeyCode::RAltGr => self.altgr = down,
+            KeyCode::CapsLock => self.caps_lock ^= down,
+            KeyCode::NumLock => self.num_lock ^= down,
+            KeyCode::ScrollLock => self.scroll_lock ^= down,
+            _ => return false,
+        }
+        true
+    }
+
+    // The lock LED bits of the keyboard's "set LEDs" command
+    fn leds(&self) -> u8 {
+        (self.scroll_lock as u8) | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+}
+
+impl Default for Modifiers {
+    fn default() -> Modifiers {
+        Modifiers::new()
+    }
+}
+
+/// What a key press means: a character, or a key that has none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodedKey {
+    Unicode(char),
+    RawKey(KeyCode),
+}
+
+/// Translates keys to characters for one keyboard layout.
+pub trait Keymap: Sync {
+    /// Short name of the layout, like "us".
+    fn name(&self) -> &'static str;
+
+    /// The character the key at this position produces, if any.
+    fn map(&self, code: KeyCode, modifiers: &Modifiers) -> Option<char>;
+}
+
+/// Turns key events into characters, keeping track of the modifier keys.
+pub struct Keyboard {
+    modifiers: Modifiers,
+    keymap: &'static dyn Keymap,
+}
+
+impl Keyboard {
+    pub const fn new(keymap: &'static dyn Keymap) -> Keyboard {
+        Keyboard {
+            modifiers: Modifiers::new(),
+            keymap,
+        }
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    pub fn keymap(&self) -> &'static dyn Keymap {
+        self.keymap
+    }
+
+    pub fn set_keymap(&mut self, keymap: &'static dyn Keymap) {
+        self.keymap = keymap;
+    }
+
+    /// Processes an event, returning the key it types. Releases and modifier keys type
+    /// nothing.
+    pub fn process(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        if self.modifiers.update(event) || event.state == KeyState::Up {
+            return None;
+        }
+        let code = self.numpad_navigation(event.code);
+        // the middle key of the numpad has no navigation meaning
+        if code == KeyCode::Numpad5 && !self.numpad_types_digits() {
+            return Some(DecodedKey::RawKey(code));
+        }
+        let character = match code {
+            KeyCode::Enter | KeyCode::NumpadEnter => '\n',
+            KeyCode::Tab => '\t',
+            KeyCode::Backspace => '\x08',
+            KeyCode::Escape => '\x1b',
+            KeyCode::Delete => '\x7f',
+            KeyCode::Spacebar => ' ',
+            KeyCode::NumpadDivide => '/',
+            KeyCode::NumpadMultiply => '*',
+            KeyCode::NumpadSubtract => '-',
+            KeyCode::NumpadAdd => '+',
+            KeyCode::Numpad0 => '0',
+            KeyCode::Numpad1 => '1',
+            KeyCode::Numpad2 => '2',
+            KeyCode::Numpad3 => '3',
+            KeyCode::Numpad4 => '4',
+            KeyCode::Numpad5 => '5',
+            KeyCode::Numpad6 => '6',
+            KeyCode::Numpad7 => '7',
+            KeyCode::Numpad8 => '8',
+            KeyCode::Numpad9 => '9',
+            KeyCode::NumpadPeriod => '.',
+            _ => match self.keymap.map(code, &self.modifiers) {
+                // Ctrl+A to Ctrl+Z are the control characters 1 to 26
+                Some(c) if self.modifiers.ctrl() && c.is_ascii_alphabetic() => {
+                    char::from(c.to_ascii_lowercase() as u8 - b'a' + 1)
+                }
+                Some(c) => c,
+                None => return Some(DecodedKey::RawKey(code)),
+            },
+        };
+        Some(DecodedKey::Unicode(character))
+    }
+
+    fn numpad_types_digits(&self) -> bool {
+        self.modifiers.num_lock && !self.modifiers.shift()
+    }
+
+    // Without Num Lock, or with shift held, the numpad is a second set of navigation keys
+    fn numpad_navigation(&self, code: KeyCode) -> KeyCode {
+        if self.numpad_types_digits() {
+            return code;
+        }
+        match code {
+            KeyCode::Numpad0 => KeyCode::Insert,
+            KeyCode::Numpad1 => KeyCode::End,
+            KeyCode::Numpad2 => KeyCode::ArrowDown,
+            KeyCode::Numpad3 => KeyCode::PageDown,
+            KeyCode::Numpad4 => KeyCode::ArrowLeft,
+            KeyCode::Numpad6 => KeyCode::ArrowRight,
+            KeyCode::Numpad7 => KeyCode::Home,
+            KeyCode::Numpad8 => KeyCode::ArrowUp,
+            KeyCode::Numpad9 => KeyCode::PageUp,
+            KeyCode::NumpadPeriod => KeyCode::Delete,
+            code => code,
+        }
+    }
+}
+
+const CMD_SET_LEDS: u8 = 0xed;
+
+// Events waiting for the kernel. At 128 entries the queue only overflows if nobody reads it
+// for a while, and then dropping the newest keys is the best we can do.
+static EVENTS: ArrayQueue<KeyEvent, 128> = ArrayQueue::new();
+
+// Only touched by the interrupt handler, and by `init` before the IRQ is enabled
+static DECODER: Mutex<ScancodeDecoder> = Mutex::new(ScancodeDecoder::new(ScancodeSet::Set1));
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new(&Us104));
+
+/// Finds out which scancode set the keyboard bytes arrive in and enables the keyboard IRQ
+/// on the controller. Must run before the IRQ is unmasked.
+pub fn init() {
+    ps2::flush_output();
+    let config = match ps2::read_config() {
+        Ok(config) => config,
+        Err(ps2::Timeout) => {
+            serial_println!("keyboard: no PS/2 controller");
+            return;
+        }
+    };
+    let set = if config & ps2::CONFIG_TRANSLATION != 0 {
+        ScancodeSet::Set1
+    } else {
+        ScancodeSet::Set2
+    };
+    *DECODER.lock() = ScancodeDecoder::new(set);
+    if ps2::write_config(config | ps2::CONFIG_FIRST_PORT_IRQ).is_err() {
+        serial_println!("keyboard: failed to enable the PS/2 keyboard interrupt");
+    }
+}
+
+// The task reading from a `KeyStream`
+static WAKER: InterruptWaker = InterruptWaker::new();
+
+/// Called on the keyboard IRQ.
+pub(crate) fn handle_interrupt() {
+    // the controller won't send the next byte until this one has been read
+    let byte = ps2::read_data_unchecked();
+    if let Some(event) = DECODER.lock().add_byte(byte) {
+        let _ = EVENTS.push(event);
+        WAKER.wake();
+    }
+}
+
+/// Takes events out of the queue until one of them types a key.
+pub fn read_key() -> Option<DecodedKey> {
+    let mut keyboard = KEYBOARD.lock();
+    while let Some(event) = EVENTS.pop() {
+        let leds = keyboard.modifiers.leds();
+        let key = keyboard.process(event);
+        if keyboard.modifiers.leds() != leds {
+            set_leds(keyboard.modifiers.leds());
+        }
+        if key.is_some() {
+            return key;
+        }
+    }
+    None
+}
+
+/// Whether there are events `read_key` hasn't taken yet.
+pub fn has_pending_events() -> bool {
+    !EVENTS.is_empty()
+}
+
+/// The typed keys as a stream, for tasks. Only one task should read keys at a time, the
+/// keyboard interrupt only wakes the last one that waited.
+pub struct KeyStream {
+    _private: (),
+}
+
+impl KeyStream {
+    pub fn new() -> KeyStream {
+        KeyStream { _private: () }
+    }
+}
+
+impl Default for KeyStream {
+    fn default() -> KeyStream {
+        KeyStream::new()
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<DecodedKey>> {
+        if let Some(key) = read_key() {
+            return Poll::Ready(Some(key));
+        }
+        WAKER.register(context.waker());
+        // a key that arrived before the waker was registered didn't wake anybody
+        match read_key() {
+            Some(key) => Poll::Ready(Some(key)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+pub fn modifiers() -> Modifiers {
+    KEYBOARD.lock().modifiers()
+}
+
+pub fn set_keymap(keymap: &'static dyn Keymap) {
+    KEYBOARD.lock().set_keymap(keymap);
+}
+
+// The keyboard acknowledges both bytes, and the decoder drops the acknowledgements
+fn set_leds(leds: u8) {
+    let _ = ps2::write_data(CMD_SET_LEDS).and_then(|()| ps2::write_data(leds));
+}
+
+#[cfg(test)]
+fn type_bytes(set: ScancodeSet, keymap: &'static dyn Keymap, bytes: &[u8]) -> [Option<char>; 8] {
+    let mut decoder = ScancodeDecoder::new(set);
+    let mut keyboard = Keyboard::new(keymap);
+    let mut typed = [None; 8];
+    let mut count = 0;
+    for &byte in bytes {
+        if let Some(DecodedKey::Unicode(c)) = decoder
+            .add_byte(byte)
+            .and_then(|event| keyboard.process(event))
+        {
+            typed[count] = Some(c);
+            count += 1;
+        }
+    }
+    typed
+}
+
+#[test_case]
+fn test_set1_press_and_release() {
+    let mut decoder = ScancodeDecoder::new(ScancodeSet::Set1);
+    assert_eq!(
+        decoder.add_byte(0x1e),
+        Some(KeyEvent::new(KeyCode::A, KeyState::Down))
+    );
+    assert_eq!(
+        decoder.add_byte(0x9e),
+        Some(KeyEvent::new(KeyCode::A, KeyState::Up))
+    );
+}
+
+#[test_case]
+fn test_set1_extended_key() {
+    let mut decoder = ScancodeDecoder::new(ScancodeSet::Set1);
+    assert_eq!(decoder.add_byte(0xe0), None);
+    assert_eq!(
+        decoder.add_byte(0x48),
+        Some(KeyEvent::new(KeyCode::ArrowUp, KeyState::Down))
+    );
+    // the fake shift around Print Screen is dropped
+    assert_eq!(decoder.add_byte(0xe0), None);
+    assert_eq!(decoder.add_byte(0x2a), None);
+}
+
+#[test_case]
+fn test_set2_release_and_extended_release() {
+    let mut decoder = ScancodeDecoder::new(ScancodeSet::Set2);
+    assert_eq!(decoder.add_byte(0xf0), None);
+    assert_eq!(
+        decoder.add_byte(0x1c),
+        Some(KeyEvent::new(KeyCode::A, KeyState::Up))
+    );
+    assert_eq!(decoder.add_byte(0xe0), None);
+    assert_eq!(decoder.add_byte(0xf0), None);
+    assert_eq!(
+        decoder.add_byte(0x71),
+        Some(KeyEvent::new(KeyCode::Delete, KeyState::Up))
+    );
+}
+
+#[test_case]
+fn test_pause_sequence() {
+    let mut decoder = ScancodeDecoder::new(ScancodeSet::Set2);
+    let events =
+        [0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77].map(|byte| decoder.add_byte(byte));
+    assert_eq!(events[..7], [None; 7]);
+    assert_eq!(
+        events[7],
+        Some(KeyEvent::new(KeyCode::Pause, KeyState::Down))
+    );
+}
+
+#[test_case]
+fn test_shift_and_caps_lock() {
+    // shift+a, a, caps lock, a, shift+1 with caps lock on
+    let typed = type_bytes(
+        ScancodeSet::Set1,
+        &Us104,
+        &[0x2a, 0x1e, 0x9e, 0xaa, 0x1e, 0x3a, 0xba, 0x1e, 0x2a, 0x02],
+    );
+    assert_eq!(typed[..4], [Some('A'), Some('a'), Some('A'), Some('!')]);
+}
+
+#[test_case]
+fn test_ctrl_letter_is_control_character() {
+    let typed = type_bytes(ScancodeSet::Set2, &Us104, &[0x14, 0x21]);
+    assert_eq!(typed[0], Some('\x03'));
+}
+
+#[test_case]
+fn test_layouts() {
+    // the keys at the US positions of y, z, shift+2 and the semicolon
+    let bytes = [0x35, 0x1a, 0x12, 0x1e, 0xf0, 0x12, 0x4c];
+    let us = type_bytes(ScancodeSet::Set2, &Us104, &bytes);
+    let uk = type_bytes(ScancodeSet::Set2, &Uk105, &bytes);
+    let de = type_bytes(ScancodeSet::Set2, &De105, &bytes);
+    assert_eq!(us[..4], [Some('y'), Some('z'), Some('@'), Some(';')]);
+    assert_eq!(uk[..4], [Some('y'), Some('z'), Some('"'), Some(';')]);
+    assert_eq!(de[..4], [Some('z'), Some('y'), Some('"'), Some('ö')]);
+}
+
+#[test_case]
+fn test_altgr() {
+    // AltGr+Q on a German keyboard
+    let typed = type_bytes(ScancodeSet::Set2, &De105, &[0xe0, 0x11, 0x15]);
+    assert_eq!(typed[0], Some('@'));
+}
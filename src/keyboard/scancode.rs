@@ -0,0 +1,356 @@
+// Scancode sets 1 and 2, turned into key events. Set 2 is what PS/2 keyboards send natively,
+// set 1 is what the controller hands out when it translates for compatibility with the XT.
+
+use super::{KeyCode, KeyEvent, KeyState};
+
+/// The scancode set the keyboard bytes arrive in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+const EXTENDED_PREFIX: u8 = 0xe0;
+const PAUSE_PREFIX: u8 = 0xe1;
+const SET1_RELEASE_BIT: u8 = 0x80;
+const SET2_RELEASE_PREFIX: u8 = 0xf0;
+
+// Pause has no release code, just a fixed sequence: E1 1D 45 E1 9D C5 in set 1 and
+// E1 14 77 E1 F0 14 F0 77 in set 2. These are the bytes after the first E1.
+const SET1_PAUSE_REMAINING: u8 = 5;
+const SET2_PAUSE_REMAINING: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    Extended,
+    Release,
+    ExtendedRelease,
+    Pause { remaining: u8 },
+}
+
+/// Assembles the bytes of multi-byte scancodes into key events.
+#[derive(Debug, Clone)]
+pub struct ScancodeDecoder {
+    set: ScancodeSet,
+    state: State,
+}
+
+impl ScancodeDecoder {
+    pub const fn new(set: ScancodeSet) -> ScancodeDecoder {
+        ScancodeDecoder {
+            set,
+            state: State::Start,
+        }
+    }
+
+    pub fn set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    /// Feeds one byte from the keyboard, returning the key event it completes, if any.
+    /// Bytes that aren't scancodes, like command acknowledgements, are dropped.
+    pub fn add_byte(&mut self, byte: u8) -> Option<KeyEvent> {
+        match (self.set, self.state) {
+            (_, State::Pause { remaining }) => {
+                if remaining > 1 {
+                    self.state = State::Pause {
+                        remaining: remaining - 1,
+                    };
+                    None
+                } else {
+                    self.state = State::Start;
+                    Some(KeyEvent::new(KeyCode::Pause, KeyState::Down))
+                }
+            }
+            (ScancodeSet::Set1, State::Start) => match byte {
+                EXTENDED_PREFIX => self.enter(State::Extended),
+                PAUSE_PREFIX => self.enter(State::Pause {
+                    remaining: SET1_PAUSE_REMAINING,
+                }),
+                _ => set1_event(byte, set1_key),
+            },
+            (ScancodeSet::Set1, _) => {
+                self.state = State::Start;
+                set1_event(byte, set1_extended_key)
+            }
+            (ScancodeSet::Set2, State::Start) => match byte {
+                EXTENDED_PREFIX => self.enter(State::Extended),
+                SET2_RELEASE_PREFIX => self.enter(State::Release),
+                PAUSE_PREFIX => self.enter(State::Pause {
+                    remaining: SET2_PAUSE_REMAINING,
+                }),
+                _ => set2_key(byte).map(|code| KeyEvent::new(code, KeyState::Down)),
+            },
+            (ScancodeSet::Set2, State::Extended) => match byte {
+                SET2_RELEASE_PREFIX => self.enter(State::ExtendedRelease),
+                _ => {
+                    self.state = State::Start;
+                    set2_extended_key(byte).map(|code| KeyEvent::new(code, KeyState::Down))
+                }
+            },
+            (ScancodeSet::Set2, State::Release) => {
+                self.state = State::Start;
+                set2_key(byte).map(|code| KeyEvent::new(code, KeyState::Up))
+            }
+            (ScancodeSet::Set2, State::ExtendedRelease) => {
+                self.state = State::Start;
+                set2_extended_key(byte).map(|code| KeyEvent::new(code, KeyState::Up))
+            }
+        }
+    }
+
+    fn enter(&mut self, state: State) -> Option<KeyEvent> {
+        self.state = state;
+        None
+    }
+}
+
+// In set 1 a release is the make code with the top bit set
+fn set1_event(byte: u8, lookup: fn(u8) -> Option<KeyCode>) -> Option<KeyEvent> {
+    let state = if byte & SET1_RELEASE_BIT != 0 {
+        KeyState::Up
+    } else {
+        KeyState::Down
+    };
+    lookup(byte & !SET1_RELEASE_BIT).map(|code| KeyEvent::new(code, state))
+}
+
+fn set1_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => Escape,
+        0x02 => Key1,
+        0x03 => Key2,
+        0x04 => Key3,
+        0x05 => Key4,
+        0x06 => Key5,
+        0x07 => Key6,
+        0x08 => Key7,
+        0x09 => Key8,
+        0x0a => Key9,
+        0x0b => Key0,
+        0x0c => Minus,
+        0x0d => Equals,
+        0x0e => Backspace,
+        0x0f => Tab,
+        0x10 => Q,
+        0x11 => W,
+        0x12 => E,
+        0x13 => R,
+        0x14 => T,
+        0x15 => Y,
+        0x16 => U,
+        0x17 => I,
+        0x18 => O,
+        0x19 => P,
+        0x1a => LeftBracket,
+        0x1b => RightBracket,
+        0x1c => Enter,
+        0x1d => LControl,
+        0x1e => A,
+        0x1f => S,
+        0x20 => D,
+        0x21 => F,
+        0x22 => G,
+        0x23 => H,
+        0x24 => J,
+        0x25 => K,
+        0x26 => L,
+        0x27 => SemiColon,
+        0x28 => Quote,
+        0x29 => Backtick,
+        0x2a => LShift,
+        0x2b => Backslash,
+        0x2c => Z,
+        0x2d => X,
+        0x2e => C,
+        0x2f => V,
+        0x30 => B,
+        0x31 => N,
+        0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RShift,
+        0x37 => NumpadMultiply,
+        0x38 => LAlt,
+        0x39 => Spacebar,
+        0x3a => CapsLock,
+        0x3b => F1,
+        0x3c => F2,
+        0x3d => F3,
+        0x3e => F4,
+        0x3f => F5,
+        0x40 => F6,
+        0x41 => F7,
+        0x42 => F8,
+        0x43 => F9,
+        0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Numpad7,
+        0x48 => Numpad8,
+        0x49 => Numpad9,
+        0x4a => NumpadSubtract,
+        0x4b => Numpad4,
+        0x4c => Numpad5,
+        0x4d => Numpad6,
+        0x4e => NumpadAdd,
+        0x4f => Numpad1,
+        0x50 => Numpad2,
+        0x51 => Numpad3,
+        0x52 => Numpad0,
+        0x53 => NumpadPeriod,
+        0x56 => NonUsBackslash,
+        0x57 => F11,
+        0x58 => F12,
+        _ => return None,
+    })
+}
+
+// E0 2A and E0 36 are fake shifts some keyboards wrap around keys like Print Screen; they
+// have no entry here and are dropped
+fn set1_extended_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x1c => NumpadEnter,
+        0x1d => RControl,
+        0x35 => NumpadDivide,
+        0x37 => PrintScreen,
+        0x38 => RAltGr,
+        0x47 => Home,
+        0x48 => ArrowUp,
+        0x49 => PageUp,
+        0x4b => ArrowLeft,
+        0x4d => ArrowRight,
+        0x4f => End,
+        0x50 => ArrowDown,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5b => LWin,
+        0x5c => RWin,
+        0x5d => Apps,
+        _ => return None,
+    })
+}
+
+fn set2_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0a => F8,
+        0x0b => F6,
+        0x0c => F4,
+        0x0d => Tab,
+        0x0e => Backtick,
+        0x11 => LAlt,
+        0x12 => LShift,
+        0x14 => LControl,
+        0x15 => Q,
+        0x16 => Key1,
+        0x1a => Z,
+        0x1b => S,
+        0x1c => A,
+        0x1d => W,
+        0x1e => Key2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Key4,
+        0x26 => Key3,
+        0x29 => Spacebar,
+        0x2a => V,
+        0x2b => F,
+        0x2c => T,
+        0x2d => R,
+        0x2e => Key5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Key6,
+        0x3a => M,
+        0x3b => J,
+        0x3c => U,
+        0x3d => Key7,
+        0x3e => Key8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Key0,
+        0x46 => Key9,
+        0x49 => Period,
+        0x4a => Slash,
+        0x4b => L,
+        0x4c => SemiColon,
+        0x4d => P,
+        0x4e => Minus,
+        0x52 => Quote,
+        0x54 => LeftBracket,
+        0x55 => Equals,
+        0x58 => CapsLock,
+        0x59 => RShift,
+        0x5a => Enter,
+        0x5b => RightBracket,
+        0x5d => Backslash,
+        0x61 => NonUsBackslash,
+        0x66 => Backspace,
+        0x69 => Numpad1,
+        0x6b => Numpad4,
+        0x6c => Numpad7,
+        0x70 => Numpad0,
+        0x71 => NumpadPeriod,
+        0x72 => Numpad2,
+        0x73 => Numpad5,
+        0x74 => Numpad6,
+        0x75 => Numpad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => NumpadAdd,
+        0x7a => Numpad3,
+        0x7b => NumpadSubtract,
+        0x7c => NumpadMultiply,
+        0x7d => Numpad9,
+        0x7e => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    })
+}
+
+// E0 12 and E0 59 are the fake shifts of set 2
+fn set2_extended_key(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+    Some(match code {
+        0x11 => RAltGr,
+        0x14 => RControl,
+        0x1f => LWin,
+        0x27 => RWin,
+        0x2f => Apps,
+        0x4a => NumpadDivide,
+        0x5a => NumpadEnter,
+        0x69 => End,
+        0x6b => ArrowLeft,
+        0x6c => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => ArrowDown,
+        0x74 => ArrowRight,
+        0x75 => ArrowUp,
+        0x7a => PageDown,
+        0x7c => PrintScreen,
+        0x7d => PageUp,
+        _ => return None,
+    })
+}
@@ -0,0 +1,69 @@
+// The kernel as a library, so that the kernel binary and every test kernel under `tests/`
+// can share the same drivers.
+#![no_std]
+#![cfg_attr(test, no_main)]
+#![feature(alloc_error_handler)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+pub mod acpi;
+pub mod allocator;
+pub mod apic;
+pub mod gdt;
+pub mod interrupts;
+pub mod keyboard;
+pub mod memory;
+pub mod mouse;
+pub mod pic;
+pub mod pit;
+pub mod process;
+pub mod ps2;
+pub mod queue;
+pub mod serial;
+pub mod sync;
+pub mod task;
+pub mod testing;
+pub mod thread;
+pub mod time;
+pub mod user;
+pub mod vga_buffer;
+
+use bootloader::BootInfo;
+
+/// Sets up the CPU state the rest of the kernel relies on. Called first thing in `_start`.
+pub fn init(boot_info: &'static BootInfo) {
+    memory::init(boot_info);
+    allocator::init_heap().expect("failed to map the kernel heap");
+    gdt::init();
+    user::init();
+    interrupts::init_idt();
+    thread::init();
+    keyboard::init();
+    mouse::init();
+    interrupts::init_hardware_interrupts();
+}
+
+/// Halts the CPU until the next interrupt, forever. Unlike a busy loop this lets the CPU rest.
+pub fn hlt_loop() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+// Entry point of the test kernel for `cargo test --lib`
+#[cfg(test)]
+#[no_mangle]
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
+    test_main();
+    hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    testing::test_panic_handler(info)
+}
@@ -2,17 +2,99 @@
 #![no_std]
 // telling the compiler to not use the normal entry point chain
 #![no_main]
+// the kernel brings its own test harness, since the standard one needs `std`
+#![feature(custom_test_frameworks)]
+#![test_runner(focus_os::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
+use bootloader::BootInfo;
 use core::panic::PanicInfo;
+use focus_os::task::{Executor, StreamExt, Task};
+use focus_os::{print, println, serial_println, vga_buffer};
 
-// This function is called on panic
+// This function is called on panic. It reports where and why the kernel panicked on both
+// the screen and the serial port, then halts the CPU for good.
+#[cfg(not(test))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    // keep interrupt handlers from printing into the report, or waking the CPU up afterwards
+    x86_64::instructions::interrupts::disable();
+
+    // We might have panicked in the middle of a print, in which case the output locks are
+    // still held and would never be released. Nothing else runs anymore, so take them over.
+    unsafe {
+        vga_buffer::WRITER.force_unlock();
+        focus_os::serial::SERIAL1.force_unlock();
+    }
+
+    vga_buffer::WRITER
+        .lock()
+        .set_color(vga_buffer::Color::LightRed, vga_buffer::Color::Black);
+
+    match info.location() {
+        Some(location) => {
+            println!(
+                "KERNEL PANIC at {}:{}:{}",
+                location.file(),
+                location.line(),
+                location.column()
+            );
+            serial_println!(
+                "KERNEL PANIC at {}:{}:{}",
+                location.file(),
+                location.line(),
+                location.column()
+            );
+        }
+        None => {
+            println!("KERNEL PANIC at unknown location");
+            serial_println!("KERNEL PANIC at unknown location");
+        }
+    }
+    println!("{}", info.message());
+    serial_println!("{}", info.message());
+
+    focus_os::hlt_loop();
+}
+
+// Test kernels report panics over serial and exit QEMU instead
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    focus_os::testing::test_panic_handler(info)
 }
 
 // Creating an entry point. Also tells the compiler to use the C calling convention, rather than the rust convention.
+// The bootloader looks for this symbol when it loads the kernel from the disk image, and passes
+// it the boot information.
 #[no_mangle]
-pub extern "C" fn _start() -> ! {
-    loop {}
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    // the bootloader leaves its own messages on the screen
+    vga_buffer::WRITER.lock().clear_screen();
+    println!("Hello from focus_os{}", "!");
+    serial_println!("Hello from focus_os{}", "!");
+
+    focus_os::init(boot_info);
+
+    #[cfg(test)]
+    test_main();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(echo_keys()));
+    executor.run();
+}
+
+// Prints what is typed. F12 dumps the heap usage over serial.
+async fn echo_keys() {
+    use focus_os::allocator;
+    use focus_os::keyboard::{DecodedKey, KeyCode, KeyStream};
+
+    let mut keys = KeyStream::new();
+    while let Some(key) = keys.next().await {
+        match key {
+            DecodedKey::Unicode(c) => print!("{}", c),
+            DecodedKey::RawKey(KeyCode::F12) => allocator::dump(),
+            DecodedKey::RawKey(_) => {}
+        }
+    }
 }
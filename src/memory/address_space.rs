@@ -0,0 +1,321 @@
+// User address spaces. Each one has a PML4 of its own, which shares all of the kernel's
+// entries and keeps the user area in a range of PML4 slots the kernel never uses. The
+// kernel's mappings are supervisor only, so user code can't touch them, but they stay
+// where they are when the kernel is entered from user mode.
+//
+// Since only the PML4 is copied, a mapping the kernel adds later is visible in every
+// address space as long as it lands below an existing PML4 entry. The heap, the kernel
+// stacks and the device mappings each live below one entry that is set up at boot, before
+// any address space exists.
+
+use super::buddy::BuddyFrameAllocator;
+use super::frame::{frame_allocator, FRAME_SIZE};
+use super::paging::{kernel_page_tables, MapError, PageTables};
+use super::phys_to_virt;
+use crate::sync::SpinLock;
+use core::fmt;
+use core::ops::Range;
+use core::ptr;
+use x86_64::structures::paging::{Page, PageTable, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+/// The lowest address user code can map, at the start of PML4 slot 192.
+pub const USER_START: u64 = 0x6000_0000_0000;
+/// One past the highest user address. The last page of the lower half stays unmapped: a
+/// `syscall` at its very end would return to a non-canonical address, and `sysret` faults on
+/// those in ring 0, on the user's stack.
+pub const USER_END: u64 = 0x7fff_ffff_f000;
+
+// The bootloader puts its mappings in the lowest free PML4 slots, and the kernel's heap,
+// stacks and device mappings are in slots 136, 170 and 176
+const USER_SLOTS: Range<usize> = 192..256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserMemoryError {
+    /// The range isn't entirely inside the user area.
+    OutOfRange,
+    /// Part of the range isn't mapped, or not with the access that was asked for.
+    NotMapped,
+    Map(MapError),
+}
+
+impl From<MapError> for UserMemoryError {
+    fn from(error: MapError) -> UserMemoryError {
+        UserMemoryError::Map(error)
+    }
+}
+
+/// A page table hierarchy for user code, with the kernel mapped into it. Every page mapped
+/// in the user area owns its frame, which is freed together with the page tables when the
+/// address space is dropped.
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    tables: SpinLock<PageTables>,
+}
+
+impl fmt::Debug for AddressSpace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AddressSpace")
+            .field("pml4", &self.pml4.start_address())
+            .finish()
+    }
+}
+
+impl AddressSpace {
+    /// Creates an address space with nothing mapped in the user area.
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let kernel = kernel_page_tables();
+        let pml4 = frame_allocator()
+            .allocate()
+            .ok_or(MapError::FrameAllocationFailed)?;
+        // the frame is fresh, and the kernel's PML4 can't change while we hold its lock
+        unsafe {
+            let table = &mut *phys_to_virt(pml4.start_address()).as_mut_ptr::<PageTable>();
+            let kernel_table =
+                &*phys_to_virt(kernel.pml4_frame().start_address()).as_ptr::<PageTable>();
+            for (index, entry) in kernel_table.iter().enumerate() {
+                if USER_SLOTS.contains(&index) {
+                    assert!(entry.is_unused(), "kernel mapping in the user area");
+                    table[index].set_unused();
+                } else {
+                    table[index] = entry.clone();
+                }
+            }
+        }
+        Ok(AddressSpace {
+            pml4,
+            tables: SpinLock::new(unsafe { PageTables::from_pml4(pml4) }),
+        })
+    }
+
+    /// The frame of the PML4, to load into CR3.
+    pub fn root(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    /// Maps fresh zeroed frames to all pages overlapping `start..start + size`. The pages are
+    /// user accessible, with `flags` on top.
+    ///
+    /// If mapping fails halfway, the pages mapped so far stay mapped.
+    pub fn map(
+        &self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), UserMemoryError> {
+        let end = check_range(start, size)?;
+        if size == 0 {
+            return Ok(());
+        }
+        let flags = flags | PageTableFlags::USER_ACCESSIBLE;
+        let mut tables = self.tables.lock();
+        let mut frames = frame_allocator();
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(VirtAddr::new(end - 1)),
+        );
+        for page in pages {
+            let frame = frames.allocate().ok_or(MapError::FrameAllocationFailed)?;
+            // the frame is fresh, and the user area belongs to this address space alone
+            unsafe {
+                ptr::write_bytes(
+                    phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                    0,
+                    FRAME_SIZE as usize,
+                );
+                if let Err(error) = tables.map(page, frame, flags, &mut *frames) {
+                    frames.free(frame);
+                    return Err(error.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `addr` is mapped with at least `flags`.
+    pub fn is_mapped(&self, addr: VirtAddr, flags: PageTableFlags) -> bool {
+        self.tables
+            .lock()
+            .translate(addr)
+            .is_some_and(|translation| translation.flags.contains(flags))
+    }
+
+    /// Copies user memory at `addr` into `buf`, as user code could read it.
+    pub fn read(&self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), UserMemoryError> {
+        let flags = PageTableFlags::USER_ACCESSIBLE;
+        self.copy(addr, buf.len(), flags, |offset, memory| {
+            buf[offset..offset + memory.len()].copy_from_slice(memory);
+        })
+    }
+
+    /// Copies `bytes` into user memory at `addr`, as user code could write it.
+    pub fn write(&self, addr: VirtAddr, bytes: &[u8]) -> Result<(), UserMemoryError> {
+        let flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+        self.copy(addr, bytes.len(), flags, |offset, memory| {
+            memory.copy_from_slice(&bytes[offset..offset + memory.len()]);
+        })
+    }
+
+    /// Copies `bytes` into user memory at `addr` even if the pages are read only, for
+    /// setting up a program before it runs.
+    pub fn load(&self, addr: VirtAddr, bytes: &[u8]) -> Result<(), UserMemoryError> {
+        let flags = PageTableFlags::USER_ACCESSIBLE;
+        self.copy(addr, bytes.len(), flags, |offset, memory| {
+            memory.copy_from_slice(&bytes[offset..offset + memory.len()]);
+        })
+    }
+
+    // Calls `f` with the offset into the range and the memory behind it, page by page. The
+    // memory is reached through the physical memory mapping, so this works no matter which
+    // address space is active.
+    fn copy(
+        &self,
+        addr: VirtAddr,
+        len: usize,
+        flags: PageTableFlags,
+        mut f: impl FnMut(usize, &mut [u8]),
+    ) -> Result<(), UserMemoryError> {
+        check_range(addr, len as u64)?;
+        let tables = self.tables.lock();
+        let mut offset = 0;
+        while offset < len {
+            let current = addr + offset as u64;
+            let translation = tables
+                .translate(current)
+                .filter(|translation| translation.flags.contains(flags))
+                .ok_or(UserMemoryError::NotMapped)?;
+            let in_page = (FRAME_SIZE - u64::from(current.page_offset())) as usize;
+            let chunk = in_page.min(len - offset);
+            // the page is mapped into this address space, which owns its frame
+            let memory = unsafe {
+                core::slice::from_raw_parts_mut(phys_to_virt(translation.addr).as_mut_ptr(), chunk)
+            };
+            f(offset, memory);
+            offset += chunk;
+        }
+        Ok(())
+    }
+}
+
+// Returns the end of the range if it's inside the user area
+fn check_range(start: VirtAddr, size: u64) -> Result<u64, UserMemoryError> {
+    let end = start
+        .as_u64()
+        .checked_add(size)
+        .ok_or(UserMemoryError::OutOfRange)?;
+    if start.as_u64() < USER_START || end > USER_END {
+        return Err(UserMemoryError::OutOfRange);
+    }
+    Ok(end)
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let mut frames = frame_allocator();
+        // Nothing runs in the address space anymore, and everything below the user slots
+        // belongs to it. There are no huge pages in the user area.
+        unsafe {
+            debug_assert!(!self.tables.get_mut().is_active());
+            let table = &mut *phys_to_virt(self.pml4.start_address()).as_mut_ptr::<PageTable>();
+            for entry in table.iter_mut().take(USER_SLOTS.end).skip(USER_SLOTS.start) {
+                if !entry.is_unused() {
+                    free_table(PhysFrame::containing_address(entry.addr()), 3, &mut frames);
+                }
+            }
+            frames.free(self.pml4);
+        }
+    }
+}
+
+// Frees a table below the PML4, everything it maps and its own frame. `level` is 3 for a
+// page directory pointer table and 1 for a page table.
+unsafe fn free_table(frame: PhysFrame, level: u8, frames: &mut BuddyFrameAllocator) {
+    let table = unsafe { &*phys_to_virt(frame.start_address()).as_ptr::<PageTable>() };
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        let child = PhysFrame::containing_address(entry.addr());
+        if level > 1 {
+            unsafe { free_table(child, level - 1, frames) };
+        } else {
+            unsafe { frames.free(child) };
+        }
+    }
+    unsafe { frames.free(frame) };
+}
+
+#[test_case]
+fn test_kernel_is_mapped() {
+    let space = AddressSpace::new().unwrap();
+    let kernel = kernel_page_tables();
+    let code = VirtAddr::from_ptr(kernel_page_tables as *const ());
+    let tables = space.tables.lock();
+    assert_eq!(tables.translate(code), kernel.translate(code));
+    assert!(!tables
+        .translate(code)
+        .unwrap()
+        .flags
+        .contains(PageTableFlags::USER_ACCESSIBLE));
+}
+
+#[test_case]
+fn test_later_device_mappings_are_shared() {
+    use super::mmio;
+    use x86_64::PhysAddr;
+
+    let space = AddressSpace::new().unwrap();
+    let registers = mmio::map(PhysAddr::new(0xb8000), 4).unwrap();
+    let translation = space.tables.lock().translate(registers);
+    assert_eq!(translation, kernel_page_tables().translate(registers));
+    assert!(translation.is_some());
+}
+
+#[test_case]
+fn test_map_read_and_write() {
+    let space = AddressSpace::new().unwrap();
+    let start = VirtAddr::new(USER_START + 0x1000);
+    space.map(start, 0x2000, PageTableFlags::WRITABLE).unwrap();
+    assert!(space.is_mapped(start, PageTableFlags::USER_ACCESSIBLE));
+    // crossing the page boundary in the middle
+    let addr = start + 0xffeu64;
+    space.write(addr, b"hello").unwrap();
+    let mut buf = [0; 5];
+    space.read(addr, &mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+    assert_eq!(
+        space.read(start + 0x1ffeu64, &mut buf),
+        Err(UserMemoryError::NotMapped)
+    );
+    assert_eq!(
+        space.read(VirtAddr::new(USER_START - 2), &mut buf),
+        Err(UserMemoryError::OutOfRange)
+    );
+}
+
+#[test_case]
+fn test_read_only_pages() {
+    let space = AddressSpace::new().unwrap();
+    let start = VirtAddr::new(USER_START);
+    space.map(start, 0x1000, PageTableFlags::empty()).unwrap();
+    assert_eq!(space.write(start, b"x"), Err(UserMemoryError::NotMapped));
+    space.load(start, b"x").unwrap();
+    let mut buf = [0; 1];
+    space.read(start, &mut buf).unwrap();
+    assert_eq!(&buf, b"x");
+}
+
+#[test_case]
+fn test_frames_are_freed() {
+    let before = frame_allocator().stats().free;
+    let space = AddressSpace::new().unwrap();
+    space
+        .map(VirtAddr::new(USER_START), 0x5000, PageTableFlags::WRITABLE)
+        .unwrap();
+    space
+        .map(
+            VirtAddr::new(USER_END - 0x1000),
+            0x1000,
+            PageTableFlags::empty(),
+        )
+        .unwrap();
+    drop(space);
+    assert_eq!(frame_allocator().stats().free, before);
+}
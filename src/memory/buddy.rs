@@ -0,0 +1,350 @@
+// A buddy allocator for physical frames. Free memory is kept in blocks of 2^order frames,
+// each aligned to its own size. A request takes the smallest block that fits and splits off
+// the halves it doesn't need. When a block is freed and its buddy (the other half of the
+// block one order up) is free too, the two merge back together.
+//
+// Devices that can't address all of memory need frames from below their limit, so the free
+// blocks are kept per zone. The zone boundaries are aligned far beyond the largest block,
+// which keeps blocks and their buddies from ever straddling two zones.
+
+use super::frame::{FrameStats, FRAME_SIZE};
+use super::phys_to_virt;
+use crate::serial_println;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ptr;
+use core::slice;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+/// The largest block is 2^MAX_ORDER frames, i.e. 4 MiB.
+pub const MAX_ORDER: usize = 10;
+const ORDERS: usize = MAX_ORDER + 1;
+
+// Per frame state: the first frame of a free block has this bit set along with the order
+const FREE_BLOCK: u8 = 0x80;
+
+/// A range of physical memory for devices with limited addressing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 16 MiB, reachable by the ISA DMA controller.
+    Dma,
+    /// Below 4 GiB, reachable by devices with 32 bit addresses.
+    Dma32,
+    Normal,
+}
+
+const ZONES: [Zone; 3] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+const DMA_LIMIT: u64 = 16 << 20;
+const DMA32_LIMIT: u64 = 4 << 30;
+
+impl Zone {
+    fn of(frame: u64) -> Zone {
+        match frame * FRAME_SIZE {
+            addr if addr < DMA_LIMIT => Zone::Dma,
+            addr if addr < DMA32_LIMIT => Zone::Dma32,
+            _ => Zone::Normal,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    // The zones an allocation for this zone may come from, preferring the least precious
+    fn candidates(self) -> &'static [Zone] {
+        match self {
+            Zone::Dma => &[Zone::Dma],
+            Zone::Dma32 => &[Zone::Dma32, Zone::Dma],
+            Zone::Normal => &[Zone::Normal, Zone::Dma32, Zone::Dma],
+        }
+    }
+}
+
+/// The order of the smallest block holding `frames` frames.
+pub fn order_for(frames: usize) -> usize {
+    frames.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+// Lives in the first bytes of a free block, through the physical memory mapping
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+pub struct BuddyFrameAllocator {
+    // one byte per frame up to the end of usable memory, see `FREE_BLOCK`
+    frames: &'static mut [u8],
+    free_lists: [[*mut FreeBlock; ORDERS]; ZONES.len()],
+    free_blocks: [[usize; ORDERS]; ZONES.len()],
+    stats: FrameStats,
+}
+
+// The free lists point into memory that belongs to the allocator
+unsafe impl Send for BuddyFrameAllocator {}
+
+impl BuddyFrameAllocator {
+    /// Builds the allocator from the bootloader's memory map.
+    ///
+    /// # Safety
+    ///
+    /// The memory map must be correct, i.e. nothing may be using the frames it calls usable.
+    /// There must only ever be one allocator for them.
+    pub unsafe fn new(memory_map: &MemoryMap) -> BuddyFrameAllocator {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|region| region.region_type == MemoryRegionType::Usable)
+                .map(|region| region.range.start_frame_number..region.range.end_frame_number)
+        };
+        let frame_count = usable().map(|range| range.end).max().unwrap_or(0);
+        let state_frames = frame_count.div_ceil(FRAME_SIZE);
+
+        // the per frame state goes at the start of the first region large enough for it
+        let location = usable()
+            .find(|range| range.end - range.start >= state_frames)
+            .expect("no usable memory region can hold the frame allocator state");
+        let frames = unsafe {
+            slice::from_raw_parts_mut(
+                phys_to_virt(PhysAddr::new(location.start * FRAME_SIZE)).as_mut_ptr(),
+                frame_count as usize,
+            )
+        };
+        frames.fill(0);
+
+        let mut allocator = BuddyFrameAllocator {
+            frames,
+            free_lists: [[ptr::null_mut(); ORDERS]; ZONES.len()],
+            free_blocks: [[0; ORDERS]; ZONES.len()],
+            stats: FrameStats {
+                total: state_frames as usize,
+                free: 0,
+                allocations: 0,
+                frees: 0,
+            },
+        };
+        for range in usable() {
+            let mut frame = range.start;
+            if range.start == location.start {
+                frame += state_frames;
+            }
+            // carve the region into the largest aligned blocks that fit
+            while frame < range.end {
+                let order = (0..=MAX_ORDER)
+                    .rev()
+                    .find(|&order| {
+                        frame.is_multiple_of(1 << order) && frame + (1 << order) <= range.end
+                    })
+                    .unwrap();
+                allocator.insert(frame, order);
+                allocator.stats.free += 1 << order;
+                allocator.stats.total += 1 << order;
+                frame += 1 << order;
+            }
+        }
+        allocator
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// The number of free blocks of the given order in a zone.
+    pub fn free_blocks(&self, zone: Zone, order: usize) -> usize {
+        self.free_blocks[zone.index()][order]
+    }
+
+    fn block(frame: u64) -> *mut FreeBlock {
+        phys_to_virt(PhysAddr::new(frame * FRAME_SIZE)).as_mut_ptr()
+    }
+
+    fn push(&mut self, frame: u64, order: usize) {
+        let zone = Zone::of(frame).index();
+        let block = Self::block(frame);
+        let head = self.free_lists[zone][order];
+        // the block is free, so its memory is ours to link it with
+        unsafe {
+            block.write(FreeBlock {
+                next: head,
+                prev: ptr::null_mut(),
+            });
+            if !head.is_null() {
+                (*head).prev = block;
+            }
+        }
+        self.free_lists[zone][order] = block;
+        self.free_blocks[zone][order] += 1;
+        self.frames[frame as usize] = FREE_BLOCK | order as u8;
+    }
+
+    fn remove(&mut self, frame: u64, order: usize) {
+        let zone = Zone::of(frame).index();
+        let block = Self::block(frame);
+        unsafe {
+            let FreeBlock { next, prev } = block.read();
+            if prev.is_null() {
+                self.free_lists[zone][order] = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+        self.free_blocks[zone][order] -= 1;
+        self.frames[frame as usize] = 0;
+    }
+
+    fn is_free_block(&self, frame: u64, order: usize) -> bool {
+        self.frames.get(frame as usize) == Some(&(FREE_BLOCK | order as u8))
+    }
+
+    // Adds a free block, merging it with its buddy as long as that is free as well
+    fn insert(&mut self, mut frame: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    /// Takes a block of 2^order frames from the given zone or one below it. The block is
+    /// aligned to its size.
+    pub fn allocate_order(&mut self, order: usize, zone: Zone) -> Option<PhysFrame> {
+        assert!(order <= MAX_ORDER, "no blocks of order {}", order);
+        let (zone, found) = zone.candidates().iter().find_map(|&zone| {
+            (order..=MAX_ORDER)
+                .find(|&found| !self.free_lists[zone.index()][found].is_null())
+                .map(|found| (zone, found))
+        })?;
+        let block = self.free_lists[zone.index()][found];
+        let frame = (block as u64 - phys_to_virt(PhysAddr::new(0)).as_u64()) / FRAME_SIZE;
+        self.remove(frame, found);
+        // give back the upper halves until the block has the right size
+        for split in (order..found).rev() {
+            self.push(frame + (1 << split), split);
+        }
+        self.stats.free -= 1 << order;
+        self.stats.allocations += 1;
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            frame * FRAME_SIZE,
+        )))
+    }
+
+    /// Takes at least `count` physically contiguous frames. The block is rounded up to a power
+    /// of two frames, which is also its alignment, and has to be freed with that order.
+    pub fn allocate_contiguous(&mut self, count: usize, zone: Zone) -> Option<PhysFrame> {
+        let order = order_for(count);
+        if order > MAX_ORDER {
+            return None;
+        }
+        self.allocate_order(order, zone)
+    }
+
+    /// Takes a single frame.
+    pub fn allocate(&mut self) -> Option<PhysFrame> {
+        self.allocate_order(0, Zone::Normal)
+    }
+
+    /// Returns a block taken with `allocate_order`.
+    ///
+    /// # Safety
+    ///
+    /// The block must have been allocated with the same order, and nothing may use it anymore.
+    pub unsafe fn free_order(&mut self, frame: PhysFrame, order: usize) {
+        let number = frame.start_address().as_u64() / FRAME_SIZE;
+        assert!(
+            number.is_multiple_of(1 << order)
+                && number + (1 << order) <= self.frames.len() as u64
+                && self.frames[number as usize] & FREE_BLOCK == 0,
+            "freeing frame {:#x} of order {}, which isn't allocated",
+            frame.start_address().as_u64(),
+            order
+        );
+        self.insert(number, order);
+        self.stats.free += 1 << order;
+        self.stats.frees += 1;
+    }
+
+    /// Returns a frame taken with `allocate`.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use the frame anymore.
+    pub unsafe fn free(&mut self, frame: PhysFrame) {
+        unsafe { self.free_order(frame, 0) }
+    }
+
+    /// Prints the free blocks of every zone and order over serial.
+    pub fn dump(&self) {
+        serial_println!("physical memory: {}", self.stats);
+        for zone in ZONES {
+            serial_println!("  {:?}: {:?}", zone, self.free_blocks[zone.index()]);
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        unsafe { self.free(frame) }
+    }
+}
+
+#[test_case]
+fn test_split_and_merge() {
+    let mut allocator = super::frame::frame_allocator();
+    let before = allocator.stats();
+    let block = allocator.allocate_order(3, Zone::Normal).unwrap();
+    assert_eq!(block.start_address().as_u64() % (8 * FRAME_SIZE), 0);
+    assert_eq!(allocator.stats().free, before.free - 8);
+
+    let frame = allocator.allocate().unwrap();
+    unsafe {
+        allocator.free(frame);
+        allocator.free_order(block, 3);
+    }
+    assert_eq!(allocator.stats().free, before.free);
+    // everything merged back into the blocks we started with
+    let largest = |allocator: &BuddyFrameAllocator| {
+        ZONES
+            .iter()
+            .map(|&zone| allocator.free_blocks(zone, MAX_ORDER))
+            .sum::<usize>()
+    };
+    let blocks = largest(&allocator);
+    let block = allocator.allocate_order(MAX_ORDER, Zone::Normal).unwrap();
+    assert_eq!(largest(&allocator), blocks - 1);
+    unsafe { allocator.free_order(block, MAX_ORDER) };
+    assert_eq!(largest(&allocator), blocks);
+}
+
+#[test_case]
+fn test_zone_limits() {
+    let mut allocator = super::frame::frame_allocator();
+    let dma = allocator.allocate_contiguous(16, Zone::Dma).unwrap();
+    assert!(dma.start_address().as_u64() + 16 * FRAME_SIZE <= DMA_LIMIT);
+    let dma32 = allocator.allocate_order(2, Zone::Dma32).unwrap();
+    assert!(dma32.start_address().as_u64() + 4 * FRAME_SIZE <= DMA32_LIMIT);
+    unsafe {
+        allocator.free_order(dma, order_for(16));
+        allocator.free_order(dma32, 2);
+    }
+}
+
+#[test_case]
+fn test_order_for() {
+    assert_eq!(order_for(0), 0);
+    assert_eq!(order_for(1), 0);
+    assert_eq!(order_for(5), 3);
+    assert_eq!(order_for(1024), 10);
+}
@@ -0,0 +1,96 @@
+// Physical frame allocation from the memory map the bootloader hands to the kernel. The
+// frames are managed by the buddy allocator, which can also hand out contiguous blocks.
+
+use super::buddy::BuddyFrameAllocator;
+use crate::sync::{Once, SpinLock, SpinLockGuard};
+use bootloader::bootinfo::MemoryMap;
+use core::fmt;
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/// How much physical memory there is, and how much of it is taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Usable frames according to the memory map.
+    pub total: usize,
+    pub free: usize,
+    pub allocations: usize,
+    pub frees: usize,
+}
+
+impl FrameStats {
+    pub fn used(&self) -> usize {
+        self.total - self.free
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kib = |frames: usize| frames as u64 * FRAME_SIZE / 1024;
+        write!(
+            f,
+            "{} KiB of {} KiB free ({} frames used, {} allocations, {} frees)",
+            kib(self.free),
+            kib(self.total),
+            self.used(),
+            self.allocations,
+            self.frees
+        )
+    }
+}
+
+static FRAME_ALLOCATOR: Once<SpinLock<BuddyFrameAllocator>> = Once::new();
+
+pub(super) fn init(memory_map: &MemoryMap) {
+    // the bootloader's memory map is what we build the allocator from
+    FRAME_ALLOCATOR.call_once(|| SpinLock::new(unsafe { BuddyFrameAllocator::new(memory_map) }));
+}
+
+/// The allocator for all usable physical memory.
+pub fn frame_allocator() -> SpinLockGuard<'static, BuddyFrameAllocator> {
+    FRAME_ALLOCATOR
+        .get()
+        .expect("memory::init has not been called")
+        .lock()
+}
+
+#[test_case]
+fn test_allocate_and_free() {
+    let mut allocator = frame_allocator();
+    let before = allocator.stats();
+    let first = allocator.allocate().unwrap();
+    let second = allocator.allocate().unwrap();
+    assert_ne!(first, second);
+    assert_eq!(allocator.stats().free, before.free - 2);
+    assert_eq!(allocator.stats().allocations, before.allocations + 2);
+
+    unsafe {
+        allocator.free(first);
+        allocator.free(second);
+    }
+    assert_eq!(allocator.stats().free, before.free);
+    assert_eq!(allocator.stats().frees, before.frees + 2);
+}
+
+#[test_case]
+fn test_allocated_frames_are_usable() {
+    let mut allocator = frame_allocator();
+    let frame = allocator.allocate().unwrap();
+    // the frame is ours, so writing all of it must not break anything
+    let words = super::phys_to_virt(frame.start_address()).as_mut_ptr::<u64>();
+    unsafe {
+        for i in 0..(FRAME_SIZE / 8) as usize {
+            words.add(i).write_volatile(i as u64);
+        }
+        assert_eq!(words.add(17).read_volatile(), 17);
+        allocator.free(frame);
+    }
+}
+
+#[test_case]
+fn test_stats_are_consistent() {
+    let stats = frame_allocator().stats();
+    assert!(stats.total > 0);
+    assert!(stats.free <= stats.total);
+    assert_eq!(stats.used(), stats.total - stats.free);
+}
@@ -0,0 +1,73 @@
+// Mappings of memory mapped device registers. The physical memory mapping is cacheable, which
+// only works for devices as long as the firmware happens to mark their range uncacheable in
+// the MTRRs, so device registers get pages of their own with caching turned off.
+
+use super::frame::frame_allocator;
+use super::paging::{kernel_page_tables, MapError};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Where device mappings are placed, between the kernel stacks and the user area.
+pub const MMIO_AREA_START: u64 = 0x5800_0000_0000;
+const PAGE_SIZE: u64 = 4096;
+
+// Device mappings are never taken down, so the area is handed out in order
+static NEXT_ADDR: AtomicU64 = AtomicU64::new(MMIO_AREA_START);
+
+/// Sets up the area's PML4 entry, so devices mapped at any time are visible in every
+/// address space. Must run before the first address space is created.
+pub(super) fn init() {
+    kernel_page_tables()
+        .create_pml4_entry(VirtAddr::new(MMIO_AREA_START), &mut *frame_allocator())
+        .expect("failed to set up the MMIO area");
+}
+
+/// Maps the `size` bytes of device memory at `addr` uncached, and returns the virtual
+/// address of `addr`. The mapping stays for good; if it fails halfway, so do the pages
+/// mapped so far.
+pub fn map(addr: PhysAddr, size: u64) -> Result<VirtAddr, MapError> {
+    let first = PhysFrame::<Size4KiB>::containing_address(addr);
+    let last = PhysFrame::<Size4KiB>::containing_address(addr + size.max(1) - 1u64);
+    let pages = (last.start_address() - first.start_address()) / PAGE_SIZE + 1;
+    let start = NEXT_ADDR.fetch_add(pages * PAGE_SIZE, Ordering::Relaxed);
+
+    let flags = PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    let mut tables = kernel_page_tables();
+    let mut frames = frame_allocator();
+    for (i, frame) in PhysFrame::range_inclusive(first, last).enumerate() {
+        let page =
+            Page::<Size4KiB>::containing_address(VirtAddr::new(start + i as u64 * PAGE_SIZE));
+        // the page was just handed out, and device memory isn't used for anything else
+        unsafe { tables.map(page, frame, flags, &mut *frames)? };
+    }
+    Ok(VirtAddr::new(start) + addr.as_u64() % PAGE_SIZE)
+}
+
+#[test_case]
+fn test_map_device_memory() {
+    use super::phys_to_virt;
+    use core::ptr;
+
+    // the VGA text buffer, straddling a page boundary
+    let addr = PhysAddr::new(0xb8ffe);
+    let registers = map(addr, 4).unwrap();
+    assert_eq!(registers.as_u64() % PAGE_SIZE, 0xffe);
+    let translation = kernel_page_tables().translate(registers + 2u64).unwrap();
+    assert_eq!(translation.addr, addr + 2u64);
+    assert!(translation.flags.contains(PageTableFlags::NO_CACHE));
+
+    let alias = registers.as_mut_ptr::<u16>();
+    unsafe {
+        let original = ptr::read_volatile(alias);
+        ptr::write_volatile(alias, 0x0f21);
+        assert_eq!(
+            ptr::read_volatile(phys_to_virt(addr).as_ptr::<u16>()),
+            0x0f21
+        );
+        ptr::write_volatile(alias, original);
+    }
+}
@@ -0,0 +1,40 @@
+// Access to physical memory. The bootloader maps all of physical memory at an offset in the
+// virtual address space (the `map_physical_memory` feature), so any physical address can be
+// reached by adding that offset.
+
+pub mod address_space;
+pub mod buddy;
+pub mod frame;
+pub mod mmio;
+pub mod paging;
+
+use crate::serial_println;
+use bootloader::BootInfo;
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// Takes over the page tables and the memory map from the bootloader.
+pub fn init(boot_info: &'static BootInfo) {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| VirtAddr::new(boot_info.physical_memory_offset));
+    paging::init();
+    frame::init(&boot_info.memory_map);
+    mmio::init();
+    serial_println!("memory: {}", frame::frame_allocator().stats());
+}
+
+/// The start of the physical memory mapping.
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("memory::init has not been called")
+}
+
+/// The virtual address at which the given physical address is mapped.
+///
+/// The mapping covers everything up to the highest address in the firmware's memory map,
+/// which on PCs includes the memory mapped devices just below 4 GiB.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
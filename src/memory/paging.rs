@@ -0,0 +1,429 @@
+// The four-level page table hierarchy: the PML4 at the top, then the page directory pointer
+// tables, page directories and page tables. A page directory pointer table entry can map a
+// 1 GiB page directly and a page directory entry a 2 MiB page, everything else ends in 4 KiB
+// pages at the bottom level.
+//
+// The tables are reached through the physical memory mapping. Mapping and unmapping is left
+// to the `x86_64` crate's mapper, which does the same, walking and inspecting is done here.
+
+use super::phys_to_virt;
+use crate::serial_println;
+use crate::sync::{Once, SpinLock, SpinLockGuard};
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::{MapToError, UnmapError as MapperUnmapError};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame,
+    Size1GiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// CPUID leaf with the extended feature bits, and the one saying whether it exists
+const CPUID_EXTENDED_MAX: u32 = 0x8000_0000;
+const CPUID_EXTENDED_FEATURES: u32 = 0x8000_0001;
+const CPUID_PDPE1GB: u32 = 1 << 26;
+
+/// The size of the page an address is mapped with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MappingSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            MappingSize::Size4KiB => 4 << 10,
+            MappingSize::Size2MiB => 2 << 20,
+            MappingSize::Size1GiB => 1 << 30,
+        }
+    }
+}
+
+impl fmt::Display for MappingSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MappingSize::Size4KiB => "4K",
+            MappingSize::Size2MiB => "2M",
+            MappingSize::Size1GiB => "1G",
+        })
+    }
+}
+
+/// Where a virtual address is mapped to, and how.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub addr: PhysAddr,
+    pub size: MappingSize,
+    /// The flags that apply to the address. The page is only writable or user accessible if
+    /// every level allows it, and not executable if any level forbids it.
+    pub flags: PageTableFlags,
+}
+
+/// One page mapped in a hierarchy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub page: VirtAddr,
+    pub frame: PhysAddr,
+    pub size: MappingSize,
+    pub flags: PageTableFlags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// A page table was needed, but there was no frame left for it.
+    FrameAllocationFailed,
+    /// The page is part of a larger page that is already mapped.
+    ParentEntryHugePage,
+    AlreadyMapped,
+    /// The CPU can't map 1 GiB pages.
+    HugePagesUnsupported,
+}
+
+impl<S: PageSize> From<MapToError<S>> for MapError {
+    fn from(error: MapToError<S>) -> MapError {
+        match error {
+            MapToError::FrameAllocationFailed => MapError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MapError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(_) => MapError::AlreadyMapped,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnmapError {
+    /// The page is part of a larger page, which can only be unmapped as a whole.
+    ParentEntryHugePage,
+    NotMapped,
+    /// The entry holds an address that isn't a frame of the page's size.
+    InvalidFrameAddress(PhysAddr),
+}
+
+impl From<MapperUnmapError> for UnmapError {
+    fn from(error: MapperUnmapError) -> UnmapError {
+        match error {
+            MapperUnmapError::ParentEntryHugePage => UnmapError::ParentEntryHugePage,
+            MapperUnmapError::PageNotMapped => UnmapError::NotMapped,
+            MapperUnmapError::InvalidFrameAddress(addr) => UnmapError::InvalidFrameAddress(addr),
+        }
+    }
+}
+
+/// Whether the CPU supports 1 GiB pages.
+pub fn supports_1gib_pages() -> bool {
+    __cpuid(CPUID_EXTENDED_MAX).eax >= CPUID_EXTENDED_FEATURES
+        && __cpuid(CPUID_EXTENDED_FEATURES).edx & CPUID_PDPE1GB != 0
+}
+
+/// A page table hierarchy, identified by the frame of its PML4.
+pub struct PageTables {
+    pml4: PhysFrame,
+}
+
+impl PageTables {
+    /// # Safety
+    ///
+    /// The frame must hold a valid PML4, and no other `PageTables` may modify the same
+    /// hierarchy while this one is in use.
+    pub unsafe fn from_pml4(pml4: PhysFrame) -> PageTables {
+        PageTables { pml4 }
+    }
+
+    pub fn pml4_frame(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    /// Whether this is the hierarchy the CPU currently uses.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.pml4
+    }
+
+    fn table(&self, addr: PhysAddr) -> &PageTable {
+        // all tables of the hierarchy are reachable through the physical memory mapping, and
+        // only change through `&mut self`
+        unsafe { &*phys_to_virt(addr).as_ptr() }
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let pml4 = unsafe { &mut *phys_to_virt(self.pml4.start_address()).as_mut_ptr() };
+        unsafe { OffsetPageTable::new(pml4, super::physical_memory_offset()) }
+    }
+
+    /// Looks up where the given address is mapped to, if anywhere.
+    pub fn translate(&self, addr: VirtAddr) -> Option<Translation> {
+        let indices = [
+            addr.p4_index(),
+            addr.p3_index(),
+            addr.p2_index(),
+            addr.p1_index(),
+        ];
+        let mut table = self.table(self.pml4.start_address());
+        let mut flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        for (level, &index) in indices.iter().enumerate() {
+            let entry = &table[index];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            flags = combine_flags(flags, entry.flags());
+            let huge = entry.flags().contains(PageTableFlags::HUGE_PAGE);
+            let size = match level {
+                1 if huge => MappingSize::Size1GiB,
+                2 if huge => MappingSize::Size2MiB,
+                3 => MappingSize::Size4KiB,
+                _ => {
+                    table = self.table(entry.addr());
+                    continue;
+                }
+            };
+            return Some(Translation {
+                addr: entry.addr() + (addr.as_u64() & (size.bytes() - 1)),
+                size,
+                flags,
+            });
+        }
+        unreachable!("the bottom level always maps")
+    }
+
+    /// Gives the PML4 slot of `addr` an empty page directory pointer table, unless it has one
+    /// already. Address spaces copy the PML4, so whatever the kernel maps below the entry
+    /// later is shared with address spaces created after this.
+    pub fn create_pml4_entry(
+        &mut self,
+        addr: VirtAddr,
+        frames: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MapError> {
+        // only changes through `&mut self`, like every table of the hierarchy
+        let pml4 =
+            unsafe { &mut *phys_to_virt(self.pml4.start_address()).as_mut_ptr::<PageTable>() };
+        let entry = &mut pml4[addr.p4_index()];
+        if !entry.is_unused() {
+            return Ok(());
+        }
+        let frame = frames
+            .allocate_frame()
+            .ok_or(MapError::FrameAllocationFailed)?;
+        // the frame is fresh
+        unsafe {
+            phys_to_virt(frame.start_address())
+                .as_mut_ptr::<PageTable>()
+                .write(PageTable::new());
+        }
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        Ok(())
+    }
+
+    /// Maps `page` to `frame`. Page tables that don't exist yet are allocated from `frames`.
+    ///
+    /// # Safety
+    ///
+    /// The frame must not be in use for anything that the new mapping could break, and the
+    /// page must not be in use by the kernel.
+    pub unsafe fn map<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+        frames: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MapError>
+    where
+        for<'a> OffsetPageTable<'a>: Mapper<S>,
+    {
+        if S::SIZE == Size1GiB::SIZE && !supports_1gib_pages() {
+            return Err(MapError::HugePagesUnsupported);
+        }
+        let flush = unsafe {
+            self.mapper()
+                .map_to(page, frame, flags | PageTableFlags::PRESENT, frames)?
+        };
+        // The tables of the kernel half are shared between hierarchies, so even a change to
+        // an inactive hierarchy can be visible in the active one
+        flush.flush();
+        Ok(())
+    }
+
+    /// Removes the mapping of `page`, returning the frame it was mapped to.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may access the page anymore.
+    pub unsafe fn unmap<S: PageSize>(&mut self, page: Page<S>) -> Result<PhysFrame<S>, UnmapError>
+    where
+        for<'a> OffsetPageTable<'a>: Mapper<S>,
+    {
+        let (frame, flush) = self.mapper().unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    }
+
+    /// Calls `f` for every mapped page, in the order of the virtual addresses.
+    pub fn for_each_mapping(&self, mut f: impl FnMut(Mapping)) {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        self.walk(self.pml4.start_address(), 4, 0, flags, &mut f);
+    }
+
+    fn walk(
+        &self,
+        table: PhysAddr,
+        level: u8,
+        base: u64,
+        flags: PageTableFlags,
+        f: &mut impl FnMut(Mapping),
+    ) {
+        // each entry covers 512 times the area of an entry one level down
+        let entry_span = 1u64 << (12 + 9 * (u32::from(level) - 1));
+        for (index, entry) in self.table(table).iter().enumerate() {
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            // the upper half of the address space has the top bits set
+            let start = VirtAddr::new_truncate(base + index as u64 * entry_span);
+            let flags = combine_flags(flags, entry.flags());
+            let huge = entry.flags().contains(PageTableFlags::HUGE_PAGE);
+            let size = match level {
+                3 if huge => MappingSize::Size1GiB,
+                2 if huge => MappingSize::Size2MiB,
+                1 => MappingSize::Size4KiB,
+                _ => {
+                    self.walk(entry.addr(), level - 1, start.as_u64(), flags, f);
+                    continue;
+                }
+            };
+            f(Mapping {
+                page: start,
+                frame: entry.addr(),
+                size,
+                flags,
+            });
+        }
+    }
+
+    /// Prints the mappings of the hierarchy over serial. Consecutive pages of the same size
+    /// and flags that map consecutive frames are printed as one range.
+    pub fn dump(&self) {
+        serial_println!("page tables at {:#x}:", self.pml4.start_address().as_u64());
+        let mut range: Option<(Mapping, u64)> = None;
+        self.for_each_mapping(|mapping| {
+            if let Some((first, length)) = &mut range {
+                let continues = first.size == mapping.size
+                    && first.flags == mapping.flags
+                    && first.page + *length == mapping.page
+                    && first.frame + *length == mapping.frame;
+                if continues {
+                    *length += mapping.size.bytes();
+                    return;
+                }
+                print_range(first, *length);
+            }
+            range = Some((mapping, mapping.size.bytes()));
+        });
+        if let Some((first, length)) = range {
+            print_range(&first, length);
+        }
+    }
+}
+
+// Access rights narrow down from level to level
+fn combine_flags(upper: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let narrowing = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    (entry - narrowing) | (upper & entry & narrowing) | (upper & PageTableFlags::NO_EXECUTE)
+}
+
+fn print_range(first: &Mapping, length: u64) {
+    let flag = |flag, c| if first.flags.contains(flag) { c } else { '-' };
+    serial_println!(
+        "  {:#018x}-{:#018x} -> {:#014x} {} r{}{}{}{}",
+        first.page.as_u64(),
+        first.page.as_u64() + (length - 1),
+        first.frame.as_u64(),
+        first.size,
+        flag(PageTableFlags::WRITABLE, 'w'),
+        if first.flags.contains(PageTableFlags::NO_EXECUTE) {
+            '-'
+        } else {
+            'x'
+        },
+        flag(PageTableFlags::USER_ACCESSIBLE, 'u'),
+        flag(PageTableFlags::GLOBAL, 'g'),
+    );
+}
+
+static KERNEL_PAGE_TABLES: Once<SpinLock<PageTables>> = Once::new();
+
+pub(super) fn init() {
+    // Make the no-execute bit and read-only pages work for the kernel too. The bootloader
+    // already does this, but we rely on it.
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    // the bootloader's hierarchy becomes the kernel's
+    KERNEL_PAGE_TABLES.call_once(|| SpinLock::new(unsafe { PageTables::from_pml4(Cr3::read().0) }));
+}
+
+/// The page tables the kernel runs on.
+pub fn kernel_page_tables() -> SpinLockGuard<'static, PageTables> {
+    KERNEL_PAGE_TABLES
+        .get()
+        .expect("memory::init has not been called")
+        .lock()
+}
+
+#[cfg(test)]
+struct NoFrames;
+
+#[cfg(test)]
+unsafe impl FrameAllocator<Size4KiB> for NoFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        None
+    }
+}
+
+#[test_case]
+fn test_translate_physical_memory_mapping() {
+    let addr = PhysAddr::new(0xb8123);
+    let translation = kernel_page_tables().translate(phys_to_virt(addr)).unwrap();
+    assert_eq!(translation.addr, addr);
+    assert!(translation.flags.contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn test_translate_unmapped_address() {
+    // the bootloader leaves the first page unmapped to catch null pointers
+    assert_eq!(kernel_page_tables().translate(VirtAddr::new(0)), None);
+}
+
+#[test_case]
+fn test_map_and_unmap() {
+    use core::ptr;
+
+    let mut tables = kernel_page_tables();
+    // an unused page next to the VGA buffer, where the bottom level table already exists
+    let vga = VirtAddr::new(0xb8000);
+    // page 0 is unmapped too, but an alias there would be a null pointer
+    let page = Page::<Size4KiB>::range(
+        Page::containing_address(VirtAddr::new(0x1000)),
+        Page::containing_address(vga.align_up(2u64 << 20)),
+    )
+    .find(|page| tables.translate(page.start_address()).is_none())
+    .unwrap();
+    let frame = PhysFrame::containing_address(tables.translate(vga).unwrap().addr);
+
+    unsafe {
+        tables
+            .map(page, frame, PageTableFlags::WRITABLE, &mut NoFrames)
+            .unwrap();
+        // the VGA buffer is now visible through the new page too
+        let alias = page.start_address().as_mut_ptr::<u16>();
+        let original = ptr::read_volatile(alias);
+        ptr::write_volatile(alias, 0x0f21);
+        assert_eq!(ptr::read_volatile(vga.as_ptr::<u16>()), 0x0f21);
+        ptr::write_volatile(alias, original);
+
+        assert_eq!(tables.unmap(page), Ok(frame));
+        assert_eq!(tables.unmap(page), Err(UnmapError::NotMapped));
+    }
+    assert_eq!(tables.translate(page.start_address()), None);
+}
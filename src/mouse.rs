@@ -0,0 +1,341 @@
+// PS/2 mouse driver, for the device on the controller's second (auxiliary) port.
+//
+// A standard mouse reports movement in 3 byte packets: buttons and sign bits, then X and Y
+// movement. An IntelliMouse adds a fourth byte for the scroll wheel, but only after it has
+// been switched into that mode with a magic sequence of sample rates. Like the keyboard, the
+// interrupt handler only assembles the packets, queues the resulting events and wakes the task
+// reading them.
+
+use crate::ps2;
+use crate::queue::ArrayQueue;
+use crate::serial_println;
+use crate::task::{InterruptWaker, Stream};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use spin::Mutex;
+
+const CMD_SET_SAMPLE_RATE: u8 = 0xf3;
+const CMD_GET_DEVICE_ID: u8 = 0xf2;
+const CMD_ENABLE_REPORTING: u8 = 0xf4;
+const CMD_SET_DEFAULTS: u8 = 0xf6;
+
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+const COMMAND_RETRIES: usize = 3;
+
+const DEVICE_ID_STANDARD: u8 = 0;
+const DEVICE_ID_INTELLIMOUSE: u8 = 3;
+// Setting these sample rates in a row unlocks the wheel of an IntelliMouse
+const INTELLIMOUSE_SEQUENCE: [u8; 3] = [200, 100, 80];
+const DEFAULT_SAMPLE_RATE: u8 = 100;
+
+// Bits of the first packet byte
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+/// Something went wrong talking to the mouse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseError {
+    Timeout,
+    /// The mouse answered a command with this byte instead of acknowledging it.
+    NotAcknowledged(u8),
+}
+
+impl From<ps2::Timeout> for MouseError {
+    fn from(_: ps2::Timeout) -> MouseError {
+        MouseError::Timeout
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// Movement and button state reported by one packet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Horizontal movement, positive to the right.
+    pub dx: i16,
+    /// Vertical movement in screen direction, positive downwards.
+    pub dy: i16,
+    /// Scroll wheel movement, positive towards the user. Always 0 without a wheel.
+    pub wheel: i8,
+    pub buttons: MouseButtons,
+}
+
+/// Assembles packet bytes into events.
+#[derive(Debug, Clone)]
+pub struct PacketDecoder {
+    packet: [u8; 4],
+    received: usize,
+    has_wheel: bool,
+}
+
+impl PacketDecoder {
+    pub const fn new(has_wheel: bool) -> PacketDecoder {
+        PacketDecoder {
+            packet: [0; 4],
+            received: 0,
+            has_wheel,
+        }
+    }
+
+    pub fn has_wheel(&self) -> bool {
+        self.has_wheel
+    }
+
+    fn packet_size(&self) -> usize {
+        if self.has_wheel {
+            4
+        } else {
+            3
+        }
+    }
+
+    /// Feeds one byte from the mouse, returning the event once a packet is complete.
+    pub fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // If a byte got lost we are in the middle of a packet without knowing it. The first
+        // byte always has bit 3 set, so skip bytes until one could start a packet. That can
+        // still pick a movement byte, but then the next check is likely to fail.
+        if self.received == 0 && byte & ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet_size() {
+            return None;
+        }
+        self.received = 0;
+        decode(&self.packet[..self.packet_size()])
+    }
+}
+
+fn decode(packet: &[u8]) -> Option<MouseEvent> {
+    let flags = packet[0];
+    // the movement didn't fit into 9 bits, so the values are garbage
+    if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+        return None;
+    }
+    // the movement is 9 bit two's complement, with the sign bit in the first byte
+    let dx = i16::from(packet[1]) - if flags & X_SIGN != 0 { 0x100 } else { 0 };
+    let dy = i16::from(packet[2]) - if flags & Y_SIGN != 0 { 0x100 } else { 0 };
+    // The wheel movement is a full byte of two's complement. Only the 5 button format (ID 4),
+    // which isn't enabled, packs it into the low 4 bits.
+    let wheel = packet.get(3).map_or(0, |&byte| byte as i8);
+    Some(MouseEvent {
+        dx,
+        // the mouse counts upwards movement as positive
+        dy: -dy,
+        wheel,
+        buttons: MouseButtons {
+            left: flags & LEFT_BUTTON != 0,
+            right: flags & RIGHT_BUTTON != 0,
+            middle: flags & MIDDLE_BUTTON != 0,
+        },
+    })
+}
+
+static EVENTS: ArrayQueue<MouseEvent, 64> = ArrayQueue::new();
+
+// Only touched by the interrupt handler, and by `init` before the IRQ is enabled
+static DECODER: Mutex<PacketDecoder> = Mutex::new(PacketDecoder::new(false));
+
+fn command(byte: u8) -> Result<(), MouseError> {
+    let mut response = 0;
+    for _ in 0..COMMAND_RETRIES {
+        ps2::write_second_port(byte)?;
+        response = ps2::read_second_port()?;
+        if response != RESEND {
+            break;
+        }
+    }
+    if response == ACK {
+        Ok(())
+    } else {
+        Err(MouseError::NotAcknowledged(response))
+    }
+}
+
+fn command_with_argument(byte: u8, argument: u8) -> Result<(), MouseError> {
+    command(byte)?;
+    command(argument)
+}
+
+fn device_id() -> Result<u8, MouseError> {
+    command(CMD_GET_DEVICE_ID)?;
+    Ok(ps2::read_second_port()?)
+}
+
+// Tries to switch the mouse into IntelliMouse mode, returning whether it has a wheel
+fn enable_wheel() -> Result<bool, MouseError> {
+    for rate in INTELLIMOUSE_SEQUENCE {
+        command_with_argument(CMD_SET_SAMPLE_RATE, rate)?;
+    }
+    let id = device_id()?;
+    command_with_argument(CMD_SET_SAMPLE_RATE, DEFAULT_SAMPLE_RATE)?;
+    Ok(id == DEVICE_ID_INTELLIMOUSE)
+}
+
+fn init_device() -> Result<bool, MouseError> {
+    ps2::enable_second_port()?;
+    // keep the IRQ off while talking to the mouse, the answers are read by polling
+    let config = ps2::read_config()?;
+    ps2::write_config(
+        config & !(ps2::CONFIG_SECOND_PORT_CLOCK_DISABLED | ps2::CONFIG_SECOND_PORT_IRQ),
+    )?;
+
+    command(CMD_SET_DEFAULTS)?;
+    let id = device_id()?;
+    let has_wheel = id == DEVICE_ID_STANDARD && enable_wheel()?;
+    command(CMD_ENABLE_REPORTING)?;
+
+    ps2::write_config(ps2::read_config()? | ps2::CONFIG_SECOND_PORT_IRQ)?;
+    Ok(has_wheel)
+}
+
+/// Sets up the mouse and enables its IRQ on the controller. Must run before the IRQ is
+/// unmasked, and after `keyboard::init`, which reads the controller configuration first.
+pub fn init() {
+    match init_device() {
+        Ok(has_wheel) => {
+            *DECODER.lock() = PacketDecoder::new(has_wheel);
+            serial_println!(
+                "mouse: PS/2 mouse {} scroll wheel",
+                if has_wheel { "with" } else { "without" }
+            );
+        }
+        Err(error) => serial_println!("mouse: no PS/2 mouse ({:?})", error),
+    }
+}
+
+// The task reading from a `MouseStream`
+static WAKER: InterruptWaker = InterruptWaker::new();
+
+/// Called on the mouse IRQ.
+pub(crate) fn handle_interrupt() {
+    let byte = ps2::read_data_unchecked();
+    if let Some(event) = DECODER.lock().add_byte(byte) {
+        let _ = EVENTS.push(event);
+        WAKER.wake();
+    }
+}
+
+/// Takes the oldest event out of the queue.
+pub fn read_event() -> Option<MouseEvent> {
+    EVENTS.pop()
+}
+
+/// Whether there are events `read_event` hasn't taken yet.
+pub fn has_pending_events() -> bool {
+    !EVENTS.is_empty()
+}
+
+/// The mouse events as a stream, for tasks. Only one task should read events at a time, the
+/// mouse interrupt only wakes the last one that waited.
+pub struct MouseStream {
+    _private: (),
+}
+
+impl MouseStream {
+    pub fn new() -> MouseStream {
+        MouseStream { _private: () }
+    }
+}
+
+impl Default for MouseStream {
+    fn default() -> MouseStream {
+        MouseStream::new()
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<MouseEvent>> {
+        if let Some(event) = read_event() {
+            return Poll::Ready(Some(event));
+        }
+        WAKER.register(context.waker());
+        // an event that arrived before the waker was registered didn't wake anybody
+        match read_event() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[test_case]
+fn test_three_byte_packet() {
+    let mut decoder = PacketDecoder::new(false);
+    assert_eq!(decoder.add_byte(ALWAYS_ONE | LEFT_BUTTON), None);
+    assert_eq!(decoder.add_byte(5), None);
+    let event = decoder.add_byte(3).unwrap();
+    assert_eq!((event.dx, event.dy, event.wheel), (5, -3, 0));
+    assert!(event.buttons.left && !event.buttons.right && !event.buttons.middle);
+}
+
+#[test_case]
+fn test_negative_movement() {
+    let mut decoder = PacketDecoder::new(false);
+    decoder.add_byte(ALWAYS_ONE | X_SIGN | Y_SIGN);
+    decoder.add_byte(0xff);
+    let event = decoder.add_byte(0xfe).unwrap();
+    assert_eq!((event.dx, event.dy), (-1, 2));
+}
+
+#[test_case]
+fn test_four_byte_packet_with_wheel() {
+    let mut decoder = PacketDecoder::new(true);
+    for byte in [ALWAYS_ONE, 0, 0] {
+        assert_eq!(decoder.add_byte(byte), None);
+    }
+    assert_eq!(decoder.add_byte(0xff).unwrap().wheel, -1);
+    for byte in [ALWAYS_ONE, 0, 0] {
+        decoder.add_byte(byte);
+    }
+    assert_eq!(decoder.add_byte(0x0f).unwrap().wheel, 15);
+}
+
+#[test_case]
+fn test_resync_and_overflow() {
+    let mut decoder = PacketDecoder::new(false);
+    // a stray movement byte can't start a packet
+    assert_eq!(decoder.add_byte(0x04), None);
+    for byte in [ALWAYS_ONE | X_OVERFLOW, 0xff, 0xff] {
+        assert_eq!(decoder.add_byte(byte), None);
+    }
+    decoder.add_byte(ALWAYS_ONE | RIGHT_BUTTON);
+    decoder.add_byte(1);
+    let event = decoder.add_byte(1).unwrap();
+    assert_eq!((event.dx, event.dy), (1, -1));
+    assert!(event.buttons.right);
+}
+
+#[test_case]
+fn test_mouse_stream() {
+    use core::task::Waker;
+
+    let mut stream = MouseStream::new();
+    let mut context = Context::from_waker(Waker::noop());
+    while read_event().is_some() {}
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut context), Poll::Pending);
+
+    let mut decoder = PacketDecoder::new(false);
+    decoder.add_byte(ALWAYS_ONE);
+    decoder.add_byte(1);
+    let event = decoder.add_byte(0).unwrap();
+    EVENTS.push(event).unwrap();
+    assert_eq!(
+        Pin::new(&mut stream).poll_next(&mut context),
+        Poll::Ready(Some(event))
+    );
+}
@@ -0,0 +1,172 @@
+// Driver for the two cascaded 8259 Programmable Interrupt Controllers. By default they
+// deliver IRQs 0-15 on vectors 8-15 and 0x70-0x77, which overlap the CPU exceptions, so
+// they get remapped to the vectors right after the exceptions.
+
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// Vector of the primary PIC's IRQ 0.
+pub const PIC_1_OFFSET: u8 = 32;
+/// Vector of the secondary PIC's IRQ 8.
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
+pub const MOUSE_IRQ: u8 = 12;
+// the line the secondary PIC is chained to the primary on
+const CASCADE_IRQ: u8 = 2;
+
+// Initialization command words
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x01;
+const ICW4_8086: u8 = 0x01;
+
+const CMD_END_OF_INTERRUPT: u8 = 0x20;
+const CMD_READ_ISR: u8 = 0x0b;
+
+struct Pic {
+    offset: u8,
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Pic {
+    fn handles_interrupt(&self, vector: u8) -> bool {
+        (self.offset..self.offset + 8).contains(&vector)
+    }
+
+    unsafe fn end_of_interrupt(&mut self) {
+        self.command.write(CMD_END_OF_INTERRUPT);
+    }
+
+    // The In-Service Register, i.e. the IRQs currently being handled
+    unsafe fn in_service(&mut self) -> u8 {
+        self.command.write(CMD_READ_ISR);
+        self.command.read()
+    }
+}
+
+/// The primary and secondary PIC, chained together the way they are in every PC.
+pub struct ChainedPics {
+    pics: [Pic; 2],
+}
+
+impl ChainedPics {
+    /// # Safety
+    /// The offsets must not overlap the CPU exceptions or other interrupt vectors in use.
+    pub const unsafe fn new(offset1: u8, offset2: u8) -> ChainedPics {
+        ChainedPics {
+            pics: [
+                Pic {
+                    offset: offset1,
+                    command: Port::new(0x20),
+                    data: Port::new(0x21),
+                },
+                Pic {
+                    offset: offset2,
+                    command: Port::new(0xa0),
+                    data: Port::new(0xa1),
+                },
+            ],
+        }
+    }
+
+    /// Remaps both PICs to their offsets and masks every IRQ. Use `unmask` to enable them.
+    ///
+    /// # Safety
+    /// Interrupts must be disabled.
+    pub unsafe fn initialize(&mut self) {
+        // Older machines need a short delay between the init words. Writing to the unused
+        // port 0x80 takes about a microsecond, which is long enough.
+        let mut wait_port: Port<u8> = Port::new(0x80);
+        let mut wait = || wait_port.write(0);
+
+        // start the init sequence, announcing that a fourth init word follows
+        self.pics[0].command.write(ICW1_INIT | ICW1_ICW4);
+        wait();
+        self.pics[1].command.write(ICW1_INIT | ICW1_ICW4);
+        wait();
+
+        // the vector offsets
+        self.pics[0].data.write(self.pics[0].offset);
+        wait();
+        self.pics[1].data.write(self.pics[1].offset);
+        wait();
+
+        // tell the primary that the secondary sits on IRQ 2, and the secondary its cascade identity
+        self.pics[0].data.write(1 << CASCADE_IRQ);
+        wait();
+        self.pics[1].data.write(CASCADE_IRQ);
+        wait();
+
+        self.pics[0].data.write(ICW4_8086);
+        wait();
+        self.pics[1].data.write(ICW4_8086);
+        wait();
+
+        self.write_masks([!(1 << CASCADE_IRQ), 0xff]);
+    }
+
+    /// Lets the given IRQ through.
+    ///
+    /// # Safety
+    /// A handler for the IRQ's vector must be installed.
+    pub unsafe fn unmask(&mut self, irq: u8) {
+        let pic = &mut self.pics[usize::from(irq / 8)];
+        let mask = pic.data.read() & !(1 << (irq % 8));
+        pic.data.write(mask);
+    }
+
+    /// Masks every IRQ, e.g. when another interrupt controller takes over.
+    ///
+    /// # Safety
+    /// Nothing may rely on the PIC delivering interrupts afterwards.
+    pub unsafe fn disable(&mut self) {
+        self.write_masks([0xff, 0xff]);
+    }
+
+    unsafe fn write_masks(&mut self, masks: [u8; 2]) {
+        self.pics[0].data.write(masks[0]);
+        self.pics[1].data.write(masks[1]);
+    }
+
+    pub fn handles_interrupt(&self, vector: u8) -> bool {
+        self.pics.iter().any(|pic| pic.handles_interrupt(vector))
+    }
+
+    /// Signals the end of the interrupt with the given vector, so the PICs deliver the next
+    /// one. IRQs from the secondary PIC need an end of interrupt on both.
+    ///
+    /// # Safety
+    /// `vector` must be the vector of the interrupt that is currently being handled.
+    pub unsafe fn notify_end_of_interrupt(&mut self, vector: u8) {
+        if self.pics[1].handles_interrupt(vector) {
+            self.pics[1].end_of_interrupt();
+        }
+        if self.handles_interrupt(vector) {
+            self.pics[0].end_of_interrupt();
+        }
+    }
+
+    /// Checks for a spurious interrupt on the lowest priority line of either PIC (IRQ 7 or
+    /// 15), which happens when an IRQ is withdrawn before the CPU acknowledged it. Spurious
+    /// interrupts must not get an end of interrupt, except that a spurious IRQ 15 still needs
+    /// one on the primary PIC, which did see a real IRQ 2.
+    ///
+    /// # Safety
+    /// `vector` must be the vector of the interrupt that is currently being handled.
+    pub unsafe fn check_spurious(&mut self, vector: u8) -> bool {
+        if vector == self.pics[0].offset + 7 {
+            return self.pics[0].in_service() & 0x80 == 0;
+        }
+        if vector == self.pics[1].offset + 7 && self.pics[1].in_service() & 0x80 == 0 {
+            self.pics[0].end_of_interrupt();
+            return true;
+        }
+        false
+    }
+}
+
+/// The PICs of this machine, remapped to `PIC_1_OFFSET` and `PIC_2_OFFSET`.
+pub static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
@@ -0,0 +1,67 @@
+// Driver for the 8253/8254 Programmable Interval Timer. Channel 0 is wired to IRQ 0 and
+// gives the kernel its periodic timer interrupt when there is no local APIC. Channel 2 can
+// be polled without interrupts, which makes it the reference for calibrating other timers.
+
+use crate::time::TIMER_FREQUENCY_HZ;
+use x86_64::instructions::port::Port;
+
+/// Frequency of the oscillator driving the PIT, in Hz.
+pub const BASE_FREQUENCY_HZ: u32 = 1_193_182;
+
+const CHANNEL_0_DATA: u16 = 0x40;
+const CHANNEL_2_DATA: u16 = 0x42;
+const MODE_COMMAND: u16 = 0x43;
+// Port B of the keyboard controller: bit 0 gates channel 2, bit 1 connects it to the PC
+// speaker and bit 5 reads back the channel's output
+const CHANNEL_2_GATE: u16 = 0x61;
+const GATE_ENABLE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL_2_OUTPUT: u8 = 1 << 5;
+
+// low byte then high byte of the reload value, in mode 2 (rate generator) for channel 0 and
+// mode 0 (interrupt on terminal count) for channel 2
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+/// Longest wait `poll_wait_ms` supports, limited by the 16 bit counter.
+pub const MAX_POLL_WAIT_MS: u32 = 0xffff * 1000 / BASE_FREQUENCY_HZ;
+
+/// Programs channel 0 to fire `TIMER_FREQUENCY_HZ` times per second.
+pub fn init() {
+    // the reload value is 16 bits wide, where 0 stands for 65536
+    let divisor = (BASE_FREQUENCY_HZ / TIMER_FREQUENCY_HZ).clamp(1, 0x10000) as u16;
+    let mut command = Port::<u8>::new(MODE_COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL_0_DATA);
+    // the PIT is only ever programmed here, before its interrupt is enabled
+    unsafe {
+        command.write(CHANNEL_0_RATE_GENERATOR);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+/// Busy waits for `ms` milliseconds on channel 2, without relying on interrupts.
+pub fn poll_wait_ms(ms: u32) {
+    assert!(ms <= MAX_POLL_WAIT_MS, "PIT can't wait for {} ms", ms);
+    let count = (BASE_FREQUENCY_HZ * ms / 1000) as u16;
+
+    let mut gate = Port::<u8>::new(CHANNEL_2_GATE);
+    let mut command = Port::<u8>::new(MODE_COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL_2_DATA);
+    // channel 2 isn't used for anything else, and the speaker stays disconnected
+    unsafe {
+        let port_b = gate.read() & !(GATE_ENABLE | SPEAKER_ENABLE);
+        gate.write(port_b);
+
+        command.write(CHANNEL_2_ONE_SHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // raising the gate starts the countdown, the output goes high once it reaches zero
+        gate.write(port_b | GATE_ENABLE);
+        while gate.read() & CHANNEL_2_OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+        gate.write(port_b);
+    }
+}
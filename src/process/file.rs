@@ -0,0 +1,122 @@
+// What a file descriptor can refer to, and the per process table of them. There is no file
+// system yet, so the console is the only kind of file.
+
+use crate::{print, serial_print};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// How many files a process can have open at once.
+pub const MAX_FILES: usize = 64;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileError {
+    NotReadable,
+    NotWritable,
+}
+
+/// Something a file descriptor refers to. Shared between the processes that inherited it.
+pub trait File: Send + Sync {
+    /// Reads into `buf`, returning how many bytes were read. 0 means the end of the file.
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FileError>;
+
+    /// Writes `bytes`, returning how many were written.
+    fn write(&self, bytes: &[u8]) -> Result<usize, FileError>;
+}
+
+/// The VGA text screen, with a copy of everything going to the serial port. Reading it
+/// always hits the end, there is no line input yet.
+#[derive(Debug, Default)]
+pub struct Console;
+
+impl File for Console {
+    fn read(&self, _: &mut [u8]) -> Result<usize, FileError> {
+        Ok(0)
+    }
+
+    fn write(&self, bytes: &[u8]) -> Result<usize, FileError> {
+        let text = String::from_utf8_lossy(bytes);
+        print!("{}", text);
+        serial_print!("{}", text);
+        Ok(bytes.len())
+    }
+}
+
+/// The open files of a process, indexed by file descriptor. Cloning it shares the files.
+#[derive(Clone, Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    /// A table with the console open as standard input, output and error.
+    pub fn standard() -> FileTable {
+        let console: Arc<dyn File> = Arc::new(Console);
+        FileTable {
+            files: (STDIN..=STDERR).map(|_| Some(console.clone())).collect(),
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> Option<Arc<dyn File>> {
+        self.files.get(fd)?.clone()
+    }
+
+    /// Opens `file` under the lowest free descriptor, `None` if the table is full.
+    pub fn insert(&mut self, file: Arc<dyn File>) -> Option<usize> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < MAX_FILES => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return None,
+        };
+        self.files[fd] = Some(file);
+        Some(fd)
+    }
+
+    /// Closes `fd`, returning whether it was open.
+    pub fn close(&mut self, fd: usize) -> bool {
+        self.files.get_mut(fd).and_then(Option::take).is_some()
+    }
+
+    /// How many files are open.
+    pub fn len(&self) -> usize {
+        self.files.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test_case]
+fn test_file_table() {
+    let mut files = FileTable::standard();
+    assert_eq!(files.len(), 3);
+    assert!(files.get(STDOUT).is_some());
+    assert!(files.get(3).is_none());
+
+    assert!(files.close(STDIN));
+    assert!(!files.close(STDIN));
+    // the lowest free descriptor is taken first
+    assert_eq!(files.insert(Arc::new(Console)), Some(STDIN));
+    assert_eq!(files.insert(Arc::new(Console)), Some(3));
+
+    let inherited = files.clone();
+    files.close(STDOUT);
+    assert!(inherited.get(STDOUT).is_some());
+}
+
+#[test_case]
+fn test_file_table_limit() {
+    let mut files = FileTable::default();
+    for fd in 0..MAX_FILES {
+        assert_eq!(files.insert(Arc::new(Console)), Some(fd));
+    }
+    assert_eq!(files.insert(Arc::new(Console)), None);
+}
@@ -0,0 +1,481 @@
+// Processes: user programs with an address space, open files and a place in the family tree
+// of their own. Each process runs on one kernel thread, which drops to user mode as soon as
+// it starts.
+//
+// A process that exits closes its files right away. Its thread still runs on the kernel stack
+// and in the address space until it's switched away from, after which the thread module's
+// reaper frees both, the address space with every frame and page table in it. What's left is
+// a zombie which only keeps its exit status, until its parent collects that with `wait`.
+//
+// Processes started by kernel code have no parent process, and are waited for by the kernel
+// instead. Children that outlive their parent are orphaned: nobody waits for them, so they
+// are taken out of the table as soon as they exit, and the reaper frees the rest.
+
+pub mod file;
+
+use crate::memory::address_space::AddressSpace;
+use crate::memory::paging::MapError;
+use crate::sync::SpinLock;
+use crate::thread::{self, JoinHandle, ThreadId, WaitQueue};
+use crate::user::elf::{self, ElfError};
+use crate::user::enter_user_mode;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use file::{File, FileTable};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Pid {
+        // 0 is left out, user code gets it for "no process"
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The process with this ID, which may not exist.
+    pub fn from_u64(pid: u64) -> Pid {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// How a process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The program exited with this code.
+    Exited(i32),
+    /// The program was ended by the CPU exception with this vector.
+    Killed(u8),
+}
+
+impl ExitStatus {
+    /// The status as user code gets it from `wait`: the low byte of the exit code shifted up
+    /// by 8, or the exception vector.
+    pub fn to_raw(self) -> u64 {
+        match self {
+            ExitStatus::Exited(code) => u64::from(code as u8) << 8,
+            ExitStatus::Killed(vector) => u64::from(vector & 0x7f),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    Map(MapError),
+    Elf(ElfError),
+}
+
+impl From<MapError> for SpawnError {
+    fn from(error: MapError) -> SpawnError {
+        SpawnError::Map(error)
+    }
+}
+
+impl From<ElfError> for SpawnError {
+    fn from(error: ElfError) -> SpawnError {
+        SpawnError::Elf(error)
+    }
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpawnError::Map(error) => write!(f, "out of memory: {:?}", error),
+            SpawnError::Elf(error) => write!(f, "invalid executable: {}", error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// There is no child that could be waited for.
+    NoChild,
+}
+
+/// What is known about a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pub parent: Option<Pid>,
+    /// `Some` once the process has exited and is a zombie.
+    pub exit_status: Option<ExitStatus>,
+    pub open_files: usize,
+}
+
+struct Process {
+    parent: Option<Pid>,
+    // given up when the process exits
+    address_space: Option<Arc<AddressSpace>>,
+    files: FileTable,
+    exit_status: Option<ExitStatus>,
+    // set right after the thread is started, reaping drops it
+    thread: Option<JoinHandle<()>>,
+    // the parent exited first, so nobody will wait for it
+    orphaned: bool,
+    // woken when a child exits
+    child_exited: Arc<WaitQueue>,
+}
+
+impl Process {
+    fn info(&self) -> ProcessInfo {
+        ProcessInfo {
+            parent: self.parent,
+            exit_status: self.exit_status,
+            open_files: self.files.len(),
+        }
+    }
+}
+
+struct ProcessTable {
+    processes: BTreeMap<Pid, Process>,
+    // the process each running process thread belongs to
+    threads: BTreeMap<ThreadId, Pid>,
+}
+
+impl ProcessTable {
+    // The child of `parent` that `wait` should collect, `None` if the matching children are
+    // all still running
+    fn find_zombie(&self, parent: Option<Pid>, pid: Option<Pid>) -> Option<Result<Pid, WaitError>> {
+        let mut children = self.processes.iter().filter(|(&id, process)| {
+            process.parent == parent && !process.orphaned && pid.is_none_or(|pid| pid == id)
+        });
+        let mut any = false;
+        for (&id, process) in &mut children {
+            if process.exit_status.is_some() {
+                return Some(Ok(id));
+            }
+            any = true;
+        }
+        match any {
+            true => None,
+            false => Some(Err(WaitError::NoChild)),
+        }
+    }
+}
+
+// Processes drop their threads and address spaces when they are taken out of the table,
+// which takes other locks. That has to happen outside of this one.
+static PROCESSES: SpinLock<ProcessTable> = SpinLock::new(ProcessTable {
+    processes: BTreeMap::new(),
+    threads: BTreeMap::new(),
+});
+
+// Woken when a process started by the kernel exits
+static KERNEL_CHILD_EXITED: WaitQueue = WaitQueue::new();
+
+/// The process the calling thread belongs to, `None` for kernel threads.
+pub fn current() -> Option<Pid> {
+    PROCESSES.lock().threads.get(&thread::current()).copied()
+}
+
+/// What is known about the given process, `None` if there is no such process or it has
+/// been waited for.
+pub fn info(pid: Pid) -> Option<ProcessInfo> {
+    PROCESSES.lock().processes.get(&pid).map(Process::info)
+}
+
+/// The children of the given process that haven't been waited for yet. Kernel code gets the
+/// processes it started with `None`.
+pub fn children(parent: Option<Pid>) -> Vec<Pid> {
+    let table = PROCESSES.lock();
+    table
+        .processes
+        .iter()
+        .filter(|(_, process)| process.parent == parent && !process.orphaned)
+        .map(|(&id, _)| id)
+        .collect()
+}
+
+/// Starts the executable in `image` as a child of the calling process, or of the kernel when
+/// called from a kernel thread. The child inherits the parent's open files; children of the
+/// kernel get the console.
+pub fn spawn(image: &[u8], args: &[&str], env: &[&str]) -> Result<Pid, SpawnError> {
+    let address_space = Arc::new(AddressSpace::new()?);
+    let program = elf::load(&address_space, image, args, env)?;
+
+    let parent = current();
+    let pid = Pid::new();
+    {
+        let mut table = PROCESSES.lock();
+        let files = parent
+            .and_then(|parent| table.processes.get(&parent))
+            .map_or_else(FileTable::standard, |parent| parent.files.clone());
+        let process = Process {
+            parent,
+            address_space: Some(address_space.clone()),
+            files,
+            exit_status: None,
+            thread: None,
+            orphaned: false,
+            child_exited: Arc::new(WaitQueue::new()),
+        };
+        table.processes.insert(pid, process);
+    }
+
+    let thread = thread::spawn_in(address_space, move || {
+        // registered before any system call could ask which process this is
+        PROCESSES.lock().threads.insert(thread::current(), pid);
+        // `load` mapped the program and its stack into the thread's address space
+        unsafe { enter_user_mode(program.entry, program.stack_pointer) }
+    });
+    match thread {
+        Ok(thread) => {
+            let mut table = PROCESSES.lock();
+            // it may have exited and been waited for already
+            let rest = match table.processes.get_mut(&pid) {
+                Some(process) => process.thread.replace(thread),
+                None => Some(thread),
+            };
+            drop(table);
+            drop(rest);
+            Ok(pid)
+        }
+        Err(error) => {
+            let process = PROCESSES.lock().processes.remove(&pid);
+            drop(process);
+            Err(error.into())
+        }
+    }
+}
+
+// Who to tell that a process exited
+enum Waiter {
+    Process(Arc<WaitQueue>),
+    Kernel,
+    Nobody,
+}
+
+/// Ends the calling thread's process with `status`. A thread that isn't part of a process
+/// just exits.
+pub fn exit(status: ExitStatus) -> ! {
+    let mut finished = Vec::new();
+    let mut resources = None;
+    let waiter = {
+        let mut table = PROCESSES.lock();
+        match table.threads.remove(&thread::current()) {
+            None => Waiter::Nobody,
+            Some(pid) => {
+                let process = table.processes.get_mut(&pid).unwrap();
+                process.exit_status = Some(status);
+                resources = Some((mem::take(&mut process.files), process.address_space.take()));
+                let parent = process.parent;
+                let orphaned = process.orphaned;
+
+                // the children are orphaned, the ones that already exited can go right away
+                let children: Vec<Pid> = table
+                    .processes
+                    .iter()
+                    .filter(|(_, child)| child.parent == Some(pid))
+                    .map(|(&id, _)| id)
+                    .collect();
+                for id in children {
+                    let child = table.processes.get_mut(&id).unwrap();
+                    child.parent = None;
+                    child.orphaned = true;
+                    if child.exit_status.is_some() {
+                        finished.extend(table.processes.remove(&id));
+                    }
+                }
+
+                match parent {
+                    _ if orphaned => {
+                        finished.extend(table.processes.remove(&pid));
+                        Waiter::Nobody
+                    }
+                    Some(parent) => Waiter::Process(table.processes[&parent].child_exited.clone()),
+                    None => Waiter::Kernel,
+                }
+            }
+        }
+    };
+    // Dropping our own thread handle only detaches the thread, which is then reaped by the
+    // thread module. The address space lives on in the thread until then.
+    drop(finished);
+    drop(resources);
+    match waiter {
+        Waiter::Process(queue) => {
+            queue.notify_all();
+        }
+        Waiter::Kernel => {
+            KERNEL_CHILD_EXITED.notify_all();
+        }
+        Waiter::Nobody => {}
+    }
+    thread::exit();
+}
+
+/// Waits for a child of the calling process to exit, any child if `pid` is `None`, and
+/// takes it out of the process table. Kernel threads wait for the processes started by the
+/// kernel.
+pub fn wait(pid: Option<Pid>) -> Result<(Pid, ExitStatus), WaitError> {
+    let parent = current();
+    let queue = parent.map(|parent| PROCESSES.lock().processes[&parent].child_exited.clone());
+    let queue = queue.as_deref().unwrap_or(&KERNEL_CHILD_EXITED);
+    loop {
+        queue.wait_until(|| PROCESSES.lock().find_zombie(parent, pid).is_some());
+        let mut table = PROCESSES.lock();
+        match table.find_zombie(parent, pid) {
+            Some(Ok(child)) => {
+                let process = table.processes.remove(&child).unwrap();
+                drop(table);
+                let status = process.exit_status.unwrap();
+                // The thread may still be on its way out. Joining it takes it out of the
+                // thread table.
+                if let Some(thread) = process.thread {
+                    thread.join();
+                }
+                return Ok((child, status));
+            }
+            Some(Err(error)) => return Err(error),
+            // another thread got to it first
+            None => continue,
+        }
+    }
+}
+
+/// The file open under `fd` in the calling process. Threads running user code outside of a
+/// process get the console as standard input, output and error.
+pub fn file(fd: usize) -> Option<Arc<dyn File>> {
+    let table = PROCESSES.lock();
+    match table.threads.get(&thread::current()) {
+        Some(pid) => table.processes[pid].files.get(fd),
+        None => {
+            drop(table);
+            FileTable::standard().get(fd)
+        }
+    }
+}
+
+/// Closes `fd` in the calling process, returning whether it was open.
+pub fn close(fd: usize) -> bool {
+    let mut table = PROCESSES.lock();
+    let Some(&pid) = table.threads.get(&thread::current()) else {
+        return false;
+    };
+    let process = table.processes.get_mut(&pid).unwrap();
+    // the file itself may be dropped here, which is fine for the console
+    process.files.close(fd)
+}
+
+/// The parent of the calling process.
+pub fn parent() -> Option<Pid> {
+    let table = PROCESSES.lock();
+    let pid = table.threads.get(&thread::current())?;
+    table.processes[pid].parent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator;
+    use crate::memory::address_space::USER_START;
+    use crate::memory::frame::frame_allocator;
+    use crate::user::elf::tests::executable;
+    use crate::user::syscall::{SYS_EXIT, SYS_GETPPID, SYS_SPAWN, SYS_WAIT};
+
+    fn mov_eax(code: &mut Vec<u8>, value: u64) {
+        code.push(0xb8);
+        code.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+
+    // exit(code)
+    fn exit_with(code: u32) -> Vec<u8> {
+        let mut program = Vec::new();
+        mov_eax(&mut program, SYS_EXIT);
+        program.push(0xbf);
+        program.extend_from_slice(&code.to_le_bytes());
+        program.extend_from_slice(&[0x0f, 0x05]);
+        program
+    }
+
+    #[test_case]
+    fn test_exit_status_and_reaping() {
+        let pid = spawn(&executable(&exit_with(7), &[]), &["seven"], &[]).unwrap();
+        assert_eq!(info(pid).unwrap().parent, None);
+        assert!(children(None).contains(&pid));
+        assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Exited(7))));
+        assert_eq!(info(pid), None);
+        assert_eq!(wait(Some(pid)), Err(WaitError::NoChild));
+    }
+
+    #[test_case]
+    fn test_zombie_until_waited_for() {
+        let pid = spawn(&executable(&exit_with(1), &[]), &[], &[]).unwrap();
+        while info(pid).unwrap().exit_status.is_none() {
+            thread::yield_now();
+        }
+        let zombie = info(pid).unwrap();
+        assert_eq!(zombie.exit_status, Some(ExitStatus::Exited(1)));
+        assert_eq!(zombie.open_files, 0);
+        assert_eq!(wait(None), Ok((pid, ExitStatus::Exited(1))));
+    }
+
+    #[test_case]
+    fn test_exception_kills_process() {
+        let pid = spawn(&executable(&[0x0f, 0x0b], &[]), &[], &[]).unwrap();
+        assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Killed(6))));
+    }
+
+    #[test_case]
+    fn test_memory_is_freed() {
+        let run = || {
+            let pid = spawn(&executable(&exit_with(0), &[]), &[], &[]).unwrap();
+            wait(Some(pid)).unwrap();
+        };
+        // the first run may grow the heap, which keeps its frames, and leave the tables with
+        // more capacity
+        run();
+        let frames = frame_allocator().stats().free;
+        let heap = allocator::stats();
+        run();
+        assert_eq!(frame_allocator().stats().free, frames);
+        let after = allocator::stats();
+        assert_eq!(after.used, heap.used);
+        assert_eq!(after.allocations, heap.allocations);
+    }
+
+    #[test_case]
+    fn test_child_process() {
+        // exits with getppid()
+        let mut child = Vec::new();
+        mov_eax(&mut child, SYS_GETPPID);
+        child.extend_from_slice(&[0x0f, 0x05, 0x89, 0xc7]);
+        mov_eax(&mut child, SYS_EXIT);
+        child.extend_from_slice(&[0x0f, 0x05]);
+        let child = executable(&child, &[]);
+
+        // The child's image is at the start of the data segment, `wait` stores its status
+        // after it. Exits with the child's exit code.
+        let image = USER_START + 0x1000;
+        let status = image + child.len().next_multiple_of(8) as u64;
+        let mut parent = Vec::new();
+        mov_eax(&mut parent, SYS_SPAWN);
+        parent.extend_from_slice(&[0x48, 0xbf]);
+        parent.extend_from_slice(&image.to_le_bytes());
+        parent.push(0xbe);
+        parent.extend_from_slice(&(child.len() as u32).to_le_bytes());
+        parent.extend_from_slice(&[0x0f, 0x05, 0x48, 0x89, 0xc7, 0x48, 0xbe]);
+        parent.extend_from_slice(&status.to_le_bytes());
+        mov_eax(&mut parent, SYS_WAIT);
+        parent.extend_from_slice(&[0x0f, 0x05, 0x48, 0xa1]);
+        parent.extend_from_slice(&status.to_le_bytes());
+        parent.extend_from_slice(&[0x48, 0x89, 0xc7, 0x48, 0xc1, 0xef, 0x08]);
+        mov_eax(&mut parent, SYS_EXIT);
+        parent.extend_from_slice(&[0x0f, 0x05]);
+        let mut data = child.clone();
+        data.resize(child.len().next_multiple_of(8) + 8, 0);
+
+        let pid = spawn(&executable(&parent, &data), &[], &[]).unwrap();
+        let expected = (pid.as_u64() & 0xff) as i32;
+        assert_eq!(wait(Some(pid)), Ok((pid, ExitStatus::Exited(expected))));
+        // the child was waited for by its parent
+        assert!(children(Some(pid)).is_empty());
+    }
+}
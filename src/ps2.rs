@@ -0,0 +1,124 @@
+// The 8042 PS/2 controller, which connects the keyboard (first port) and the mouse (second,
+// auxiliary port). Both devices send their bytes through the same data port, and the
+// controller raises IRQ 1 or IRQ 12 depending on which one a byte came from.
+
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+// reads as the status register, writes are controller commands
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+// the byte in the output buffer came from the second port
+const STATUS_SECOND_PORT_DATA: u8 = 1 << 5;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_ENABLE_SECOND_PORT: u8 = 0xa8;
+// the next byte written to the data port goes to the second port's device
+const CMD_WRITE_SECOND_PORT: u8 = 0xd4;
+
+/// Bits of the controller configuration byte.
+pub const CONFIG_FIRST_PORT_IRQ: u8 = 1 << 0;
+pub const CONFIG_SECOND_PORT_IRQ: u8 = 1 << 1;
+pub const CONFIG_SECOND_PORT_CLOCK_DISABLED: u8 = 1 << 5;
+/// The controller translates the keyboard's scancode set 2 to set 1.
+pub const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// Polling limit, so a missing controller doesn't hang the boot
+const TIMEOUT_ITERATIONS: u32 = 100_000;
+
+/// The controller didn't react in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(STATUS_COMMAND_PORT).read() }
+}
+
+fn wait_until(condition: impl Fn(u8) -> bool) -> Result<(), Timeout> {
+    for _ in 0..TIMEOUT_ITERATIONS {
+        if condition(status()) {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Timeout)
+}
+
+/// Sends a command to the controller itself.
+pub fn send_command(command: u8) -> Result<(), Timeout> {
+    wait_until(|status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(STATUS_COMMAND_PORT).write(command) };
+    Ok(())
+}
+
+/// Writes a byte to the data port, i.e. to the first port's device or as a command argument.
+pub fn write_data(byte: u8) -> Result<(), Timeout> {
+    wait_until(|status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(DATA_PORT).write(byte) };
+    Ok(())
+}
+
+/// Writes a byte to the device on the second port.
+pub fn write_second_port(byte: u8) -> Result<(), Timeout> {
+    send_command(CMD_WRITE_SECOND_PORT)?;
+    write_data(byte)
+}
+
+/// Waits for a byte from the controller or one of the devices.
+///
+/// Only to be used while the device IRQs are masked, since the interrupt handlers would
+/// otherwise take the byte first.
+pub fn read_data() -> Result<u8, Timeout> {
+    wait_until(|status| status & STATUS_OUTPUT_FULL != 0)?;
+    Ok(read_data_unchecked())
+}
+
+/// Waits for a byte from the device on the second port, dropping any from the first one.
+///
+/// Like `read_data`, only to be used while the device IRQs are masked.
+pub fn read_second_port() -> Result<u8, Timeout> {
+    for _ in 0..TIMEOUT_ITERATIONS {
+        let status = status();
+        if status & STATUS_OUTPUT_FULL != 0 {
+            let byte = read_data_unchecked();
+            if status & STATUS_SECOND_PORT_DATA != 0 {
+                return Ok(byte);
+            }
+        }
+        core::hint::spin_loop();
+    }
+    Err(Timeout)
+}
+
+/// Reads the data port without waiting. This is what the interrupt handlers do, the IRQ
+/// already says a byte is there.
+pub fn read_data_unchecked() -> u8 {
+    unsafe { Port::<u8>::new(DATA_PORT).read() }
+}
+
+/// Drops any bytes the devices sent before we were ready for them.
+pub fn flush_output() {
+    for _ in 0..16 {
+        if status() & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        read_data_unchecked();
+    }
+}
+
+pub fn read_config() -> Result<u8, Timeout> {
+    send_command(CMD_READ_CONFIG)?;
+    read_data()
+}
+
+pub fn write_config(config: u8) -> Result<(), Timeout> {
+    send_command(CMD_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+pub fn enable_second_port() -> Result<(), Timeout> {
+    send_command(CMD_ENABLE_SECOND_PORT)
+}
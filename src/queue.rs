@@ -0,0 +1,168 @@
+// A bounded lock-free queue, for handing data from interrupt handlers to the rest of the
+// kernel. Interrupt handlers can't take locks the code they interrupted might hold, and
+// there is no heap to allocate from, so the queue is a fixed size array that can live in a
+// static.
+//
+// This is Dmitry Vyukov's bounded MPMC queue: every slot carries a sequence number that says
+// whether it is ready to be written or read in the current lap around the array. Neither side
+// ever waits for the other, a push into a queue whose next slot is still being read simply
+// fails like a push into a full queue.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+struct Slot<T> {
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A lock-free queue holding up to `N` values. `N` must be a power of two.
+pub struct ArrayQueue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// Values only ever move in and out through the slot protocol, so the queue can be shared
+// as long as the values can be sent between contexts.
+unsafe impl<T: Send, const N: usize> Sync for ArrayQueue<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for ArrayQueue<T, N> {}
+
+impl<T, const N: usize> ArrayQueue<T, N> {
+    pub const fn new() -> Self {
+        assert!(N.is_power_of_two(), "queue capacity must be a power of two");
+        let mut slots = [const {
+            Slot {
+                sequence: AtomicUsize::new(0),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            }
+        }; N];
+        // slot `i` is the first to be written to in lap zero
+        let mut i = 0;
+        while i < N {
+            slots[i].sequence = AtomicUsize::new(i);
+            i += 1;
+        }
+        ArrayQueue {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Appends a value, or hands it back if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[tail & (N - 1)];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match sequence as isize - tail as isize {
+                // the slot is free in this lap, try to claim it
+                0 => match self.tail.compare_exchange_weak(
+                    tail,
+                    tail.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // the slot is ours until we publish it with the next sequence number
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.sequence.store(tail.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => tail = current,
+                },
+                // the slot still holds a value from the previous lap
+                difference if difference < 0 => return Err(value),
+                // another producer claimed the slot, catch up
+                _ => tail = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Removes the oldest value, if there is one.
+    pub fn pop(&self) -> Option<T> {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[head & (N - 1)];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            match sequence as isize - head.wrapping_add(1) as isize {
+                // the slot was written in this lap, try to claim it
+                0 => match self.head.compare_exchange_weak(
+                    head,
+                    head.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // the slot is ours until we hand it to the producers of the next lap
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.sequence.store(head.wrapping_add(N), Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => head = current,
+                },
+                // nothing was written to the slot yet
+                difference if difference < 0 => return None,
+                // another consumer took the value, catch up
+                _ => head = self.head.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let head = self.head.load(Ordering::SeqCst);
+        let tail = self.tail.load(Ordering::SeqCst);
+        head == tail
+    }
+}
+
+impl<T, const N: usize> Default for ArrayQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for ArrayQueue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[test_case]
+fn test_push_pop_in_order() {
+    let queue: ArrayQueue<u8, 4> = ArrayQueue::new();
+    assert!(queue.is_empty());
+    assert_eq!(queue.push(1), Ok(()));
+    assert_eq!(queue.push(2), Ok(()));
+    assert_eq!(queue.pop(), Some(1));
+    assert_eq!(queue.pop(), Some(2));
+    assert_eq!(queue.pop(), None);
+}
+
+#[test_case]
+fn test_full_queue_rejects_push() {
+    let queue: ArrayQueue<u8, 2> = ArrayQueue::new();
+    assert_eq!(queue.push(1), Ok(()));
+    assert_eq!(queue.push(2), Ok(()));
+    assert_eq!(queue.push(3), Err(3));
+    assert_eq!(queue.pop(), Some(1));
+    assert_eq!(queue.push(3), Ok(()));
+}
+
+#[test_case]
+fn test_wraps_around_many_laps() {
+    let queue: ArrayQueue<usize, 4> = ArrayQueue::new();
+    for i in 0..100 {
+        assert_eq!(queue.push(i), Ok(()));
+        assert_eq!(queue.push(i + 1000), Ok(()));
+        assert_eq!(queue.pop(), Some(i));
+        assert_eq!(queue.pop(), Some(i + 1000));
+    }
+    assert!(queue.is_empty());
+}
@@ -0,0 +1,128 @@
+// Serial port driver for the 16550 UART. QEMU forwards COM1 to the host, so this is our
+// output path when running headless and the channel tests report their results on.
+
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+// IO port base of the first serial port
+const COM1: u16 = 0x3f8;
+
+// Line status register bit that is set once the transmit holding register can take a byte
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 5;
+
+/// A 16550 UART accessed through port IO.
+pub struct SerialPort {
+    data: Port<u8>,
+    interrupt_enable: Port<u8>,
+    fifo_control: Port<u8>,
+    line_control: Port<u8>,
+    modem_control: Port<u8>,
+    line_status: Port<u8>,
+}
+
+impl SerialPort {
+    /// Creates a driver for the UART at the given base port.
+    ///
+    /// # Safety
+    /// The caller must guarantee that a UART lives at `base` and that nothing else drives it.
+    pub const unsafe fn new(base: u16) -> SerialPort {
+        SerialPort {
+            data: Port::new(base),
+            interrupt_enable: Port::new(base + 1),
+            fifo_control: Port::new(base + 2),
+            line_control: Port::new(base + 3),
+            modem_control: Port::new(base + 4),
+            line_status: Port::new(base + 5),
+        }
+    }
+
+    /// Configures the port for 38400 baud, 8 data bits, no parity and one stop bit.
+    pub fn init(&mut self) {
+        unsafe {
+            // no interrupts, we poll the line status
+            self.interrupt_enable.write(0x00);
+
+            // set the divisor latch access bit, then program divisor 3 (115200 / 3 = 38400 baud)
+            self.line_control.write(0x80);
+            self.data.write(0x03);
+            self.interrupt_enable.write(0x00);
+
+            // 8 bits, no parity, one stop bit, and clear the divisor latch access bit again
+            self.line_control.write(0x03);
+
+            // enable and clear the FIFOs with a 14 byte threshold
+            self.fifo_control.write(0xc7);
+
+            // data terminal ready, request to send and auxiliary output 2
+            self.modem_control.write(0x0b);
+        }
+    }
+
+    fn line_status(&mut self) -> u8 {
+        unsafe { self.line_status.read() }
+    }
+
+    /// Sends one byte, waiting until the transmitter can take it.
+    pub fn send(&mut self, byte: u8) {
+        while self.line_status() & LSR_TRANSMITTER_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        unsafe { self.data.write(byte) }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    // Terminals on the host side expect carriage returns before newlines
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    /// The COM1 port, initialized on first use.
+    pub static ref SERIAL1: Mutex<SerialPort> = {
+        // COM1 is a fixed part of the PC platform and only ever used through this lock
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    // an interrupt handler that prints while we hold the lock would deadlock
+    interrupts::without_interrupts(|| {
+        SERIAL1
+            .lock()
+            .write_fmt(args)
+            .expect("Printing to serial failed");
+    });
+}
+
+/// Prints to the host through the serial interface.
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!($($arg)*))
+    };
+}
+
+/// Prints to the host through the serial interface, appending a newline.
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
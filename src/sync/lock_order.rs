@@ -0,0 +1,165 @@
+// Lock order checks, in debug builds only.
+//
+// Whenever a thread takes a lock while holding others, the held ones are recorded as coming
+// before it. Two threads taking the same two locks in opposite orders can deadlock, so taking
+// a lock that was recorded as coming before one that is held panics right away, whether or
+// not the timing ever lined up for an actual deadlock. Only pairs of locks are compared, not
+// longer cycles. Locks are told apart by their address, and forgotten when they are dropped.
+//
+// The checks run inside the heap allocator's lock, so they must not allocate, and inside the
+// scheduler, so they must not lock the thread table.
+
+#[cfg(debug_assertions)]
+use crate::thread;
+#[cfg(debug_assertions)]
+use x86_64::instructions::interrupts;
+
+#[cfg(debug_assertions)]
+const MAX_ORDERS: usize = 512;
+#[cfg(debug_assertions)]
+const MAX_HELD: usize = 64;
+
+#[cfg(debug_assertions)]
+struct State {
+    // (held, taken) pairs, the first lock was held while the second was taken
+    orders: [(usize, usize); MAX_ORDERS],
+    order_count: usize,
+    // (thread, lock) for every lock that is held right now
+    held: [(u64, usize); MAX_HELD],
+    held_count: usize,
+}
+
+#[cfg(debug_assertions)]
+static STATE: spin::Mutex<State> = spin::Mutex::new(State {
+    orders: [(0, 0); MAX_ORDERS],
+    order_count: 0,
+    held: [(0, 0); MAX_HELD],
+    held_count: 0,
+});
+
+#[cfg(debug_assertions)]
+enum Violation {
+    Recursive,
+    Inverted(usize),
+}
+
+#[cfg(debug_assertions)]
+impl State {
+    fn check(&self, thread: u64, lock: usize) -> Option<Violation> {
+        for &(owner, held) in &self.held[..self.held_count] {
+            if owner != thread {
+                continue;
+            }
+            if held == lock {
+                return Some(Violation::Recursive);
+            }
+            if self.orders[..self.order_count].contains(&(lock, held)) {
+                return Some(Violation::Inverted(held));
+            }
+        }
+        None
+    }
+
+    fn record(&mut self, thread: u64, lock: usize) {
+        for i in 0..self.held_count {
+            let (owner, held) = self.held[i];
+            let order = (held, lock);
+            // once the table is full new orders go unchecked, which is only less strict
+            if owner == thread
+                && self.order_count < MAX_ORDERS
+                && !self.orders[..self.order_count].contains(&order)
+            {
+                self.orders[self.order_count] = order;
+                self.order_count += 1;
+            }
+        }
+        self.hold(thread, lock);
+    }
+
+    fn hold(&mut self, thread: u64, lock: usize) {
+        if self.held_count < MAX_HELD {
+            self.held[self.held_count] = (thread, lock);
+            self.held_count += 1;
+        }
+    }
+}
+
+/// Checks that `lock` may be taken now, and records it as held. Called before waiting for it.
+pub(super) fn acquire(lock: usize) {
+    #[cfg(debug_assertions)]
+    {
+        let thread = thread::current().as_u64();
+        let violation = interrupts::without_interrupts(|| {
+            let mut state = STATE.lock();
+            let violation = state.check(thread, lock);
+            if violation.is_none() {
+                state.record(thread, lock);
+            }
+            violation
+        });
+        match violation {
+            Some(Violation::Recursive) => {
+                panic!("lock {:#x} taken again by the thread holding it", lock)
+            }
+            Some(Violation::Inverted(held)) => panic!(
+                "lock order violation: {:#x} taken while holding {:#x}, which was taken while \
+                 holding {:#x} before",
+                lock, held, lock
+            ),
+            None => {}
+        }
+    }
+    #[cfg(not(debug_assertions))]
+    let _ = lock;
+}
+
+/// Records `lock` as held without checking, for locks taken without waiting.
+pub(super) fn hold(lock: usize) {
+    #[cfg(debug_assertions)]
+    {
+        let thread = thread::current().as_u64();
+        interrupts::without_interrupts(|| STATE.lock().hold(thread, lock));
+    }
+    #[cfg(not(debug_assertions))]
+    let _ = lock;
+}
+
+/// Records that the calling thread released `lock`.
+pub(super) fn release(lock: usize) {
+    #[cfg(debug_assertions)]
+    {
+        let thread = thread::current().as_u64();
+        interrupts::without_interrupts(|| {
+            let mut state = STATE.lock();
+            let count = state.held_count;
+            if let Some(i) = state.held[..count]
+                .iter()
+                .rposition(|&held| held == (thread, lock))
+            {
+                state.held.copy_within(i + 1..count, i);
+                state.held_count -= 1;
+            }
+        });
+    }
+    #[cfg(not(debug_assertions))]
+    let _ = lock;
+}
+
+/// Forgets the orders `lock` was part of, since its address may be reused by another lock.
+pub(super) fn forget(lock: usize) {
+    #[cfg(debug_assertions)]
+    interrupts::without_interrupts(|| {
+        let mut state = STATE.lock();
+        let mut kept = 0;
+        for i in 0..state.order_count {
+            let order = state.orders[i];
+            if order.0 != lock && order.1 != lock {
+                state.orders[kept] = order;
+                kept += 1;
+            }
+        }
+        state.order_count = kept;
+    });
+    #[cfg(not(debug_assertions))]
+    let _ = lock;
+}
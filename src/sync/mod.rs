@@ -0,0 +1,22 @@
+// Synchronization primitives for state shared between threads and interrupt handlers.
+//
+// `SpinLock` is for short critical sections and for anything an interrupt handler touches:
+// it disables interrupts while held, so the holder can neither be preempted nor interrupted
+// by a handler that wants the same lock. Everything else blocks the waiting thread on a
+// `WaitQueue`, so the CPU goes to whoever holds the lock instead of being spun away.
+//
+// In debug builds, which includes the test kernels, every lock records the order it is
+// taken in relative to the other locks, see `lock_order`.
+
+mod lock_order;
+mod mutex;
+mod once;
+mod rwlock;
+mod semaphore;
+mod spin_lock;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use once::{Lazy, Once};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use spin_lock::{SpinLock, SpinLockGuard};
@@ -0,0 +1,154 @@
+// A mutex that blocks the waiting threads until it is unlocked.
+
+use super::lock_order;
+use crate::thread::WaitQueue;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+// The lock hands out access to the value to one holder at a time
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        let mutex = ManuallyDrop::new(self);
+        lock_order::forget(mutex.addr());
+        // `mutex` is never dropped, so every field is moved out exactly once
+        unsafe {
+            drop(core::ptr::read(&mutex.waiters));
+            core::ptr::read(&mutex.value).into_inner()
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    fn addr(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    fn try_take(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Blocks until the mutex is free, then locks it. Must not be called from interrupt
+    /// handlers.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        lock_order::acquire(self.addr());
+        while !self.try_take() {
+            self.waiters
+                .wait_until(|| !self.locked.load(Ordering::Relaxed));
+        }
+        MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Locks the mutex if it is free.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if !self.try_take() {
+            return None;
+        }
+        lock_order::hold(self.addr());
+        Some(MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for Mutex<T> {
+    fn drop(&mut self) {
+        lock_order::forget(self.addr());
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    // the lock order checks expect the thread that locked to unlock
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // the guard proves we hold the lock
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        lock_order::release(self.mutex.addr());
+        self.mutex.waiters.notify_one();
+    }
+}
+
+#[test_case]
+fn test_contended_mutex() {
+    use crate::thread;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    let counter = Arc::new(Mutex::new(0u64));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    let mut value = counter.lock();
+                    let read = *value;
+                    // give the others a chance to barge in while the mutex is held
+                    thread::yield_now();
+                    *value = read + 1;
+                }
+            })
+            .unwrap()
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(*counter.lock(), 400);
+}
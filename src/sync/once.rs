@@ -0,0 +1,160 @@
+// One-time initialization, for globals that can't be built in a `const` context.
+
+use crate::thread::WaitQueue;
+use core::cell::{Cell, UnsafeCell};
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value that is set once and then only read.
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+    // threads waiting for another one to finish `init`
+    waiters: WaitQueue,
+}
+
+// Whoever wins the race writes the value, everybody reads it afterwards
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Once<T> {
+        Once {
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Runs `init` if nobody has before, and returns the value. A thread that comes along
+    /// while another one runs `init` waits for it to finish.
+    pub fn call_once(&self, init: impl FnOnce() -> T) -> &T {
+        match self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                // we won the race, nobody else touches the value until it's complete
+                unsafe { (*self.value.get()).write(init()) };
+                self.state.store(COMPLETE, Ordering::Release);
+                self.waiters.notify_all();
+            }
+            Err(_) => {
+                // Blocking rather than yielding, so a waiter with a higher priority doesn't
+                // keep the thread running `init` off the CPU. A panic in `init` stops the
+                // kernel, so the value does get written eventually.
+                self.waiters
+                    .wait_until(|| self.state.load(Ordering::Acquire) == COMPLETE);
+            }
+        }
+        self.get().unwrap()
+    }
+
+    /// The value, if it has been set.
+    pub fn get(&self) -> Option<&T> {
+        match self.state.load(Ordering::Acquire) {
+            // only read once complete, after which the value never changes
+            COMPLETE => Some(unsafe { (*self.value.get()).assume_init_ref() }),
+            _ => None,
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Once<T> {
+        Once::new()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// A value that is built by `init` the first time it is used.
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: Cell<Option<F>>,
+}
+
+// `init` is only taken by the thread that wins the race in `call_once`
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F> Lazy<T, F> {
+    pub const fn new(init: F) -> Lazy<T, F> {
+        Lazy {
+            once: Once::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.once.call_once(|| match self.init.take() {
+            Some(init) => init(),
+            None => unreachable!("`Lazy` initialized twice"),
+        })
+    }
+}
+
+#[test_case]
+fn test_once_runs_init_once() {
+    let once = Once::new();
+    assert!(once.get().is_none());
+    assert_eq!(*once.call_once(|| 1), 1);
+    assert_eq!(*once.call_once(|| 2), 1);
+    assert!(once.is_completed());
+}
+
+#[test_case]
+fn test_once_waits_for_init() {
+    use crate::thread::{self, Priority};
+    use alloc::sync::Arc;
+
+    let once = Arc::new(Once::new());
+    let initializing = {
+        let once = once.clone();
+        thread::spawn_with_priority(Priority::Low, move || {
+            *once.call_once(|| {
+                thread::sleep_ms(20);
+                1
+            })
+        })
+        .unwrap()
+    };
+    // let it start on `init`
+    thread::sleep_ms(5);
+    thread::set_priority(Priority::High);
+    assert_eq!(*once.call_once(|| 2), 1);
+    thread::set_priority(Priority::Normal);
+    assert_eq!(initializing.join(), Some(1));
+}
+
+#[test_case]
+fn test_lazy() {
+    use core::sync::atomic::AtomicUsize;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    static VALUE: Lazy<u64> = Lazy::new(|| {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+        42
+    });
+    assert_eq!(*VALUE, 42);
+    assert_eq!(*VALUE, 42);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,219 @@
+// A reader-writer lock: any number of readers or a single writer, blocking whoever has to
+// wait. Waiting writers keep new readers out, so a steady stream of readers can't starve
+// them.
+
+use super::lock_order;
+use crate::thread::WaitQueue;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// The state is the number of readers, or this while a writer holds the lock
+const WRITER: usize = usize::MAX;
+
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    writers_waiting: AtomicUsize,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+// Readers share the value between threads, so it has to be `Sync` as well
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> RwLock<T> {
+        RwLock {
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    fn addr(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    fn try_take_read(&self) -> bool {
+        if self.writers_waiting.load(Ordering::Relaxed) > 0 {
+            return false;
+        }
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |readers| {
+                (readers < WRITER - 1).then_some(readers + 1)
+            })
+            .is_ok()
+    }
+
+    fn try_take_write(&self) -> bool {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Blocks until no writer holds or waits for the lock, then locks it for reading.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        lock_order::acquire(self.addr());
+        while !self.try_take_read() {
+            self.waiters.wait_until(|| {
+                self.writers_waiting.load(Ordering::Relaxed) == 0
+                    && self.state.load(Ordering::Relaxed) != WRITER
+            });
+        }
+        RwLockReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Blocks until nobody holds the lock, then locks it for writing.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        lock_order::acquire(self.addr());
+        if !self.try_take_write() {
+            self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+            while !self.try_take_write() {
+                self.waiters
+                    .wait_until(|| self.state.load(Ordering::Relaxed) == 0);
+            }
+            self.writers_waiting.fetch_sub(1, Ordering::Relaxed);
+        }
+        RwLockWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        if !self.try_take_read() {
+            return None;
+        }
+        lock_order::hold(self.addr());
+        Some(RwLockReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        if !self.try_take_write() {
+            return None;
+        }
+        lock_order::hold(self.addr());
+        Some(RwLockWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for RwLock<T> {
+    fn drop(&mut self) {
+        lock_order::forget(self.addr());
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> RwLock<T> {
+        RwLock::new(T::default())
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    // the lock order checks expect the thread that locked to unlock
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // readers only ever get shared references
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let readers = self.lock.state.fetch_sub(1, Ordering::Release) - 1;
+        lock_order::release(self.lock.addr());
+        // only a writer can be waiting for the last reader
+        if readers == 0 {
+            self.lock.waiters.notify_all();
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // the guard proves we are the only holder
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        lock_order::release(self.lock.addr());
+        self.lock.waiters.notify_all();
+    }
+}
+
+#[test_case]
+fn test_readers_share_and_writers_exclude() {
+    let lock = RwLock::new(5);
+    {
+        let first = lock.read();
+        let second = lock.try_read().unwrap();
+        assert_eq!(*first + *second, 10);
+        assert!(lock.try_write().is_none());
+    }
+    {
+        let mut writer = lock.write();
+        *writer += 1;
+        assert!(lock.try_read().is_none());
+    }
+    assert_eq!(*lock.read(), 6);
+}
+
+#[test_case]
+fn test_writer_waits_for_readers() {
+    use crate::thread;
+    use alloc::sync::Arc;
+
+    let lock = Arc::new(RwLock::new(0));
+    let reader = lock.read();
+    let writer = {
+        let lock = lock.clone();
+        thread::spawn(move || *lock.write() = 1).unwrap()
+    };
+    thread::sleep_ms(20);
+    // the writer is waiting, so new readers have to wait too
+    assert!(lock.try_read().is_none());
+    assert_eq!(*reader, 0);
+    drop(reader);
+    writer.join().unwrap();
+    assert_eq!(*lock.read(), 1);
+}
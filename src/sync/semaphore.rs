@@ -0,0 +1,76 @@
+// A counting semaphore, blocking the threads that wait for a permit.
+
+use crate::thread::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes a permit if one is available.
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Blocks until a permit is available and takes it. Must not be called from interrupt
+    /// handlers.
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            self.waiters
+                .wait_until(|| self.permits.load(Ordering::Relaxed) > 0);
+        }
+    }
+
+    /// Hands back a permit, waking a waiting thread. Can be called from interrupt handlers.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+#[test_case]
+fn test_semaphore_limits_concurrency() {
+    use crate::thread;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    static INSIDE: AtomicUsize = AtomicUsize::new(0);
+    static MOST_INSIDE: AtomicUsize = AtomicUsize::new(0);
+    let semaphore = Arc::new(Semaphore::new(2));
+    let threads: Vec<_> = (0..5)
+        .map(|_| {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || {
+                semaphore.acquire();
+                let inside = INSIDE.fetch_add(1, Ordering::SeqCst) + 1;
+                MOST_INSIDE.fetch_max(inside, Ordering::SeqCst);
+                thread::sleep_ms(10);
+                INSIDE.fetch_sub(1, Ordering::SeqCst);
+                semaphore.release();
+            })
+            .unwrap()
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(MOST_INSIDE.load(Ordering::SeqCst), 2);
+    assert_eq!(semaphore.available(), 2);
+}
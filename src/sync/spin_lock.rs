@@ -0,0 +1,173 @@
+// A spinlock that keeps interrupts disabled while it is held.
+
+use super::lock_order;
+use core::cell::UnsafeCell;
+use core::hint;
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    // whether the lock takes part in the lock order checks
+    checked: bool,
+    value: UnsafeCell<T>,
+}
+
+// The lock hands out access to the value to one holder at a time
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> SpinLock<T> {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            checked: true,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// A lock that is left out of the lock order checks. Only for the heap allocator, which
+    /// the checks run inside of.
+    pub const fn new_unchecked(value: T) -> SpinLock<T> {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            checked: false,
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        let lock = ManuallyDrop::new(self);
+        lock.forget_order();
+        // `lock` is never dropped, so the value is moved out exactly once
+        unsafe { core::ptr::read(&lock.value) }.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    fn addr(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    fn forget_order(&self) {
+        if self.checked {
+            lock_order::forget(self.addr());
+        }
+    }
+
+    /// Disables interrupts and spins until the lock is free.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if self.checked {
+            lock_order::acquire(self.addr());
+        }
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+        SpinLockGuard {
+            lock: self,
+            interrupts_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Takes the lock if it is free.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            if interrupts_enabled {
+                interrupts::enable();
+            }
+            return None;
+        }
+        if self.checked {
+            lock_order::hold(self.addr());
+        }
+        Some(SpinLockGuard {
+            lock: self,
+            interrupts_enabled,
+            _not_send: PhantomData,
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLock<T> {
+    fn drop(&mut self) {
+        self.forget_order();
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> SpinLock<T> {
+        SpinLock::new(T::default())
+    }
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    interrupts_enabled: bool,
+    // interrupts have to be enabled again on the CPU that disabled them
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // the guard proves we hold the lock
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        if self.lock.checked {
+            lock_order::release(self.lock.addr());
+        }
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_interrupts_disabled_while_held() {
+    let lock = SpinLock::new(1);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.try_lock().is_none());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(lock.into_inner(), 2);
+}
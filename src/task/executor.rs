@@ -0,0 +1,175 @@
+// Runs tasks whenever they are woken. Waking a task puts its ID into the ready queue, which
+// is lock free, so interrupt handlers can do it.
+
+use super::{timer, Task, TaskId};
+use crate::queue::ArrayQueue;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts;
+
+/// How many tasks an executor can run at once. A task is in the ready queue at most once,
+/// so the queue can't overflow.
+pub const MAX_TASKS: usize = 256;
+
+type ReadyQueue = ArrayQueue<TaskId, MAX_TASKS>;
+
+struct TaskWaker {
+    id: TaskId,
+    // whether the task is in the ready queue already, so that waking it over and over
+    // doesn't fill the queue
+    queued: AtomicBool,
+    ready: Arc<ReadyQueue>,
+}
+
+impl TaskWaker {
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            // every task is in the queue at most once, and there are no more than fit
+            let _ = self.ready.push(self.id);
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, (Task, Arc<TaskWaker>)>,
+    ready: Arc<ReadyQueue>,
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor {
+            tasks: BTreeMap::new(),
+            ready: Arc::new(ArrayQueue::new()),
+        }
+    }
+
+    /// Adds a task, which first runs the next time the executor looks for ready tasks.
+    pub fn spawn(&mut self, task: Task) {
+        assert!(
+            self.tasks.len() < MAX_TASKS,
+            "executor can't run more than {} tasks",
+            MAX_TASKS
+        );
+        let id = task.id();
+        let waker = Arc::new(TaskWaker {
+            id,
+            queued: AtomicBool::new(false),
+            ready: self.ready.clone(),
+        });
+        waker.wake_task();
+        if self.tasks.insert(id, (task, waker)).is_some() {
+            panic!("task {:?} spawned twice", id);
+        }
+    }
+
+    /// Whether all tasks have completed.
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Runs the tasks forever, halting the CPU whenever none of them is ready.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Runs the tasks until all of them have completed.
+    pub fn run_until_complete(&mut self) {
+        while !self.is_empty() {
+            self.run_ready_tasks();
+            if !self.is_empty() {
+                self.sleep_if_idle();
+            }
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        timer::wake_expired();
+        while let Some(id) = self.ready.pop() {
+            // wakers can outlive their task, their wake ups are ignored
+            let Some((task, waker)) = self.tasks.get_mut(&id) else {
+                continue;
+            };
+            // cleared before polling, so that a wake up during the poll queues it again
+            waker.queued.store(false, Ordering::Release);
+            let context_waker = Waker::from(waker.clone());
+            if let Poll::Ready(()) = task.poll(&mut Context::from_waker(&context_waker)) {
+                self.tasks.remove(&id);
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // an interrupt between the check and the `hlt` would otherwise only be noticed after
+        // the next one
+        interrupts::disable();
+        if self.ready.is_empty() && !timer::has_expired() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Executor {
+        Executor::new()
+    }
+}
+
+#[test_case]
+fn test_tasks_interleave() {
+    use alloc::rc::Rc;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for name in ['a', 'b'] {
+        let log = log.clone();
+        executor.spawn(Task::new(async move {
+            for _ in 0..2 {
+                log.borrow_mut().push(name);
+                super::yield_now().await;
+            }
+        }));
+    }
+    executor.run_until_complete();
+    assert_eq!(*log.borrow(), ['a', 'b', 'a', 'b']);
+}
+
+#[test_case]
+fn test_wakers_of_completed_tasks_are_ignored() {
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    let stored = Rc::new(RefCell::new(None));
+    let mut executor = Executor::new();
+    let inner = stored.clone();
+    executor.spawn(Task::new(async move {
+        core::future::poll_fn(|context| {
+            *inner.borrow_mut() = Some(context.waker().clone());
+            Poll::Ready(())
+        })
+        .await
+    }));
+    executor.run_until_complete();
+    stored.borrow().as_ref().unwrap().wake_by_ref();
+    executor.run_ready_tasks();
+    assert!(executor.is_empty());
+}
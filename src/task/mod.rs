@@ -0,0 +1,118 @@
+// Cooperative kernel tasks, written as futures and driven by the executor.
+//
+// A task runs until it has to wait for something, then returns `Poll::Pending` and the
+// executor moves on. Interrupt handlers wake the task again once what it waits for has
+// happened, and when no task is ready the executor halts the CPU until the next interrupt.
+
+pub mod executor;
+pub mod stream;
+pub mod timer;
+
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub use executor::Executor;
+pub use stream::{Stream, StreamExt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> TaskId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// A future the executor runs to completion.
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+/// The waker of the task waiting for an interrupt, to be woken from the interrupt handler.
+///
+/// Only one task can wait at a time, registering replaces the previous waker.
+pub struct InterruptWaker {
+    waker: Mutex<Option<Waker>>,
+}
+
+impl InterruptWaker {
+    pub const fn new() -> InterruptWaker {
+        InterruptWaker {
+            waker: Mutex::new(None),
+        }
+    }
+
+    // The lock is only ever taken with interrupts disabled, so an interrupt handler can't
+    // spin on it forever. Waking leaves the waker in place, dropping it in the interrupt
+    // handler could free it, and the heap may be locked by whatever was interrupted.
+
+    pub fn register(&self, waker: &Waker) {
+        interrupts::without_interrupts(|| {
+            let mut slot = self.waker.lock();
+            match &*slot {
+                Some(registered) if registered.will_wake(waker) => {}
+                _ => *slot = Some(waker.clone()),
+            }
+        });
+    }
+
+    pub fn wake(&self) {
+        interrupts::without_interrupts(|| {
+            if let Some(waker) = &*self.waker.lock() {
+                waker.wake_by_ref();
+            }
+        });
+    }
+}
+
+impl Default for InterruptWaker {
+    fn default() -> InterruptWaker {
+        InterruptWaker::new()
+    }
+}
+
+/// Lets the other ready tasks run before the calling task continues.
+pub async fn yield_now() {
+    struct YieldNow {
+        yielded: bool,
+    }
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            if self.yielded {
+                return Poll::Ready(());
+            }
+            self.yielded = true;
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    YieldNow { yielded: false }.await
+}
@@ -0,0 +1,40 @@
+// Streams are the asynchronous version of iterators: a future that can complete more than
+// once. The trait mirrors the one of the `futures` crate, reduced to what the kernel uses.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// A source of values that arrive over time.
+pub trait Stream {
+    type Item;
+
+    /// Returns the next value if there is one, `Poll::Ready(None)` if the stream has ended,
+    /// and otherwise arranges for the task to be woken once there is a value.
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<Self::Item>>;
+}
+
+pub trait StreamExt: Stream {
+    /// Waits for the next value, `None` if the stream has ended.
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Unpin,
+    {
+        Next { stream: self }
+    }
+}
+
+impl<S: Stream + ?Sized> StreamExt for S {}
+
+/// The future returned by `StreamExt::next`.
+pub struct Next<'a, S: ?Sized> {
+    stream: &'a mut S,
+}
+
+impl<S: Stream + Unpin + ?Sized> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(context)
+    }
+}
@@ -0,0 +1,153 @@
+// Futures that complete at a point in time, measured in timer ticks.
+//
+// The waiting tasks are kept sorted by deadline. The timer interrupt doesn't look at them,
+// it only wakes the CPU from its `hlt`, and the executor then wakes whichever tasks are due.
+// That keeps the list out of interrupt context, so a plain spinlock protects it.
+
+use super::Stream;
+use crate::time::{self, ms_to_ticks};
+use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+// The second part of the key tells apart timers with the same deadline
+type TimerKey = (u64, u64);
+
+static TIMERS: Mutex<BTreeMap<TimerKey, Waker>> = Mutex::new(BTreeMap::new());
+
+fn next_key(deadline: u64) -> TimerKey {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    (deadline, NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Wakes the tasks whose deadline has passed. Called by the executor.
+pub(super) fn wake_expired() {
+    let now = time::ticks();
+    loop {
+        // the timer list isn't locked while waking, the waker could be anything
+        let waker = {
+            let mut timers = TIMERS.lock();
+            match timers.first_key_value() {
+                Some((&(deadline, _), _)) if deadline <= now => timers.pop_first().unwrap().1,
+                _ => break,
+            }
+        };
+        waker.wake();
+    }
+}
+
+/// Whether a task's deadline has passed that `wake_expired` hasn't woken yet.
+pub(super) fn has_expired() -> bool {
+    TIMERS
+        .lock()
+        .first_key_value()
+        .is_some_and(|(&(deadline, _), _)| deadline <= time::ticks())
+}
+
+/// Completes once the tick counter has reached `deadline`.
+pub struct Sleep {
+    deadline: u64,
+    key: Option<TimerKey>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if time::ticks() >= self.deadline {
+            if let Some(key) = self.key.take() {
+                TIMERS.lock().remove(&key);
+            }
+            return Poll::Ready(());
+        }
+        let deadline = self.deadline;
+        let key = *self.key.get_or_insert_with(|| next_key(deadline));
+        // also puts the timer back if `wake_expired` took it out, for a spurious poll
+        TIMERS.lock().insert(key, context.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            TIMERS.lock().remove(&key);
+        }
+    }
+}
+
+/// Waits until the tick counter has reached `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        deadline,
+        key: None,
+    }
+}
+
+/// Waits for at least `ms` milliseconds.
+pub fn sleep_ms(ms: u64) -> Sleep {
+    sleep_until(time::ticks().saturating_add(ms_to_ticks(ms)))
+}
+
+/// A stream that yields the tick count every `period` ticks. Missed periods are made up
+/// for right away, so the average rate stays the same when the task is late.
+pub struct Interval {
+    period: u64,
+    sleep: Sleep,
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u64>> {
+        match Pin::new(&mut self.sleep).poll(context) {
+            Poll::Ready(()) => {
+                let deadline = self.sleep.deadline;
+                self.sleep = sleep_until(deadline + self.period);
+                Poll::Ready(Some(deadline))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Yields every `ms` milliseconds, starting `ms` milliseconds from now.
+pub fn interval_ms(ms: u64) -> Interval {
+    let period = ms_to_ticks(ms).max(1);
+    Interval {
+        period,
+        sleep: sleep_until(time::ticks() + period),
+    }
+}
+
+#[test_case]
+fn test_sleep() {
+    use super::{Executor, Task};
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let start = time::ticks();
+        sleep_ms(30).await;
+        assert!(time::ticks() >= start + ms_to_ticks(30));
+    }));
+    executor.run_until_complete();
+    assert!(TIMERS.lock().is_empty());
+}
+
+#[test_case]
+fn test_interval() {
+    use super::{Executor, StreamExt, Task};
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let mut interval = interval_ms(10);
+        let first = interval.next().await.unwrap();
+        let second = interval.next().await.unwrap();
+        assert_eq!(second, first + ms_to_ticks(10));
+        assert!(time::ticks() >= second);
+    }));
+    executor.run_until_complete();
+}
@@ -0,0 +1,111 @@
+// In-kernel test framework. The kernel is its own test harness: `cargo test` builds a test
+// kernel whose `_start` calls the runner, which reports over serial and then shuts QEMU down
+// through the `isa-debug-exit` device with an exit code that tells bootimage the outcome.
+
+use crate::{serial_print, serial_println};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+
+// IO port of the `isa-debug-exit` device, see `test-args` in Cargo.toml
+const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Exit codes for the `isa-debug-exit` device. QEMU exits with `(code << 1) | 1`, so these
+/// are picked to not collide with QEMU's own exit codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Shuts QEMU down with the given exit code.
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    let mut port = Port::new(ISA_DEBUG_EXIT_PORT);
+    // without the device this write is ignored, so it's harmless outside QEMU
+    unsafe {
+        port.write(exit_code as u32);
+    }
+}
+
+// Set while a test that is expected to panic runs, so the panic handler knows the panic is a pass
+static EXPECTING_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Something the test runner can run, i.e. an item marked `#[test_case]`.
+pub trait Testable {
+    fn run(&self);
+
+    fn should_panic(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+/// A test that passes only if it panics.
+///
+/// The kernel can't unwind, so a panic ends the test run. The runner therefore runs this kind
+/// of test after all others, and allows only one per test kernel. See `tests/should_panic.rs`.
+pub struct ShouldPanic {
+    name: &'static str,
+    test: fn(),
+}
+
+impl ShouldPanic {
+    pub const fn new(name: &'static str, test: fn()) -> ShouldPanic {
+        ShouldPanic { name, test }
+    }
+}
+
+impl Testable for ShouldPanic {
+    fn run(&self) {
+        serial_print!("{} (should panic)...\t", self.name);
+        EXPECTING_PANIC.store(true, Ordering::SeqCst);
+        (self.test)();
+        EXPECTING_PANIC.store(false, Ordering::SeqCst);
+        serial_println!("[failed]\n\nError: test did not panic\n");
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    fn should_panic(&self) -> bool {
+        true
+    }
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+
+    let panicking = tests.iter().filter(|test| test.should_panic()).count();
+    assert!(
+        panicking <= 1,
+        "{} should-panic tests in one kernel, but the first panic ends the run",
+        panicking
+    );
+
+    for test in tests.iter().filter(|test| !test.should_panic()) {
+        test.run();
+    }
+    for test in tests.iter().filter(|test| test.should_panic()) {
+        test.run();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Panic handler for test kernels. A panic is a failure unless a should-panic test is running.
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    if EXPECTING_PANIC.load(Ordering::SeqCst) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    crate::hlt_loop();
+}
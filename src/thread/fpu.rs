@@ -0,0 +1,61 @@
+// x87 FPU and SSE register state. The kernel itself is built without SSE and does floating
+// point in software, so the registers only ever hold what threads put there themselves, and
+// the scheduler can save and restore them around every switch.
+
+use core::arch::asm;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+// Control word with all exceptions masked, 64 bit precision and round to nearest
+const DEFAULT_FCW: u16 = 0x037f;
+// All SSE exceptions masked, round to nearest
+const DEFAULT_MXCSR: u32 = 0x1f80;
+const MXCSR_OFFSET: usize = 24;
+
+/// The area `fxsave` writes the x87 and SSE registers to.
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// The state right after `fninit`, with empty registers.
+    pub fn new() -> FpuState {
+        let mut state = FpuState([0; 512]);
+        state.0[..2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        state.0[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        state
+    }
+
+    /// Saves the current registers.
+    pub fn save(&mut self) {
+        // the area is 16 byte aligned and large enough
+        unsafe { asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack)) };
+    }
+
+    /// Loads the registers from the saved state.
+    pub fn restore(&self) {
+        // only `new` and `save` write the area, so the reserved MXCSR bits are clear
+        unsafe { asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack)) };
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> FpuState {
+        FpuState::new()
+    }
+}
+
+/// Enables the FPU and SSE instructions, along with `fxsave`/`fxrstor`.
+pub fn init() {
+    // MP makes `wait` respect TS, EM would turn every FPU instruction into an exception.
+    // OSFXSR enables SSE and the full `fxsave` area, OSXMMEXCPT reports SSE exceptions as
+    // such instead of as invalid opcodes.
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+        });
+        asm!("fninit", options(nomem, nostack));
+    }
+}
@@ -0,0 +1,770 @@
+// Preemptive kernel threads.
+//
+// Every thread has its own kernel stack. A thread is switched away from inside an interrupt
+// handler: the entry stub has already saved all its registers as a `TrapFrame` on its stack,
+// so switching only means remembering where that frame is and returning another thread's
+// frame to the stub, which restores the registers from it. The timer interrupt does that
+// to preempt threads, and `yield_now` raises an interrupt of its own to do it voluntarily.
+//
+// A thread that exits is switched away from for good. A reaper thread then frees its stack,
+// address space and closure, and takes detached threads out of the table altogether. Threads
+// somebody will join keep the little `join` needs until then.
+//
+// Threads that wait for something block: they are taken off the ready queues until whatever
+// they wait for wakes them, either a `WaitQueue` or the timer for sleeping threads.
+//
+// A thread can also run in a user address space, which is loaded into CR3 whenever it is
+// switched to. Its kernel stack is where the CPU goes when user code is interrupted or makes
+// a system call, so the switch also points the TSS at it.
+
+pub mod fpu;
+pub mod scheduler;
+pub mod stack;
+mod wait_queue;
+
+use crate::gdt;
+use crate::interrupts::TrapFrame;
+use crate::memory::address_space::AddressSpace;
+use crate::memory::paging::{kernel_page_tables, MapError};
+use crate::time::{self, ms_to_ticks};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use fpu::FpuState;
+use scheduler::{time_slice, Scheduler, BOOST_INTERVAL_TICKS, LEVELS};
+use spin::{Mutex, Once};
+use stack::KernelStack;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+
+pub use scheduler::Priority;
+pub use wait_queue::WaitQueue;
+
+/// Vector of the software interrupt `yield_now` raises.
+pub const YIELD_VECTOR: u8 = 64;
+
+// Interrupts enabled, plus the bit that is always set
+const INITIAL_RFLAGS: u64 = 0x202;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> ThreadId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    /// Waiting to be woken, off the ready queues.
+    Blocked,
+    Exited,
+}
+
+/// What the scheduler knows about a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadStats {
+    pub state: ThreadState,
+    pub priority: Priority,
+    /// The scheduler level the thread is on right now.
+    pub level: usize,
+    /// Timer ticks the thread was running for.
+    pub cpu_ticks: u64,
+}
+
+struct Thread {
+    state: ThreadState,
+    priority: Priority,
+    level: usize,
+    // ticks used of the time slice on the current level
+    slice_used: u32,
+    cpu_ticks: u64,
+    // where the registers were saved when the thread was switched away from
+    frame: *mut TrapFrame,
+    // `None` for the boot thread, which runs on the stack the bootloader set up
+    stack: Option<KernelStack>,
+    // `None` for threads that only run kernel code
+    address_space: Option<Arc<AddressSpace>>,
+    fpu: Box<FpuState>,
+    // What the thread runs. It stays here rather than on the thread's stack, which is
+    // abandoned without unwinding when the thread calls `exit`.
+    main: Option<Box<ThreadMain>>,
+    // nobody will join the thread, so it is cleaned up after it exits
+    detached: bool,
+    // woken when the thread exits
+    exited: Arc<WaitQueue>,
+}
+
+impl Thread {
+    fn has_remains(&self) -> bool {
+        self.state == ThreadState::Exited
+            && (self.detached
+                || self.stack.is_some()
+                || self.address_space.is_some()
+                || self.main.is_some())
+    }
+
+    fn stats(&self) -> ThreadStats {
+        ThreadStats {
+            state: self.state,
+            priority: self.priority,
+            level: self.level,
+            cpu_ticks: self.cpu_ticks,
+        }
+    }
+}
+
+// The frame pointer points into the thread's own stack
+unsafe impl Send for Thread {}
+
+struct ThreadTable {
+    threads: BTreeMap<ThreadId, Thread>,
+    current: ThreadId,
+    // runs when no other thread is ready, and is never queued itself
+    idle: ThreadId,
+    scheduler: Scheduler,
+    // blocked threads and the tick they want to be woken at
+    sleeping: BTreeSet<(u64, ThreadId)>,
+    last_boost: u64,
+    // the page tables of threads without an address space of their own
+    kernel_root: PhysFrame,
+}
+
+impl ThreadTable {
+    fn current(&mut self) -> &mut Thread {
+        self.threads.get_mut(&self.current).unwrap()
+    }
+
+    // Saves the interrupted thread and returns the frame of the one to continue with. A
+    // thread that is still running goes back into the queue first, so it keeps the CPU if
+    // nothing more important is ready.
+    fn switch(&mut self, frame: *mut TrapFrame) -> *mut TrapFrame {
+        let current = self.current;
+        let thread = self.current();
+        let still_running = thread.state == ThreadState::Running;
+        let level = thread.level;
+        if still_running && current != self.idle {
+            self.scheduler.add(current, level);
+        }
+        let next = self.scheduler.next().unwrap_or(self.idle);
+        if next == current {
+            return frame;
+        }
+
+        let thread = self.current();
+        thread.frame = frame;
+        thread.fpu.save();
+        if still_running {
+            thread.state = ThreadState::Ready;
+        }
+
+        let thread = self.threads.get_mut(&next).unwrap();
+        thread.state = ThreadState::Running;
+        thread.fpu.restore();
+        if let Some(stack) = &thread.stack {
+            gdt::set_kernel_stack(stack.top());
+        }
+        let root = thread
+            .address_space
+            .as_ref()
+            .map_or(self.kernel_root, |space| space.root());
+        if Cr3::read().0 != root {
+            // both hierarchies map the kernel the same way, including the stack we are on
+            unsafe { Cr3::write(root, Cr3::read().1) };
+        }
+        self.current = next;
+        CURRENT.store(next.0, Ordering::Relaxed);
+        thread.frame
+    }
+
+    // Charges the running thread for the tick and switches if its slice is used up or a
+    // thread on a higher level has become ready
+    fn tick(&mut self, frame: *mut TrapFrame) -> *mut TrapFrame {
+        let now = time::ticks();
+        self.wake_sleeping(now);
+        if now - self.last_boost >= BOOST_INTERVAL_TICKS {
+            self.boost(now);
+        }
+
+        if self.current == self.idle {
+            return match self.scheduler.highest_level() {
+                Some(_) => self.switch(frame),
+                None => frame,
+            };
+        }
+        let thread = self.current();
+        thread.cpu_ticks += 1;
+        thread.slice_used += 1;
+        if thread.slice_used >= time_slice(thread.level) {
+            thread.slice_used = 0;
+            thread.level = (thread.level + 1).min(LEVELS - 1);
+            return self.switch(frame);
+        }
+        let level = thread.level;
+        match self.scheduler.highest_level() {
+            Some(ready) if ready < level => self.switch(frame),
+            _ => frame,
+        }
+    }
+
+    fn wake_sleeping(&mut self, now: u64) {
+        while let Some(&(deadline, id)) = self.sleeping.first() {
+            if deadline > now {
+                break;
+            }
+            self.sleeping.pop_first();
+            self.wake(id);
+        }
+    }
+
+    // Puts every thread back on the level of its priority
+    fn boost(&mut self, now: u64) {
+        self.last_boost = now;
+        for thread in self.threads.values_mut() {
+            thread.level = thread.priority.level();
+            thread.slice_used = 0;
+        }
+        let ready: Vec<ThreadId> = self.scheduler.take_all().collect();
+        for id in ready {
+            let level = self.threads[&id].level;
+            self.scheduler.add(id, level);
+        }
+    }
+
+    // Makes a blocked thread ready again. Waking a thread that isn't blocked does nothing.
+    fn wake(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            if thread.state == ThreadState::Blocked {
+                thread.state = ThreadState::Ready;
+                let level = thread.level;
+                self.scheduler.add(id, level);
+            }
+        }
+    }
+
+    // Whether an exited thread still holds on to something the reaper should free
+    fn has_remains(&self) -> bool {
+        self.threads.values().any(Thread::has_remains)
+    }
+
+    // Takes what the exited threads left behind out of the table: detached threads entirely,
+    // the others down to what `join` needs
+    fn take_remains(&mut self) -> (Vec<Thread>, Vec<Remains>) {
+        let exited: Vec<ThreadId> = self
+            .threads
+            .iter()
+            .filter(|(_, thread)| thread.has_remains())
+            .map(|(&id, _)| id)
+            .collect();
+        let mut detached = Vec::new();
+        let mut remains = Vec::new();
+        for id in exited {
+            let thread = self.threads.get_mut(&id).unwrap();
+            match thread.detached {
+                true => detached.extend(self.threads.remove(&id)),
+                false => remains.push((
+                    thread.stack.take(),
+                    thread.address_space.take(),
+                    thread.main.take(),
+                )),
+            }
+        }
+        (detached, remains)
+    }
+}
+
+// What an exited thread that is still going to be joined no longer needs
+type Remains = (
+    Option<KernelStack>,
+    Option<Arc<AddressSpace>>,
+    Option<Box<ThreadMain>>,
+);
+
+static THREADS: Once<Mutex<ThreadTable>> = Once::new();
+
+// Notified when a thread exits, for the reaper
+static EXITED: WaitQueue = WaitQueue::new();
+
+// The running thread, readable without locking the table. The code running since boot
+// becomes thread 0, so this is right even before `init`.
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+
+// The interrupt handlers lock the table too, so it's only ever locked with interrupts
+// disabled. Threads taken out of the table have to be dropped outside of this, freeing
+// their stacks takes other locks.
+fn with_table<R>(f: impl FnOnce(&mut ThreadTable) -> R) -> R {
+    let table = THREADS.get().expect("thread::init has not been called");
+    interrupts::without_interrupts(|| f(&mut table.lock()))
+}
+
+/// Turns the code running since boot into the first thread and starts the idle thread.
+/// Must run before the timer interrupt is enabled, and after the heap is set up.
+pub fn init() {
+    fpu::init();
+    let boot = ThreadId::new();
+    CURRENT.store(boot.0, Ordering::Relaxed);
+    let mut threads = BTreeMap::new();
+    threads.insert(
+        boot,
+        Thread {
+            state: ThreadState::Running,
+            priority: Priority::Normal,
+            level: Priority::Normal.level(),
+            slice_used: 0,
+            cpu_ticks: 0,
+            frame: core::ptr::null_mut(),
+            stack: None,
+            address_space: None,
+            fpu: Box::new(FpuState::new()),
+            main: None,
+            detached: true,
+            exited: Arc::new(WaitQueue::new()),
+        },
+    );
+    let idle = ThreadId::new();
+    let idle_thread = new_thread(idle_main, core::ptr::null_mut(), Priority::Low, None)
+        .expect("failed to map the idle thread's stack");
+    threads.insert(idle, idle_thread);
+    THREADS.call_once(|| {
+        Mutex::new(ThreadTable {
+            threads,
+            current: boot,
+            idle,
+            scheduler: Scheduler::new(),
+            sleeping: BTreeSet::new(),
+            last_boost: 0,
+            kernel_root: kernel_page_tables().pml4_frame(),
+        })
+    });
+    // runs as soon as there is something to free, it never exits
+    drop(spawn_with_priority(Priority::High, reap).expect("failed to start the reaper"));
+}
+
+// Frees what exited threads leave behind. An exiting thread notifies `EXITED` only after it
+// has been marked as exited, and it's switched away from before the reaper gets to run.
+fn reap() {
+    loop {
+        EXITED.wait_until(|| with_table(|table| table.has_remains()));
+        let remains = with_table(ThreadTable::take_remains);
+        drop(remains);
+    }
+}
+
+extern "C" fn idle_main(_: *mut u8) -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+// Sets up a stack whose frame starts `main` with `argument` once the thread is switched to
+fn new_thread(
+    main: extern "C" fn(*mut u8) -> !,
+    argument: *mut u8,
+    priority: Priority,
+    address_space: Option<Arc<AddressSpace>>,
+) -> Result<Thread, MapError> {
+    let stack = KernelStack::new()?;
+    let selectors = gdt::selectors();
+    let frame_addr = stack.top() - mem::size_of::<TrapFrame>() as u64;
+    let frame = frame_addr.as_mut_ptr::<TrapFrame>();
+    // The frame is at the very top of the new stack. `main` is entered like a function
+    // that was just called, with the stack pointer 8 bytes below a 16 byte boundary.
+    unsafe {
+        frame.write(TrapFrame {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            r11: 0,
+            r10: 0,
+            r9: 0,
+            r8: 0,
+            rbp: 0,
+            rdi: argument as u64,
+            rsi: 0,
+            rdx: 0,
+            rcx: 0,
+            rbx: 0,
+            rax: 0,
+            vector: 0,
+            error_code: 0,
+            rip: main as usize as u64,
+            cs: u64::from(selectors.kernel_code.0),
+            rflags: INITIAL_RFLAGS,
+            rsp: (stack.top() - 8u64).as_u64(),
+            ss: u64::from(selectors.kernel_data.0),
+        });
+    }
+    Ok(Thread {
+        state: ThreadState::Ready,
+        priority,
+        level: priority.level(),
+        slice_used: 0,
+        cpu_ticks: 0,
+        frame,
+        stack: Some(stack),
+        address_space,
+        fpu: Box::new(FpuState::new()),
+        main: None,
+        detached: false,
+        exited: Arc::new(WaitQueue::new()),
+    })
+}
+
+// Only ever called once, but the thread's record keeps owning it
+type ThreadMain = Box<dyn FnMut() + Send>;
+
+extern "C" fn thread_main(main: *mut u8) -> ! {
+    // points into the thread's record, which outlives the thread
+    let main = unsafe { &mut *(main as *mut ThreadMain) };
+    main();
+    exit();
+}
+
+/// Called on the timer interrupt. Returns the frame to return to.
+pub(crate) fn tick(frame: *mut TrapFrame) -> *mut TrapFrame {
+    // the timer already runs before the threads are set up
+    match THREADS.get() {
+        Some(table) => table.lock().tick(frame),
+        None => frame,
+    }
+}
+
+/// Called on the interrupt `yield_now` raises. Returns the frame to return to.
+pub(crate) fn switch(frame: *mut TrapFrame) -> *mut TrapFrame {
+    match THREADS.get() {
+        Some(table) => table.lock().switch(frame),
+        None => frame,
+    }
+}
+
+/// The thread that is running.
+pub fn current() -> ThreadId {
+    ThreadId(CURRENT.load(Ordering::Relaxed))
+}
+
+/// Lets the other ready threads run before the calling thread continues.
+pub fn yield_now() {
+    // software interrupts are delivered even with interrupts disabled
+    unsafe { asm!("int {}", const YIELD_VECTOR) };
+}
+
+// Takes the calling thread off the CPU until `wake` is called for it. Interrupts have to be
+// disabled since before the thread registered itself wherever the wake up comes from, or it
+// could come before the thread is blocked and get lost.
+fn block() {
+    debug_assert!(!interrupts::are_enabled());
+    with_table(|table| table.current().state = ThreadState::Blocked);
+    yield_now();
+}
+
+fn wake(id: ThreadId) {
+    with_table(|table| table.wake(id));
+}
+
+/// Blocks the calling thread until the tick counter has reached `deadline`.
+pub fn sleep_until(deadline: u64) {
+    interrupts::without_interrupts(|| {
+        if time::ticks() >= deadline {
+            return;
+        }
+        with_table(|table| {
+            let id = table.current;
+            table.sleeping.insert((deadline, id));
+        });
+        block();
+    });
+}
+
+/// Blocks the calling thread for at least `ms` milliseconds.
+pub fn sleep_ms(ms: u64) {
+    // `ms` may come from user code
+    sleep_until(time::ticks().saturating_add(ms_to_ticks(ms)));
+}
+
+/// Ends the calling thread. Its `JoinHandle` will return `None`.
+///
+/// The thread's stack is abandoned, so nothing on it is dropped. Whatever the spawned
+/// closure captured is freed along with the thread, but values it moved onto the stack
+/// are lost.
+pub fn exit() -> ! {
+    // the thread must not be preempted between exiting and switching away
+    interrupts::disable();
+    let exited = with_table(|table| {
+        let thread = table.current();
+        thread.state = ThreadState::Exited;
+        thread.exited.clone()
+    });
+    exited.notify_all();
+    drop(exited);
+    EXITED.notify_all();
+    yield_now();
+    unreachable!("exited thread was switched back to");
+}
+
+/// Changes the priority of the calling thread, which also moves it to the priority's level.
+pub fn set_priority(priority: Priority) {
+    with_table(|table| {
+        let thread = table.current();
+        thread.priority = priority;
+        thread.level = priority.level();
+        thread.slice_used = 0;
+    });
+}
+
+/// The user address space of the calling thread, if it has one.
+pub fn address_space() -> Option<Arc<AddressSpace>> {
+    with_table(|table| table.current().address_space.clone())
+}
+
+/// What the scheduler knows about the given thread, `None` if there is no such thread.
+pub fn stats(id: ThreadId) -> Option<ThreadStats> {
+    with_table(|table| table.threads.get(&id).map(Thread::stats))
+}
+
+/// Starts a thread running `f`, with normal priority.
+pub fn spawn<F, T>(f: F) -> Result<JoinHandle<T>, MapError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_with_priority(Priority::Normal, f)
+}
+
+/// Starts a thread running `f`.
+pub fn spawn_with_priority<F, T>(priority: Priority, f: F) -> Result<JoinHandle<T>, MapError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_inner(priority, None, f)
+}
+
+/// Starts a thread running `f` in the given user address space, with normal priority. The
+/// thread runs kernel code until it enters user mode.
+pub fn spawn_in<F, T>(address_space: Arc<AddressSpace>, f: F) -> Result<JoinHandle<T>, MapError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_inner(Priority::Normal, Some(address_space), f)
+}
+
+fn spawn_inner<F, T>(
+    priority: Priority,
+    address_space: Option<Arc<AddressSpace>>,
+    f: F,
+) -> Result<JoinHandle<T>, MapError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let result_slot = result.clone();
+    let mut f = Some(f);
+    let mut main: Box<ThreadMain> = Box::new(Box::new(move || {
+        if let Some(f) = f.take() {
+            let value = f();
+            *result_slot.lock() = Some(value);
+        }
+    }));
+    let argument = &mut *main as *mut ThreadMain as *mut u8;
+    let mut thread = new_thread(thread_main, argument, priority, address_space)?;
+    // moving the box doesn't move what it points to
+    thread.main = Some(main);
+
+    let id = ThreadId::new();
+    let exited = thread.exited.clone();
+    with_table(|table| {
+        table.threads.insert(id, thread);
+        table.scheduler.add(id, priority.level());
+    });
+    Ok(JoinHandle { id, result, exited })
+}
+
+/// Owns a spawned thread. Dropping it detaches the thread, which is then taken out of the
+/// table as soon as it has exited.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+    exited: Arc<WaitQueue>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Whether the thread has exited.
+    pub fn is_finished(&self) -> bool {
+        with_table(|table| table.threads[&self.id].state == ThreadState::Exited)
+    }
+
+    /// Waits for the thread to exit and returns what it returned, `None` if it called `exit`.
+    pub fn join(self) -> Option<T> {
+        self.exited.wait_until(|| self.is_finished());
+        let thread = with_table(|table| table.threads.remove(&self.id));
+        drop(thread);
+        self.result.lock().take()
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        // after `join` the thread is gone already
+        let exited = with_table(|table| {
+            let thread = table.threads.get_mut(&self.id)?;
+            thread.detached = true;
+            match thread.state {
+                ThreadState::Exited => table.threads.remove(&self.id),
+                _ => None,
+            }
+        });
+        drop(exited);
+    }
+}
+
+#[test_case]
+fn test_spawn_and_join() {
+    let handle = spawn(|| 6 * 7).unwrap();
+    assert_ne!(handle.id(), current());
+    assert_eq!(handle.join(), Some(42));
+}
+
+#[test_case]
+fn test_exit() {
+    let handle = spawn(|| -> u32 { exit() }).unwrap();
+    assert_eq!(handle.join(), None);
+}
+
+#[test_case]
+fn test_exit_frees_the_thread() {
+    use crate::allocator;
+
+    let run = || assert_eq!(spawn(|| -> u32 { exit() }).unwrap().join(), None);
+    // the first run may leave the table and queues with more capacity
+    run();
+    let before = allocator::stats();
+    run();
+    let after = allocator::stats();
+    assert_eq!(after.used, before.used);
+    assert_eq!(after.allocations, before.allocations);
+}
+
+#[test_case]
+fn test_threads_are_preempted() {
+    use core::sync::atomic::AtomicBool;
+
+    static RELEASED: AtomicBool = AtomicBool::new(false);
+    RELEASED.store(false, Ordering::SeqCst);
+    // never yields, so the other thread only runs if this one is preempted
+    let spinning = spawn(|| {
+        while !RELEASED.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    })
+    .unwrap();
+    let releasing = spawn(|| RELEASED.store(true, Ordering::SeqCst)).unwrap();
+    spinning.join().unwrap();
+    releasing.join().unwrap();
+}
+
+#[test_case]
+fn test_sse_registers_are_saved() {
+    // each thread keeps its own value in xmm0 across a few switches
+    let threads: Vec<_> = (1..=3u64)
+        .map(|value| {
+            spawn(move || {
+                let mut read: u64;
+                unsafe { asm!("movq xmm0, {}", in(reg) value) };
+                for _ in 0..10 {
+                    yield_now();
+                }
+                unsafe { asm!("movq {}, xmm0", out(reg) read) };
+                read
+            })
+            .unwrap()
+        })
+        .collect();
+    for (value, thread) in (1..=3u64).zip(threads) {
+        assert_eq!(thread.join(), Some(value));
+    }
+}
+
+#[test_case]
+fn test_detached_threads_are_cleaned_up() {
+    drop(spawn(|| {}).unwrap());
+    // a few switches are enough for it to run and for the reaper to take it out of the table
+    for _ in 0..10 {
+        yield_now();
+    }
+    let threads = with_table(|table| table.threads.len());
+    // just the boot, idle and reaper threads
+    assert_eq!(threads, 3);
+}
+
+#[test_case]
+fn test_exited_threads_give_up_their_address_space() {
+    let space = Arc::new(AddressSpace::new().unwrap());
+    let handle = spawn_in(space.clone(), || {}).unwrap();
+    // freed without waiting for the thread to be joined
+    for _ in 0..10 {
+        yield_now();
+    }
+    assert!(handle.is_finished());
+    assert_eq!(Arc::strong_count(&space), 1);
+    handle.join().unwrap();
+}
+
+#[test_case]
+fn test_sleep() {
+    let start = time::ticks();
+    sleep_ms(30);
+    assert!(time::ticks() >= start + ms_to_ticks(30));
+    // doesn't overflow, however long
+    assert!(ms_to_ticks(u64::MAX) > ms_to_ticks(u64::MAX / 1000));
+}
+
+#[test_case]
+fn test_high_priority_preempts_busy_threads() {
+    use core::sync::atomic::AtomicBool;
+
+    static DONE: AtomicBool = AtomicBool::new(false);
+    DONE.store(false, Ordering::SeqCst);
+    let busy = spawn_with_priority(Priority::Low, || {
+        while !DONE.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    })
+    .unwrap();
+    let responsive = spawn_with_priority(Priority::High, || {
+        let mut latest = 0;
+        for _ in 0..5 {
+            let deadline = time::ticks() + 2;
+            sleep_until(deadline);
+            latest = latest.max(time::ticks() - deadline);
+        }
+        DONE.store(true, Ordering::SeqCst);
+        latest
+    })
+    .unwrap();
+    // woken right on the tick of its deadline, even though the busy thread never yields
+    assert!(responsive.join().unwrap() <= 1);
+    let stats = stats(busy.id()).unwrap();
+    assert!(stats.cpu_ticks > 0);
+    assert!(stats.level >= Priority::Low.level());
+    busy.join().unwrap();
+}
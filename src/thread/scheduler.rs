@@ -0,0 +1,98 @@
+// Decides which thread runs next, with a multilevel feedback queue.
+//
+// There is a ready queue per level, and the highest level with a ready thread always goes
+// first. A thread starts on the level of its priority. Using up its time slice moves it one
+// level down, where the slices are twice as long, so threads that compute a lot sink and
+// threads that mostly wait for input stay on top and respond quickly. Blocking or yielding
+// keeps the level, and what is left of the slice. Every `BOOST_INTERVAL_TICKS` all threads
+// go back to the level of their priority, so the sunken ones can't starve.
+
+use super::ThreadId;
+use alloc::collections::VecDeque;
+
+pub const LEVELS: usize = 4;
+
+/// How often the levels are reset, in timer ticks.
+pub const BOOST_INTERVAL_TICKS: u64 = 100;
+
+/// How much a thread runs on the given level before it is moved down, in timer ticks.
+pub fn time_slice(level: usize) -> u32 {
+    1 << level
+}
+
+/// How urgently a thread wants the CPU.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Work the user is waiting for, like input handling.
+    High,
+    #[default]
+    Normal,
+    /// Background work.
+    Low,
+}
+
+impl Priority {
+    /// The level threads of this priority start on.
+    pub fn level(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+pub(super) struct Scheduler {
+    ready: [VecDeque<ThreadId>; LEVELS],
+}
+
+impl Scheduler {
+    pub(super) const fn new() -> Scheduler {
+        Scheduler {
+            ready: [const { VecDeque::new() }; LEVELS],
+        }
+    }
+
+    /// Queues a thread that is ready to run on the given level.
+    pub(super) fn add(&mut self, id: ThreadId, level: usize) {
+        self.ready[level].push_back(id);
+    }
+
+    /// Takes the thread to run next out of the queues.
+    pub(super) fn next(&mut self) -> Option<ThreadId> {
+        self.ready.iter_mut().find_map(|queue| queue.pop_front())
+    }
+
+    /// The highest level with a ready thread.
+    pub(super) fn highest_level(&self) -> Option<usize> {
+        self.ready.iter().position(|queue| !queue.is_empty())
+    }
+
+    /// Takes all threads out of the queues, highest level first.
+    pub(super) fn take_all(&mut self) -> impl Iterator<Item = ThreadId> + '_ {
+        self.ready.iter_mut().flat_map(|queue| queue.drain(..))
+    }
+}
+
+#[test_case]
+fn test_higher_levels_go_first() {
+    let mut scheduler = Scheduler::new();
+    scheduler.add(ThreadId(100), 2);
+    scheduler.add(ThreadId(101), 1);
+    scheduler.add(ThreadId(102), 1);
+    assert_eq!(scheduler.highest_level(), Some(1));
+    assert_eq!(scheduler.next(), Some(ThreadId(101)));
+    assert_eq!(scheduler.next(), Some(ThreadId(102)));
+    assert_eq!(scheduler.next(), Some(ThreadId(100)));
+    assert_eq!(scheduler.next(), None);
+    assert_eq!(scheduler.highest_level(), None);
+}
+
+#[test_case]
+fn test_slices_grow_with_the_level() {
+    for level in 1..LEVELS {
+        assert!(time_slice(level) > time_slice(level - 1));
+    }
+    assert!(Priority::High.level() < Priority::Low.level());
+    assert!(Priority::Low.level() < LEVELS);
+}
@@ -0,0 +1,104 @@
+// Kernel stacks for threads. Each one gets a slot in its own area of the address space with
+// an unmapped guard page below it, so an overflow page faults instead of silently
+// overwriting whatever lies below.
+
+use crate::memory::frame::frame_allocator;
+use crate::memory::paging::{kernel_page_tables, MapError};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Where the stack area starts, far away from the heap.
+pub const STACK_AREA_START: u64 = 0x5555_0000_0000;
+/// The usable size of a kernel stack.
+pub const STACK_SIZE: u64 = 64 * 1024;
+const PAGE_SIZE: u64 = 4096;
+// The guard page comes first in every slot
+const SLOT_SIZE: u64 = STACK_SIZE + PAGE_SIZE;
+
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(0);
+static FREE_SLOTS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+/// A mapped kernel stack, unmapped again when dropped.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: u64,
+    // the pages are mapped from the bottom, only all of them once `new` has succeeded
+    mapped: u64,
+}
+
+impl KernelStack {
+    pub fn new() -> Result<KernelStack, MapError> {
+        let slot = FREE_SLOTS
+            .lock()
+            .pop()
+            .unwrap_or_else(|| NEXT_SLOT.fetch_add(1, Ordering::Relaxed));
+        let mut stack = KernelStack { slot, mapped: 0 };
+
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let mut tables = kernel_page_tables();
+        let mut frames = frame_allocator();
+        while stack.mapped < STACK_SIZE / PAGE_SIZE {
+            let addr = stack.bottom().as_u64() + stack.mapped * PAGE_SIZE;
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+            let frame = frames.allocate().ok_or(MapError::FrameAllocationFailed)?;
+            // the slot belongs to this stack alone, and the frame is fresh
+            if let Err(error) = unsafe { tables.map(page, frame, flags, &mut *frames) } {
+                unsafe { frames.free(frame) };
+                return Err(error);
+            }
+            stack.mapped += 1;
+        }
+        Ok(stack)
+    }
+
+    /// The lowest usable address.
+    pub fn bottom(&self) -> VirtAddr {
+        VirtAddr::new(STACK_AREA_START + self.slot * SLOT_SIZE + PAGE_SIZE)
+    }
+
+    /// The address one past the end, where the stack pointer starts.
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + STACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        {
+            let mut tables = kernel_page_tables();
+            let mut frames = frame_allocator();
+            for i in 0..self.mapped {
+                let addr = self.bottom().as_u64() + i * PAGE_SIZE;
+                let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+                // nothing runs on the stack anymore, and the frames were ours
+                unsafe {
+                    let frame = tables.unmap(page).expect("kernel stack page not mapped");
+                    frames.free(frame);
+                }
+            }
+        }
+        FREE_SLOTS.lock().push(self.slot);
+    }
+}
+
+#[test_case]
+fn test_stack_is_usable_and_reused() {
+    let stack = KernelStack::new().unwrap();
+    let top = stack.top();
+    let words = stack.bottom().as_mut_ptr::<u64>();
+    // the whole stack is mapped and writable
+    unsafe {
+        for i in 0..(STACK_SIZE / 8) as usize {
+            words.add(i).write_volatile(i as u64);
+        }
+    }
+    assert!(kernel_page_tables()
+        .translate(stack.bottom() - PAGE_SIZE)
+        .is_none());
+    drop(stack);
+    assert!(kernel_page_tables().translate(top - 8u64).is_none());
+    assert_eq!(KernelStack::new().unwrap().top(), top);
+}
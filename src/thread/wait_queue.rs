@@ -0,0 +1,91 @@
+// Threads waiting for an event. A thread that waits is blocked until another thread or an
+// interrupt handler notifies the queue, instead of spinning on the CPU.
+
+use super::{block, current, wake, ThreadId};
+use alloc::collections::VecDeque;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub struct WaitQueue {
+    // only locked with interrupts disabled, interrupt handlers may notify
+    waiters: Mutex<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the calling thread until the queue is notified.
+    pub fn wait(&self) {
+        interrupts::without_interrupts(|| {
+            self.waiters.lock().push_back(current());
+            block();
+        });
+    }
+
+    /// Blocks the calling thread until `condition` returns true. The condition is checked
+    /// with interrupts disabled, so whatever makes it true can't slip in between the check
+    /// and the thread going to sleep, as long as it notifies the queue afterwards.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        interrupts::without_interrupts(|| {
+            while !condition() {
+                self.waiters.lock().push_back(current());
+                block();
+            }
+        });
+    }
+
+    /// Wakes the thread that has been waiting the longest. Returns whether there was one.
+    pub fn notify_one(&self) -> bool {
+        let waiter = interrupts::without_interrupts(|| self.waiters.lock().pop_front());
+        match waiter {
+            Some(id) => {
+                wake(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes all waiting threads. Returns how many there were.
+    pub fn notify_all(&self) -> usize {
+        let waiters = interrupts::without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        for &id in &waiters {
+            wake(id);
+        }
+        waiters.len()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> WaitQueue {
+        WaitQueue::new()
+    }
+}
+
+#[test_case]
+fn test_wait_until_notified() {
+    use super::spawn;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    let queue = Arc::new(WaitQueue::new());
+    let ready = Arc::new(AtomicBool::new(false));
+    let waiter = {
+        let (queue, ready) = (queue.clone(), ready.clone());
+        spawn(move || queue.wait_until(|| ready.load(Ordering::SeqCst))).unwrap()
+    };
+    // let the waiter block
+    super::sleep_ms(20);
+    assert_eq!(
+        super::stats(waiter.id()).unwrap().state,
+        super::ThreadState::Blocked
+    );
+    ready.store(true, Ordering::SeqCst);
+    assert_eq!(queue.notify_all(), 1);
+    waiter.join().unwrap();
+    assert!(!queue.notify_one());
+}
@@ -0,0 +1,31 @@
+// Kernel time keeping, based on the periodic timer interrupt. Whichever timer drives it
+// (the PIT or the local APIC timer), it fires `TIMER_FREQUENCY_HZ` times per second.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// How often the timer interrupt fires, in Hz.
+pub const TIMER_FREQUENCY_HZ: u32 = 100;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Called from the timer interrupt handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the timer was started.
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / u64::from(TIMER_FREQUENCY_HZ)
+}
+
+/// Converts milliseconds to timer ticks, rounding up so that a wait is never too short.
+/// Durations too long to count in ticks come out as practically forever.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    ms.saturating_mul(u64::from(TIMER_FREQUENCY_HZ))
+        .div_ceil(1000)
+}
@@ -0,0 +1,866 @@
+// Loading ELF64 executables into a user address space.
+//
+// Only what a static program needs is supported: the file header, the program headers, and
+// for position independent executables the relative relocations in the dynamic section.
+// Programs that want a dynamic linker are turned away. Everything is decoded from the file's
+// bytes with bounds and overflow checks, so a broken file ends in an `ElfError` rather than
+// a panic.
+//
+// The stack is set up the way the System V ABI describes it for process entry: the argument
+// count at the stack pointer, followed by the argument and environment pointers, each list
+// ending in a null pointer, then the auxiliary vector. The strings are above all that.
+
+use crate::memory::address_space::{AddressSpace, UserMemoryError, USER_END, USER_START};
+use alloc::vec::Vec;
+use core::arch::x86_64::_rdtsc;
+use core::convert::{TryFrom, TryInto};
+use core::fmt;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u32 = 1;
+const TYPE_EXEC: u16 = 2;
+const TYPE_DYN: u16 = 3;
+const MACHINE_X86_64: u16 = 62;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const MAX_PROGRAM_HEADERS: usize = 64;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_PLTRELSZ: u64 = 2;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_REL: u64 = 17;
+const DT_JMPREL: u64 = 23;
+const DT_RELRSZ: u64 = 35;
+const DT_RELR: u64 = 36;
+const DT_RELRENT: u64 = 37;
+const DYNAMIC_ENTRY_SIZE: u64 = 16;
+const RELA_SIZE: u64 = 24;
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Where position independent executables are loaded.
+pub const PIE_BASE: u64 = USER_START + 0x40_0000;
+/// The user stack ends where the user area does.
+pub const STACK_TOP: u64 = USER_END;
+pub const STACK_SIZE: u64 = 128 * 1024;
+/// How much of the stack the arguments and environment may take up, strings and pointers
+/// together.
+pub const ARGUMENTS_MAX: usize = 32 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file ends before a structure it describes.
+    Truncated,
+    NotElf,
+    UnsupportedClass(u8),
+    UnsupportedByteOrder(u8),
+    UnsupportedVersion(u32),
+    UnsupportedType(u16),
+    UnsupportedMachine(u16),
+    /// The program header table has entries of the wrong size, or too many of them.
+    BadProgramHeaders,
+    /// The program wants a dynamic linker.
+    NeedsInterpreter,
+    NoLoadableSegments,
+    /// A segment is larger in the file than in memory.
+    BadSegmentSize,
+    /// A segment's address and file offset disagree on the alignment it asks for.
+    MisalignedSegment,
+    /// A segment doesn't fit into the user area below the stack.
+    SegmentOutOfRange,
+    /// Two segments share a page.
+    OverlappingSegments,
+    /// The entry point isn't in an executable segment.
+    EntryNotExecutable(u64),
+    BadDynamicSection,
+    UnsupportedRelocation(u32),
+    /// A relocation would write outside the loaded segments.
+    RelocationOutOfRange(u64),
+    ArgumentsTooLarge,
+    Memory(UserMemoryError),
+}
+
+impl From<UserMemoryError> for ElfError {
+    fn from(error: UserMemoryError) -> ElfError {
+        ElfError::Memory(error)
+    }
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "file is truncated"),
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::UnsupportedClass(class) => write!(f, "ELF class {} is not 64 bit", class),
+            ElfError::UnsupportedByteOrder(order) => {
+                write!(f, "byte order {} is not little endian", order)
+            }
+            ElfError::UnsupportedVersion(version) => write!(f, "unknown ELF version {}", version),
+            ElfError::UnsupportedType(kind) => write!(f, "file type {} is not executable", kind),
+            ElfError::UnsupportedMachine(machine) => {
+                write!(f, "machine {} is not x86_64", machine)
+            }
+            ElfError::BadProgramHeaders => write!(f, "invalid program header table"),
+            ElfError::NeedsInterpreter => write!(f, "dynamically linked programs are unsupported"),
+            ElfError::NoLoadableSegments => write!(f, "no loadable segments"),
+            ElfError::BadSegmentSize => write!(f, "segment is larger in the file than in memory"),
+            ElfError::MisalignedSegment => write!(f, "segment address and offset misaligned"),
+            ElfError::SegmentOutOfRange => write!(f, "segment outside the user area"),
+            ElfError::OverlappingSegments => write!(f, "segments overlap"),
+            ElfError::EntryNotExecutable(entry) => {
+                write!(
+                    f,
+                    "entry point {:#x} is not in an executable segment",
+                    entry
+                )
+            }
+            ElfError::BadDynamicSection => write!(f, "invalid dynamic section"),
+            ElfError::UnsupportedRelocation(kind) => {
+                write!(f, "unsupported relocation type {}", kind)
+            }
+            ElfError::RelocationOutOfRange(addr) => {
+                write!(f, "relocation at {:#x} outside the loaded segments", addr)
+            }
+            ElfError::ArgumentsTooLarge => write!(f, "arguments and environment too large"),
+            ElfError::Memory(error) => write!(f, "failed to set up memory: {:?}", error),
+        }
+    }
+}
+
+fn bytes_at<const N: usize>(data: &[u8], offset: u64) -> Result<[u8; N], ElfError> {
+    let start = usize::try_from(offset).map_err(|_| ElfError::Truncated)?;
+    let end = start.checked_add(N).ok_or(ElfError::Truncated)?;
+    let bytes = data.get(start..end).ok_or(ElfError::Truncated)?;
+    Ok(bytes.try_into().unwrap())
+}
+
+fn u16_at(data: &[u8], offset: u64) -> Result<u16, ElfError> {
+    bytes_at(data, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(data: &[u8], offset: u64) -> Result<u32, ElfError> {
+    bytes_at(data, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(data: &[u8], offset: u64) -> Result<u64, ElfError> {
+    bytes_at(data, offset).map(u64::from_le_bytes)
+}
+
+/// One entry of the program header table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    fn read(data: &[u8], offset: u64) -> Result<ProgramHeader, ElfError> {
+        let entry: [u8; PROGRAM_HEADER_SIZE] = bytes_at(data, offset)?;
+        Ok(ProgramHeader {
+            kind: u32_at(&entry, 0)?,
+            flags: u32_at(&entry, 4)?,
+            offset: u64_at(&entry, 8)?,
+            vaddr: u64_at(&entry, 16)?,
+            file_size: u64_at(&entry, 32)?,
+            memory_size: u64_at(&entry, 40)?,
+            align: u64_at(&entry, 48)?,
+        })
+    }
+
+    // The part of the file the segment is loaded from
+    fn file_bytes<'a>(&self, data: &'a [u8]) -> Result<&'a [u8], ElfError> {
+        let start = usize::try_from(self.offset).map_err(|_| ElfError::Truncated)?;
+        let size = usize::try_from(self.file_size).map_err(|_| ElfError::Truncated)?;
+        let end = start.checked_add(size).ok_or(ElfError::Truncated)?;
+        data.get(start..end).ok_or(ElfError::Truncated)
+    }
+
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// A parsed ELF64 executable. Only the headers are looked at until it is loaded.
+#[derive(Debug)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    kind: u16,
+    entry: u64,
+    program_header_offset: u64,
+    program_headers: Vec<ProgramHeader>,
+}
+
+impl<'a> Elf<'a> {
+    /// Checks that `data` is an x86_64 executable and reads its program headers.
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        let ident: [u8; 16] = bytes_at(data, 0)?;
+        if &ident[..4] != MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if ident[4] != CLASS_64 {
+            return Err(ElfError::UnsupportedClass(ident[4]));
+        }
+        if ident[5] != LITTLE_ENDIAN {
+            return Err(ElfError::UnsupportedByteOrder(ident[5]));
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        let version = u32_at(data, 20)?;
+        if u32::from(ident[6]) != VERSION_CURRENT || version != VERSION_CURRENT {
+            return Err(ElfError::UnsupportedVersion(version));
+        }
+        let kind = u16_at(data, 16)?;
+        if kind != TYPE_EXEC && kind != TYPE_DYN {
+            return Err(ElfError::UnsupportedType(kind));
+        }
+        let machine = u16_at(data, 18)?;
+        if machine != MACHINE_X86_64 {
+            return Err(ElfError::UnsupportedMachine(machine));
+        }
+
+        let entry = u64_at(data, 24)?;
+        let program_header_offset = u64_at(data, 32)?;
+        let entry_size = usize::from(u16_at(data, 54)?);
+        let count = usize::from(u16_at(data, 56)?);
+        if entry_size != PROGRAM_HEADER_SIZE || count > MAX_PROGRAM_HEADERS {
+            return Err(ElfError::BadProgramHeaders);
+        }
+        let program_headers = (0..count)
+            .map(|i| {
+                let offset = program_header_offset
+                    .checked_add((i * PROGRAM_HEADER_SIZE) as u64)
+                    .ok_or(ElfError::Truncated)?;
+                ProgramHeader::read(data, offset)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for header in program_headers
+            .iter()
+            .filter(|header| header.kind == PT_LOAD)
+        {
+            if header.file_size > header.memory_size {
+                return Err(ElfError::BadSegmentSize);
+            }
+            header.file_bytes(data)?;
+            let align = header.align.max(1);
+            if !align.is_power_of_two() || header.vaddr % align != header.offset % align {
+                return Err(ElfError::MisalignedSegment);
+            }
+        }
+
+        Ok(Elf {
+            data,
+            kind,
+            entry,
+            program_header_offset,
+            program_headers,
+        })
+    }
+
+    /// Whether the program can be loaded at any address, which means it needs relocating.
+    pub fn is_position_independent(&self) -> bool {
+        self.kind == TYPE_DYN
+    }
+
+    /// The entry point, relative to the load address for position independent programs.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> &[ProgramHeader] {
+        &self.program_headers
+    }
+
+    fn segments(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.program_headers
+            .iter()
+            .filter(|header| header.kind == PT_LOAD)
+    }
+
+    // Where the program headers end up in memory, for the auxiliary vector
+    fn program_headers_addr(&self, base: u64) -> Option<u64> {
+        if let Some(header) = self.program_headers.iter().find(|h| h.kind == PT_PHDR) {
+            return base.checked_add(header.vaddr);
+        }
+        let offset = self.program_header_offset;
+        self.segments()
+            .find(|header| offset >= header.offset && offset - header.offset < header.file_size)
+            .and_then(|header| {
+                base.checked_add(header.vaddr)?
+                    .checked_add(offset - header.offset)
+            })
+    }
+}
+
+/// Where a loaded program starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadedProgram {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+/// Loads the executable in `data` into `space` and sets up its stack with `args` and `env`.
+/// `space` must not have anything mapped yet. If loading fails, whatever was mapped until
+/// then stays, and `space` is best dropped.
+pub fn load(
+    space: &AddressSpace,
+    data: &[u8],
+    args: &[&str],
+    env: &[&str],
+) -> Result<LoadedProgram, ElfError> {
+    let elf = Elf::parse(data)?;
+    if elf.program_headers.iter().any(|h| h.kind == PT_INTERP) {
+        return Err(ElfError::NeedsInterpreter);
+    }
+    let base = if elf.is_position_independent() {
+        PIE_BASE
+    } else {
+        0
+    };
+
+    // the page ranges of all segments, which must be in the user area and apart
+    let mut ranges = Vec::new();
+    for header in elf.segments() {
+        let start = base
+            .checked_add(header.vaddr)
+            .ok_or(ElfError::SegmentOutOfRange)?;
+        let end = start
+            .checked_add(header.memory_size)
+            .ok_or(ElfError::SegmentOutOfRange)?;
+        if start < USER_START || end > STACK_TOP - STACK_SIZE {
+            return Err(ElfError::SegmentOutOfRange);
+        }
+        ranges.push((start & !(PAGE_SIZE - 1), end.next_multiple_of(PAGE_SIZE)));
+    }
+    if ranges.is_empty() {
+        return Err(ElfError::NoLoadableSegments);
+    }
+    ranges.sort_unstable();
+    if ranges.windows(2).any(|pair| pair[0].1 > pair[1].0) {
+        return Err(ElfError::OverlappingSegments);
+    }
+
+    // Fresh pages are zeroed, which takes care of the part of each segment that isn't in
+    // the file
+    for header in elf.segments() {
+        let start = VirtAddr::new(base + header.vaddr);
+        space.map(start, header.memory_size, header.page_flags())?;
+        space.load(start, header.file_bytes(data)?)?;
+    }
+    if elf.is_position_independent() {
+        relocate(space, &elf, base)?;
+    }
+
+    let entry = base
+        .checked_add(elf.entry)
+        .ok_or(ElfError::EntryNotExecutable(elf.entry))?;
+    let executable = elf.segments().any(|header| {
+        let start = base + header.vaddr;
+        header.flags & PF_X != 0 && entry >= start && entry - start < header.memory_size
+    });
+    if !executable {
+        return Err(ElfError::EntryNotExecutable(entry));
+    }
+
+    let stack_pointer = set_up_stack(space, &elf, base, entry, args, env)?;
+    Ok(LoadedProgram {
+        entry: VirtAddr::new(entry),
+        stack_pointer,
+    })
+}
+
+// Applies the relocations listed in the dynamic section. A static position independent
+// executable only has relative ones, which add the load address.
+fn relocate(space: &AddressSpace, elf: &Elf, base: u64) -> Result<(), ElfError> {
+    let Some(dynamic) = elf.program_headers.iter().find(|h| h.kind == PT_DYNAMIC) else {
+        return Ok(());
+    };
+    let mut tables = [(None, 0), (None, 0)];
+    let mut entry_size = RELA_SIZE;
+    for i in 0..dynamic.file_size / DYNAMIC_ENTRY_SIZE {
+        let offset = dynamic
+            .offset
+            .checked_add(i * DYNAMIC_ENTRY_SIZE)
+            .ok_or(ElfError::Truncated)?;
+        let entry: [u8; DYNAMIC_ENTRY_SIZE as usize] = bytes_at(elf.data, offset)?;
+        let tag = u64_at(&entry, 0)?;
+        let value = u64_at(&entry, 8)?;
+        match tag {
+            DT_NULL => break,
+            DT_RELA => tables[0].0 = Some(value),
+            DT_RELASZ => tables[0].1 = value,
+            DT_RELAENT => entry_size = value,
+            DT_JMPREL => tables[1].0 = Some(value),
+            DT_PLTRELSZ => tables[1].1 = value,
+            // x86_64 only uses relocations with explicit addends
+            DT_REL => return Err(ElfError::BadDynamicSection),
+            // Packed relative relocations aren't applied. Loading the program anyway would
+            // leave its pointers unrelocated.
+            DT_RELR | DT_RELRSZ | DT_RELRENT => return Err(ElfError::BadDynamicSection),
+            DT_NEEDED => return Err(ElfError::NeedsInterpreter),
+            // the rest is for the dynamic linker, or doesn't matter for RELATIVE relocations
+            _ => {}
+        }
+    }
+    if entry_size != RELA_SIZE {
+        return Err(ElfError::BadDynamicSection);
+    }
+
+    for (table, size) in tables {
+        let Some(table) = table else { continue };
+        let table = base.checked_add(table).ok_or(ElfError::BadDynamicSection)?;
+        for i in 0..size / RELA_SIZE {
+            let mut entry = [0; RELA_SIZE as usize];
+            let addr = table
+                .checked_add(i * RELA_SIZE)
+                .and_then(|addr| VirtAddr::try_new(addr).ok())
+                .ok_or(ElfError::BadDynamicSection)?;
+            space
+                .read(addr, &mut entry)
+                .map_err(|_| ElfError::BadDynamicSection)?;
+            let offset = u64_at(&entry, 0)?;
+            let kind = u64_at(&entry, 8)? as u32;
+            let addend = u64_at(&entry, 16)?;
+            match kind {
+                R_X86_64_NONE => {}
+                R_X86_64_RELATIVE => {
+                    let target = base.wrapping_add(offset);
+                    let value = base.wrapping_add(addend);
+                    VirtAddr::try_new(target)
+                        .ok()
+                        .and_then(|target| space.load(target, &value.to_le_bytes()).ok())
+                        .ok_or(ElfError::RelocationOutOfRange(target))?;
+                }
+                kind => return Err(ElfError::UnsupportedRelocation(kind)),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Maps the stack and fills in the arguments, environment and auxiliary vector. Returns the
+// stack pointer, which points at the argument count and is aligned to 16 bytes.
+fn set_up_stack(
+    space: &AddressSpace,
+    elf: &Elf,
+    base: u64,
+    entry: u64,
+    args: &[&str],
+    env: &[&str],
+) -> Result<VirtAddr, ElfError> {
+    let strings_size: usize = args.iter().chain(env).map(|s| s.len() + 1).sum();
+    let pointers_size = (args.len() + env.len() + 2) * 8;
+    if strings_size + pointers_size > ARGUMENTS_MAX {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    space.map(VirtAddr::new(STACK_TOP - STACK_SIZE), STACK_SIZE, flags)?;
+
+    // The strings, followed by the bytes for AT_RANDOM. There is no entropy source yet, so
+    // those are only as random as the time stamp counter.
+    let random_size = 16;
+    let strings_start = (STACK_TOP - (strings_size + random_size) as u64) & !15;
+    let mut strings = Vec::with_capacity(strings_size + random_size);
+    let pointers = |list: &[&str], strings: &mut Vec<u8>| -> Vec<u64> {
+        list.iter()
+            .map(|s| {
+                let addr = strings_start + strings.len() as u64;
+                strings.extend_from_slice(s.as_bytes());
+                strings.push(0);
+                addr
+            })
+            .collect()
+    };
+    let arg_pointers = pointers(args, &mut strings);
+    let env_pointers = pointers(env, &mut strings);
+    let random_addr = strings_start + strings.len() as u64;
+    let seed = unsafe { _rdtsc() };
+    strings.extend_from_slice(&seed.to_le_bytes());
+    strings.extend_from_slice(
+        &seed
+            .rotate_left(32)
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            .to_le_bytes(),
+    );
+
+    let mut words = Vec::new();
+    words.push(args.len() as u64);
+    words.extend_from_slice(&arg_pointers);
+    words.push(0);
+    words.extend_from_slice(&env_pointers);
+    words.push(0);
+    if let Some(addr) = elf.program_headers_addr(base) {
+        words.extend_from_slice(&[AT_PHDR, addr]);
+    }
+    words.extend_from_slice(&[
+        AT_PHENT,
+        PROGRAM_HEADER_SIZE as u64,
+        AT_PHNUM,
+        elf.program_headers.len() as u64,
+        AT_PAGESZ,
+        PAGE_SIZE,
+        AT_ENTRY,
+        entry,
+        AT_RANDOM,
+        random_addr,
+        AT_NULL,
+        0,
+    ]);
+    let stack_pointer = (strings_start - words.len() as u64 * 8) & !15;
+    let words: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    space.write(VirtAddr::new(strings_start), &strings)?;
+    space.write(VirtAddr::new(stack_pointer), &words)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::user::syscall::SYS_EXIT;
+    use alloc::sync::Arc;
+    use alloc::vec;
+
+    const PF_R: u32 = 4;
+
+    struct Segment<'a> {
+        kind: u32,
+        flags: u32,
+        vaddr: u64,
+        bytes: &'a [u8],
+        memory_size: u64,
+    }
+
+    impl Segment<'_> {
+        fn load(flags: u32, vaddr: u64, bytes: &[u8]) -> Segment<'_> {
+            Segment {
+                kind: PT_LOAD,
+                flags,
+                vaddr,
+                bytes,
+                memory_size: bytes.len() as u64,
+            }
+        }
+    }
+
+    fn put(data: &mut [u8], offset: usize, bytes: &[u8]) {
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    // The headers, then the bytes of each segment at an offset that agrees with its address
+    // on the alignment within a page
+    fn build(kind: u16, entry: u64, segments: &[Segment]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE + segments.len() * PROGRAM_HEADER_SIZE];
+        put(&mut data, 0, MAGIC);
+        put(&mut data, 4, &[CLASS_64, LITTLE_ENDIAN, 1]);
+        put(&mut data, 16, &kind.to_le_bytes());
+        put(&mut data, 18, &MACHINE_X86_64.to_le_bytes());
+        put(&mut data, 20, &VERSION_CURRENT.to_le_bytes());
+        put(&mut data, 24, &entry.to_le_bytes());
+        put(&mut data, 32, &(HEADER_SIZE as u64).to_le_bytes());
+        put(&mut data, 52, &(HEADER_SIZE as u16).to_le_bytes());
+        put(&mut data, 54, &(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        put(&mut data, 56, &(segments.len() as u16).to_le_bytes());
+        for (i, segment) in segments.iter().enumerate() {
+            let offset = data.len().next_multiple_of(PAGE_SIZE as usize)
+                + (segment.vaddr % PAGE_SIZE) as usize;
+            data.resize(offset, 0);
+            data.extend_from_slice(segment.bytes);
+            let header = HEADER_SIZE + i * PROGRAM_HEADER_SIZE;
+            put(&mut data, header, &segment.kind.to_le_bytes());
+            put(&mut data, header + 4, &segment.flags.to_le_bytes());
+            put(&mut data, header + 8, &(offset as u64).to_le_bytes());
+            put(&mut data, header + 16, &segment.vaddr.to_le_bytes());
+            put(&mut data, header + 24, &segment.vaddr.to_le_bytes());
+            put(
+                &mut data,
+                header + 32,
+                &(segment.bytes.len() as u64).to_le_bytes(),
+            );
+            put(&mut data, header + 40, &segment.memory_size.to_le_bytes());
+            put(&mut data, header + 48, &PAGE_SIZE.to_le_bytes());
+        }
+        data
+    }
+
+    /// An executable with `code` at `USER_START`, where it starts, and `data` writable in
+    /// the page after it.
+    pub(crate) fn executable(code: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut segments = vec![Segment::load(PF_R | PF_X, USER_START, code)];
+        if !data.is_empty() {
+            segments.push(Segment::load(PF_R | PF_W, USER_START + PAGE_SIZE, data));
+        }
+        build(TYPE_EXEC, USER_START, &segments)
+    }
+
+    const UD2: &[u8] = &[0x0f, 0x0b];
+
+    fn simple() -> Vec<u8> {
+        let text = Segment::load(PF_R | PF_X, USER_START, UD2);
+        build(TYPE_EXEC, USER_START, &[text])
+    }
+
+    fn load_new(data: &[u8]) -> Result<(AddressSpace, LoadedProgram), ElfError> {
+        let space = AddressSpace::new().unwrap();
+        let program = load(&space, data, &["test"], &[])?;
+        Ok((space, program))
+    }
+
+    fn read_u64(space: &AddressSpace, addr: u64) -> u64 {
+        let mut buf = [0; 8];
+        space.read(VirtAddr::new(addr), &mut buf).unwrap();
+        u64::from_le_bytes(buf)
+    }
+
+    fn read_string(space: &AddressSpace, addr: u64) -> Vec<u8> {
+        let mut string = Vec::new();
+        let mut byte = [0];
+        for i in 0.. {
+            space.read(VirtAddr::new(addr + i), &mut byte).unwrap();
+            if byte[0] == 0 {
+                return string;
+            }
+            string.push(byte[0]);
+        }
+        unreachable!()
+    }
+
+    #[test_case]
+    fn test_rejects_malformed_headers() {
+        let valid = simple();
+        assert!(Elf::parse(&valid).is_ok());
+        let broken = |offset: usize, bytes: &[u8]| {
+            let mut data = valid.clone();
+            put(&mut data, offset, bytes);
+            Elf::parse(&data).unwrap_err()
+        };
+        assert_eq!(broken(0, b"\x7fELG"), ElfError::NotElf);
+        assert_eq!(broken(4, &[1]), ElfError::UnsupportedClass(1));
+        assert_eq!(broken(5, &[2]), ElfError::UnsupportedByteOrder(2));
+        assert_eq!(broken(20, &[2]), ElfError::UnsupportedVersion(2));
+        assert_eq!(broken(16, &[1, 0]), ElfError::UnsupportedType(1));
+        assert_eq!(broken(18, &[3, 0]), ElfError::UnsupportedMachine(3));
+        assert_eq!(broken(54, &[32, 0]), ElfError::BadProgramHeaders);
+        // program header table past the end of the file
+        assert_eq!(broken(32, &[0xff; 8]), ElfError::Truncated);
+        // file size larger than memory size
+        assert_eq!(broken(HEADER_SIZE + 40, &[1]), ElfError::BadSegmentSize);
+        // address and offset disagree within the page
+        assert_eq!(broken(HEADER_SIZE + 16, &[1]), ElfError::MisalignedSegment);
+        assert_eq!(Elf::parse(&valid[..10]).unwrap_err(), ElfError::Truncated);
+        assert_eq!(
+            Elf::parse(&valid[..valid.len() - 1]).unwrap_err(),
+            ElfError::Truncated
+        );
+    }
+
+    #[test_case]
+    fn test_rejects_unloadable_programs() {
+        let text = |vaddr| Segment::load(PF_R | PF_X, vaddr, UD2);
+        let error = |data: Vec<u8>| load_new(&data).unwrap_err();
+
+        let low = build(TYPE_EXEC, 0x40_0000, &[text(0x40_0000)]);
+        assert_eq!(error(low), ElfError::SegmentOutOfRange);
+        let missed = build(TYPE_EXEC, USER_START + 0x100, &[text(USER_START)]);
+        assert_eq!(
+            error(missed),
+            ElfError::EntryNotExecutable(USER_START + 0x100)
+        );
+        let overlapping = build(
+            TYPE_EXEC,
+            USER_START,
+            &[text(USER_START), text(USER_START + 0x10)],
+        );
+        assert_eq!(error(overlapping), ElfError::OverlappingSegments);
+        let interp = Segment {
+            kind: PT_INTERP,
+            ..text(0)
+        };
+        let dynamic = build(TYPE_EXEC, USER_START, &[text(USER_START), interp]);
+        assert_eq!(error(dynamic), ElfError::NeedsInterpreter);
+        assert_eq!(
+            error(build(TYPE_EXEC, USER_START, &[])),
+            ElfError::NoLoadableSegments
+        );
+
+        let space = AddressSpace::new().unwrap();
+        let huge = "x".repeat(ARGUMENTS_MAX);
+        assert_eq!(
+            load(&space, &simple(), &[&huge], &[]),
+            Err(ElfError::ArgumentsTooLarge)
+        );
+    }
+
+    #[test_case]
+    fn test_segment_permissions() {
+        let text = Segment::load(PF_R | PF_X, USER_START, UD2);
+        let data = Segment {
+            memory_size: 0x2000,
+            ..Segment::load(PF_R | PF_W, USER_START + 0x1000, &[1, 2, 3])
+        };
+        let (space, _) = load_new(&build(TYPE_EXEC, USER_START, &[text, data])).unwrap();
+
+        let text = VirtAddr::new(USER_START);
+        assert!(space.is_mapped(text, PageTableFlags::USER_ACCESSIBLE));
+        assert!(!space.is_mapped(text, PageTableFlags::WRITABLE));
+        assert!(!space.is_mapped(text, PageTableFlags::NO_EXECUTE));
+        let data = VirtAddr::new(USER_START + 0x1000);
+        assert!(space.is_mapped(
+            data + 0x1fffu64,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+        ));
+        let mut buf = [0xff; 4];
+        space.read(data, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 0]);
+    }
+
+    #[test_case]
+    fn test_position_independent_relocation() {
+        // a pointer to fill in, followed by the relocation for it
+        let mut data = vec![0; 8];
+        data.extend_from_slice(&0x1000u64.to_le_bytes());
+        data.extend_from_slice(&u64::from(R_X86_64_RELATIVE).to_le_bytes());
+        data.extend_from_slice(&0x1234u64.to_le_bytes());
+        let mut dynamic = Vec::new();
+        for (tag, value) in [
+            (DT_RELA, 0x1008),
+            (DT_RELASZ, RELA_SIZE),
+            (DT_RELAENT, RELA_SIZE),
+            (DT_NULL, 0),
+        ] {
+            dynamic.extend_from_slice(&tag.to_le_bytes());
+            dynamic.extend_from_slice(&u64::to_le_bytes(value));
+        }
+        let segments = |data: &[u8]| {
+            build(
+                TYPE_DYN,
+                0,
+                &[
+                    Segment::load(PF_R | PF_X, 0, UD2),
+                    Segment::load(PF_R | PF_W, 0x1000, data),
+                    Segment {
+                        kind: PT_DYNAMIC,
+                        ..Segment::load(PF_R, 0x2000, &dynamic)
+                    },
+                ],
+            )
+        };
+
+        let (space, program) = load_new(&segments(&data)).unwrap();
+        assert_eq!(program.entry, VirtAddr::new(PIE_BASE));
+        assert_eq!(read_u64(&space, PIE_BASE + 0x1000), PIE_BASE + 0x1234);
+
+        // R_X86_64_64 needs a symbol table
+        put(&mut data, 16, &1u64.to_le_bytes());
+        assert_eq!(
+            load_new(&segments(&data)).unwrap_err(),
+            ElfError::UnsupportedRelocation(1)
+        );
+    }
+
+    #[test_case]
+    fn test_rejects_unsupported_dynamic_tags() {
+        let load_with = |tag: u64| {
+            let mut dynamic = Vec::new();
+            for (tag, value) in [(tag, 0x1000), (DT_NULL, 0)] {
+                dynamic.extend_from_slice(&tag.to_le_bytes());
+                dynamic.extend_from_slice(&u64::to_le_bytes(value));
+            }
+            let elf = build(
+                TYPE_DYN,
+                0,
+                &[
+                    Segment::load(PF_R | PF_X, 0, UD2),
+                    Segment {
+                        kind: PT_DYNAMIC,
+                        ..Segment::load(PF_R, 0x1000, &dynamic)
+                    },
+                ],
+            );
+            load_new(&elf).map(|_| ())
+        };
+        assert_eq!(load_with(DT_RELR), Err(ElfError::BadDynamicSection));
+        assert_eq!(load_with(DT_RELRSZ), Err(ElfError::BadDynamicSection));
+        assert_eq!(load_with(DT_NEEDED), Err(ElfError::NeedsInterpreter));
+    }
+
+    #[test_case]
+    fn test_stack_layout() {
+        let space = AddressSpace::new().unwrap();
+        let program = load(&space, &simple(), &["prog", "-v"], &["HOME=/"]).unwrap();
+        let sp = program.stack_pointer.as_u64();
+        assert_eq!(sp % 16, 0);
+        let word = |i: u64| read_u64(&space, sp + i * 8);
+        assert_eq!(word(0), 2);
+        assert_eq!(read_string(&space, word(1)), b"prog");
+        assert_eq!(read_string(&space, word(2)), b"-v");
+        assert_eq!(word(3), 0);
+        assert_eq!(read_string(&space, word(4)), b"HOME=/");
+        assert_eq!(word(5), 0);
+        let auxv: Vec<(u64, u64)> = (0..)
+            .map(|i| (word(6 + 2 * i), word(7 + 2 * i)))
+            .take_while(|&(kind, _)| kind != AT_NULL)
+            .collect();
+        assert!(auxv.contains(&(AT_ENTRY, USER_START)));
+        assert!(auxv.contains(&(AT_PAGESZ, PAGE_SIZE)));
+        assert!(auxv.contains(&(AT_PHNUM, 1)));
+    }
+
+    #[test_case]
+    fn test_run_loaded_program() {
+        const DATA: u64 = USER_START + 0x1000;
+        // stores the argument count in the data segment and exits
+        let mut code = vec![0x48, 0x8b, 0x04, 0x24, 0x48, 0xa3];
+        code.extend_from_slice(&DATA.to_le_bytes());
+        code.push(0xb8);
+        code.extend_from_slice(&(SYS_EXIT as u32).to_le_bytes());
+        code.extend_from_slice(&[0x31, 0xff, 0x0f, 0x05]);
+        let text = Segment::load(PF_R | PF_X, USER_START, &code);
+        let data = Segment::load(PF_R | PF_W, DATA, &[0; 8]);
+        let elf = build(TYPE_EXEC, USER_START, &[text, data]);
+
+        let space = Arc::new(AddressSpace::new().unwrap());
+        let program = load(&space, &elf, &["a", "b", "c"], &[]).unwrap();
+        let thread =
+            super::super::spawn(space.clone(), program.entry, program.stack_pointer).unwrap();
+        assert_eq!(thread.join(), None);
+        assert_eq!(read_u64(&space, DATA), 3);
+    }
+}
@@ -0,0 +1,200 @@
+// Running code in ring 3.
+//
+// A user thread is a kernel thread with an address space of its own, which drops to ring 3
+// with `iretq` once everything is set up. From then on it only comes back into the kernel
+// through interrupts, exceptions and system calls, all of which arrive on its kernel stack.
+// An exception in user mode ends the thread, or the process it belongs to, instead of the
+// kernel.
+
+pub mod elf;
+pub mod syscall;
+
+use crate::gdt;
+use crate::interrupts::TrapFrame;
+use crate::memory::address_space::AddressSpace;
+use crate::memory::paging::MapError;
+use crate::thread::{self, JoinHandle};
+use alloc::sync::Arc;
+use core::arch::asm;
+use x86_64::VirtAddr;
+
+// Interrupts enabled, plus the bit that is always set
+const USER_RFLAGS: u64 = 0x202;
+
+/// Sets up the system call entry. Must run after `gdt::init`.
+pub fn init() {
+    syscall::init();
+}
+
+/// Starts a thread that enters user mode in `address_space` at `entry`, with the stack
+/// pointer at `stack_top`. Both have to be mapped user accessible already. The thread ends
+/// with the exit system call or an exception, so joining it returns `None`.
+pub fn spawn(
+    address_space: Arc<AddressSpace>,
+    entry: VirtAddr,
+    stack_top: VirtAddr,
+) -> Result<JoinHandle<()>, MapError> {
+    thread::spawn_in(address_space, move || {
+        // the caller mapped the code and stack, and this thread runs in their address space
+        unsafe { enter_user_mode(entry, stack_top) }
+    })
+}
+
+/// Drops to ring 3, continuing at `entry` with the stack pointer at `stack_top`. Everything
+/// on the kernel stack is abandoned.
+///
+/// # Safety
+///
+/// The calling thread has to run in a user address space with `entry` and the stack mapped
+/// user accessible.
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    // The frame `iretq` returns through, then every general purpose register cleared, so
+    // nothing of the kernel's leaks into user mode
+    unsafe {
+        asm!(
+            "push {ss}",
+            "push {rsp}",
+            "push {rflags}",
+            "push {cs}",
+            "push {rip}",
+            "xor eax, eax",
+            "xor ebx, ebx",
+            "xor ecx, ecx",
+            "xor edx, edx",
+            "xor esi, esi",
+            "xor edi, edi",
+            "xor ebp, ebp",
+            "xor r8d, r8d",
+            "xor r9d, r9d",
+            "xor r10d, r10d",
+            "xor r11d, r11d",
+            "xor r12d, r12d",
+            "xor r13d, r13d",
+            "xor r14d, r14d",
+            "xor r15d, r15d",
+            "iretq",
+            ss = in(reg) u64::from(selectors.user_data.0),
+            rsp = in(reg) stack_top.as_u64(),
+            rflags = in(reg) USER_RFLAGS,
+            cs = in(reg) u64::from(selectors.user_code.0),
+            rip = in(reg) entry.as_u64(),
+            options(noreturn)
+        );
+    }
+}
+
+/// Whether the frame was saved on entering the kernel from user mode.
+pub fn from_user_mode(frame: &TrapFrame) -> bool {
+    frame.cs & 3 == 3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::address_space::USER_START;
+    use alloc::vec::Vec;
+    use syscall::{SyscallError, SYS_EXIT, SYS_WRITE};
+    use x86_64::structures::paging::PageTableFlags;
+
+    const CODE: u64 = USER_START;
+    const DATA: u64 = USER_START + 0x1000;
+    const STACK_TOP: u64 = USER_START + 0x3000;
+
+    // Maps `code` at `CODE`, a writable data page and a stack page, and runs it
+    fn run(code: &[u8]) -> Arc<AddressSpace> {
+        let space = Arc::new(AddressSpace::new().unwrap());
+        space
+            .map(VirtAddr::new(CODE), 0x1000, PageTableFlags::empty())
+            .unwrap();
+        space.load(VirtAddr::new(CODE), code).unwrap();
+        let writable = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        space.map(VirtAddr::new(DATA), 0x1000, writable).unwrap();
+        space
+            .map(VirtAddr::new(STACK_TOP - 0x1000), 0x1000, writable)
+            .unwrap();
+        let handle = spawn(space.clone(), VirtAddr::new(CODE), VirtAddr::new(STACK_TOP)).unwrap();
+        assert_eq!(handle.join(), None);
+        space
+    }
+
+    fn mov_eax(code: &mut Vec<u8>, value: u32) {
+        code.push(0xb8);
+        code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn store_rax(code: &mut Vec<u8>, addr: u64) {
+        code.extend_from_slice(&[0x48, 0xa3]);
+        code.extend_from_slice(&addr.to_le_bytes());
+    }
+
+    fn syscall(code: &mut Vec<u8>) {
+        code.extend_from_slice(&[0x0f, 0x05]);
+    }
+
+    fn exit(code: &mut Vec<u8>) {
+        mov_eax(code, SYS_EXIT as u32);
+        // xor edi, edi
+        code.extend_from_slice(&[0x31, 0xff]);
+        syscall(code);
+        // ud2, never reached
+        code.extend_from_slice(&[0x0f, 0x0b]);
+    }
+
+    fn read_u64(space: &AddressSpace, addr: u64) -> u64 {
+        let mut buf = [0; 8];
+        space.read(VirtAddr::new(addr), &mut buf).unwrap();
+        u64::from_le_bytes(buf)
+    }
+
+    #[test_case]
+    fn test_user_mode_and_syscall_errors() {
+        let mut code = Vec::new();
+        mov_eax(&mut code, 999);
+        syscall(&mut code);
+        store_rax(&mut code, DATA);
+        // mov eax, cs
+        code.extend_from_slice(&[0x8c, 0xc8]);
+        store_rax(&mut code, DATA + 8);
+        exit(&mut code);
+
+        let space = run(&code);
+        assert_eq!(read_u64(&space, DATA), SyscallError::InvalidSyscall.code());
+        assert_eq!(read_u64(&space, DATA + 8) & 3, 3);
+    }
+
+    #[test_case]
+    fn test_syscall_arguments() {
+        let mut code = Vec::new();
+        // write(STDOUT, buf, 1) with the buffer in the kernel's half
+        mov_eax(&mut code, SYS_WRITE as u32);
+        // mov edi, 1; mov esi, imm32; mov edx, 1
+        code.extend_from_slice(&[0xbf, 1, 0, 0, 0]);
+        code.push(0xbe);
+        code.extend_from_slice(&0x20_0000u32.to_le_bytes());
+        code.extend_from_slice(&[0xba, 1, 0, 0, 0]);
+        syscall(&mut code);
+        store_rax(&mut code, DATA);
+        // and once more with an empty buffer, which writes nothing
+        mov_eax(&mut code, SYS_WRITE as u32);
+        code.extend_from_slice(&[0xba, 0, 0, 0, 0]);
+        syscall(&mut code);
+        store_rax(&mut code, DATA + 8);
+        exit(&mut code);
+
+        let space = run(&code);
+        assert_eq!(read_u64(&space, DATA), SyscallError::BadAddress.code());
+        assert_eq!(read_u64(&space, DATA + 8), 0);
+    }
+
+    #[test_case]
+    fn test_faults_end_the_thread() {
+        // reading kernel memory
+        let mut code = Vec::new();
+        code.extend_from_slice(&[0x48, 0xa1]);
+        code.extend_from_slice(&(run as *const () as u64).to_le_bytes());
+        run(&code);
+        // ud2
+        run(&[0x0f, 0x0b]);
+    }
+}
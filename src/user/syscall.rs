@@ -0,0 +1,404 @@
+// System calls through `syscall`/`sysret`.
+//
+// `syscall` jumps to the address in the LSTAR register with the kernel's code segment, the
+// return address in RCX and the flags in R11, but leaves the stack pointer alone. The entry
+// stub switches to the thread's kernel stack itself and saves the user registers as a
+// `TrapFrame`, the same as an interrupt would, so a thread in a system call can be switched
+// away from like any other.
+//
+// The call number is passed in RAX and up to six arguments in RDI, RSI, RDX, R10, R8 and R9.
+// RCX is taken by the return address, which is why R10 stands in for it. The result comes
+// back in RAX: a negative value is a `SyscallError`, anything else is a success.
+
+use crate::gdt::{self, TSS, TSS_KERNEL_STACK_OFFSET};
+use crate::interrupts::TrapFrame;
+use crate::memory::address_space::UserMemoryError;
+use crate::process::file::FileError;
+use crate::process::{self, ExitStatus, Pid, SpawnError, WaitError};
+use crate::thread;
+use crate::user::elf::ElfError;
+use alloc::vec::Vec;
+use core::arch::naked_asm;
+use core::convert::TryFrom;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+/// Ends the calling process. `exit(status)`
+pub const SYS_EXIT: u64 = 0;
+/// Writes to an open file. `write(fd, buf, len)`, returns the number of bytes written.
+pub const SYS_WRITE: u64 = 1;
+/// Lets other threads run. `yield()`
+pub const SYS_YIELD: u64 = 2;
+/// Blocks for a while. `sleep(ms)`
+pub const SYS_SLEEP: u64 = 3;
+/// The calling process's ID. `getpid()`
+pub const SYS_GETPID: u64 = 4;
+/// The parent process's ID, 0 if there is none. `getppid()`
+pub const SYS_GETPPID: u64 = 5;
+/// Waits for a child to exit. `wait(pid, status)`, where a `pid` of -1 means any child and
+/// `status` may be 0. Returns the child's ID and stores its `ExitStatus::to_raw`.
+pub const SYS_WAIT: u64 = 6;
+/// Closes an open file. `close(fd)`
+pub const SYS_CLOSE: u64 = 7;
+/// Starts the ELF executable in memory as a child process. `spawn(image, len)`, returns
+/// the child's ID.
+pub const SYS_SPAWN: u64 = 8;
+
+// Put into the vector field of the frame, to tell it apart from a real interrupt's
+const SYSCALL_VECTOR: u64 = 0x100;
+
+// Writes are copied out of user memory in chunks of this size
+const WRITE_CHUNK: usize = 256;
+
+// The largest executable `spawn` copies into the kernel
+const SPAWN_IMAGE_MAX: u64 = 4 << 20;
+
+/// Why a system call failed. User code sees the discriminant in RAX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    /// There is no system call with that number.
+    InvalidSyscall = -1,
+    /// A pointer argument doesn't point to memory the caller may access that way.
+    BadAddress = -2,
+    InvalidArgument = -3,
+    BadFileDescriptor = -4,
+    /// The caller has no child that could be waited for.
+    NoChild = -5,
+    OutOfMemory = -6,
+    /// The image given to `spawn` isn't an executable that can be loaded.
+    BadExecutable = -7,
+}
+
+impl SyscallError {
+    /// The value returned in RAX.
+    pub fn code(self) -> u64 {
+        self as i64 as u64
+    }
+}
+
+impl From<UserMemoryError> for SyscallError {
+    fn from(_: UserMemoryError) -> SyscallError {
+        SyscallError::BadAddress
+    }
+}
+
+impl From<FileError> for SyscallError {
+    fn from(_: FileError) -> SyscallError {
+        SyscallError::BadFileDescriptor
+    }
+}
+
+impl From<WaitError> for SyscallError {
+    fn from(error: WaitError) -> SyscallError {
+        match error {
+            WaitError::NoChild => SyscallError::NoChild,
+        }
+    }
+}
+
+impl From<SpawnError> for SyscallError {
+    fn from(error: SpawnError) -> SyscallError {
+        match error {
+            SpawnError::Map(_) | SpawnError::Elf(ElfError::Memory(_)) => SyscallError::OutOfMemory,
+            SpawnError::Elf(ElfError::ArgumentsTooLarge) => SyscallError::InvalidArgument,
+            SpawnError::Elf(_) => SyscallError::BadExecutable,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+
+type SyscallHandler = fn(&[u64; 6]) -> SyscallResult;
+
+// Indexed by the call number
+static SYSCALLS: [SyscallHandler; 9] = [
+    sys_exit,
+    sys_write,
+    sys_yield,
+    sys_sleep,
+    sys_getpid,
+    sys_getppid,
+    sys_wait,
+    sys_close,
+    sys_spawn,
+];
+
+/// Sets up the MSRs for `syscall`. Must run after `gdt::init`, since the entry and return
+/// segments come from the GDT.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("GDT layout doesn't fit syscall/sysret");
+    LStar::write(VirtAddr::from_ptr(syscall_entry as *const ()));
+    // The entry runs on the user's stack until it has switched, so nothing may interrupt it
+    // there. Direction and trap flags are user state the kernel must not inherit.
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    // the entry point is set up
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+// The user's stack pointer, parked while the entry switches stacks. There is one CPU, and
+// interrupts stay masked until it is saved in the frame.
+static mut USER_RSP: u64 = 0;
+
+// Builds a `TrapFrame` on the kernel stack from the registers `syscall` left behind, calls
+// `handle_syscall` with it and returns with `sysret`. The segment selectors in the frame are
+// the ones `sysret` will load. Like in `interrupt_common`, the 22 quadwords keep the stack
+// 16 byte aligned.
+#[unsafe(naked)]
+extern "C" fn syscall_entry() {
+    naked_asm!(
+        "mov [rip + {user_rsp}], rsp",
+        "mov rsp, [rip + {tss} + {kernel_stack}]",
+        "push {user_ss}",
+        "push [rip + {user_rsp}]",
+        "push r11",
+        "push {user_cs}",
+        "push rcx",
+        "push 0",
+        "push {vector}",
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "call {handler}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        // drop the vector and error code, then take RIP, RFLAGS and RSP from the CPU's part
+        // of the frame, as `sysret` expects them
+        "add rsp, 16",
+        "pop rcx",
+        "add rsp, 8",
+        "pop r11",
+        "pop rsp",
+        "sysretq",
+        user_rsp = sym USER_RSP,
+        tss = sym TSS,
+        kernel_stack = const TSS_KERNEL_STACK_OFFSET,
+        user_ss = const USER_DATA_SELECTOR,
+        user_cs = const USER_CODE_SELECTOR,
+        vector = const SYSCALL_VECTOR,
+        handler = sym handle_syscall,
+    );
+}
+
+// The selectors `sysret` loads, derived from the kernel code selector in STAR: the user data
+// segment comes 8 bytes after it, the user code segment 16 bytes, both with RPL 3. These are
+// entries 3 and 4 of the GDT.
+const USER_DATA_SELECTOR: u64 = (3 << 3) | 3;
+const USER_CODE_SELECTOR: u64 = (4 << 3) | 3;
+
+extern "C" fn handle_syscall(frame: &mut TrapFrame) {
+    // we are on the kernel stack now, and a long call shouldn't hold up the timer
+    interrupts::enable();
+    let args = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+    frame.rax = dispatch(frame.rax, &args);
+    // the exit path switches back to the user's stack
+    interrupts::disable();
+}
+
+/// Runs system call `number` and returns what goes into RAX.
+pub fn dispatch(number: u64, args: &[u64; 6]) -> u64 {
+    let result = match SYSCALLS.get(number as usize) {
+        Some(handler) => handler(args),
+        None => Err(SyscallError::InvalidSyscall),
+    };
+    match result {
+        Ok(value) => value,
+        Err(error) => error.code(),
+    }
+}
+
+fn user_addr(addr: u64) -> Result<VirtAddr, SyscallError> {
+    VirtAddr::try_new(addr).map_err(|_| SyscallError::BadAddress)
+}
+
+fn file_descriptor(fd: u64) -> Result<usize, SyscallError> {
+    usize::try_from(fd).map_err(|_| SyscallError::BadFileDescriptor)
+}
+
+fn sys_exit(args: &[u64; 6]) -> SyscallResult {
+    process::exit(ExitStatus::Exited(args[0] as i32));
+}
+
+fn sys_write(args: &[u64; 6]) -> SyscallResult {
+    let [fd, buf, len, ..] = *args;
+    let file = process::file(file_descriptor(fd)?).ok_or(SyscallError::BadFileDescriptor)?;
+    let space = thread::address_space().ok_or(SyscallError::BadAddress)?;
+    let end = buf.checked_add(len).ok_or(SyscallError::InvalidArgument)?;
+    let mut chunk = [0; WRITE_CHUNK];
+    // bytes of a character cut off at the end of the last chunk
+    let mut carried = 0;
+    let mut addr = buf;
+    while addr < end {
+        let size = ((end - addr) as usize).min(WRITE_CHUNK - carried);
+        space.read(user_addr(addr)?, &mut chunk[carried..carried + size])?;
+        addr += size as u64;
+        let filled = carried + size;
+        // the console decodes each write on its own, so a character has to be in one piece
+        carried = match addr < end {
+            true => incomplete_utf8_tail(&chunk[..filled]),
+            false => 0,
+        };
+        file.write(&chunk[..filled - carried])?;
+        chunk.copy_within(filled - carried..filled, 0);
+    }
+    Ok(len)
+}
+
+// How many bytes at the end of `bytes` belong to a UTF-8 character that continues after them
+fn incomplete_utf8_tail(bytes: &[u8]) -> usize {
+    // characters are at most 4 bytes long
+    for len in 1..=bytes.len().min(3) {
+        let first = bytes[bytes.len() - len];
+        // continuation bytes are 0b10xxxxxx, anything else starts a character
+        if first & 0xc0 != 0x80 {
+            let needed = match first {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            return if needed > len { len } else { 0 };
+        }
+    }
+    0
+}
+
+fn sys_yield(_: &[u64; 6]) -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+fn sys_sleep(args: &[u64; 6]) -> SyscallResult {
+    thread::sleep_ms(args[0]);
+    Ok(0)
+}
+
+fn sys_getpid(_: &[u64; 6]) -> SyscallResult {
+    Ok(process::current().map_or(0, Pid::as_u64))
+}
+
+fn sys_getppid(_: &[u64; 6]) -> SyscallResult {
+    Ok(process::parent().map_or(0, Pid::as_u64))
+}
+
+fn sys_wait(args: &[u64; 6]) -> SyscallResult {
+    let [pid, status, ..] = *args;
+    let pid = match pid as i64 {
+        -1 => None,
+        pid => Some(Pid::from_u64(pid as u64)),
+    };
+    let (child, exit_status) = process::wait(pid)?;
+    if status != 0 {
+        let space = thread::address_space().ok_or(SyscallError::BadAddress)?;
+        space.write(user_addr(status)?, &exit_status.to_raw().to_le_bytes())?;
+    }
+    Ok(child.as_u64())
+}
+
+fn sys_close(args: &[u64; 6]) -> SyscallResult {
+    match process::close(file_descriptor(args[0])?) {
+        true => Ok(0),
+        false => Err(SyscallError::BadFileDescriptor),
+    }
+}
+
+fn sys_spawn(args: &[u64; 6]) -> SyscallResult {
+    let [image, len, ..] = *args;
+    if len > SPAWN_IMAGE_MAX {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let space = thread::address_space().ok_or(SyscallError::BadAddress)?;
+    // user code picks the size, so running out of heap must not panic the kernel
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len as usize)
+        .map_err(|_| SyscallError::OutOfMemory)?;
+    buf.resize(len as usize, 0);
+    space.read(user_addr(image)?, &mut buf)?;
+    // the copy is only needed until the image is loaded
+    let pid = process::spawn(&buf, &[], &[])?;
+    Ok(pid.as_u64())
+}
+
+#[test_case]
+fn test_user_selectors() {
+    let selectors = gdt::selectors();
+    assert_eq!(u64::from(selectors.user_data.0), USER_DATA_SELECTOR);
+    assert_eq!(u64::from(selectors.user_code.0), USER_CODE_SELECTOR);
+}
+
+#[test_case]
+fn test_incomplete_utf8_tail() {
+    let text = "a\u{e9}\u{20ac}\u{1f600}".as_bytes();
+    assert_eq!(incomplete_utf8_tail(text), 0);
+    // cut into the 4 byte character
+    for cut in 1..4 {
+        assert_eq!(incomplete_utf8_tail(&text[..text.len() - cut]), 4 - cut);
+    }
+    assert_eq!(incomplete_utf8_tail(&text[..2]), 1);
+    assert_eq!(incomplete_utf8_tail(b""), 0);
+    // invalid bytes are left to the console
+    assert_eq!(incomplete_utf8_tail(&[0x80, 0x80, 0x80]), 0);
+}
+
+#[test_case]
+fn test_dispatch_errors() {
+    use crate::process::file::STDOUT;
+
+    assert_eq!(
+        dispatch(SYSCALLS.len() as u64, &[0; 6]),
+        SyscallError::InvalidSyscall.code()
+    );
+    // from a kernel thread, which has no user memory
+    assert_eq!(
+        dispatch(SYS_WRITE, &[STDOUT as u64, 0x1000, 1, 0, 0, 0]),
+        SyscallError::BadAddress.code()
+    );
+    assert_eq!(
+        dispatch(SYS_WRITE, &[7, 0, 0, 0, 0, 0]),
+        SyscallError::BadFileDescriptor.code()
+    );
+    assert_eq!(dispatch(SYS_YIELD, &[0; 6]), 0);
+    // kernel threads aren't processes, and didn't start any here
+    assert_eq!(dispatch(SYS_GETPID, &[0; 6]), 0);
+    assert_eq!(
+        dispatch(SYS_CLOSE, &[STDOUT as u64, 0, 0, 0, 0, 0]),
+        SyscallError::BadFileDescriptor.code()
+    );
+}
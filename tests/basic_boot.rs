@@ -0,0 +1,31 @@
+// Boots straight into the tests, without any of the kernel's setup, to check that the
+// drivers work on the state the bootloader leaves behind.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(focus_os::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use focus_os::{println, serial_println};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    focus_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    focus_os::testing::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_println() {
+    println!("test_println output");
+}
+
+#[test_case]
+fn test_serial_println() {
+    serial_println!("test_serial_println output");
+}
@@ -0,0 +1,41 @@
+// Takes two locks in one order and then in the other. That could deadlock with two threads
+// doing it at the same time, which the lock order checks of debug builds must catch even
+// though there is only one thread here.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(focus_os::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use core::panic::PanicInfo;
+use focus_os::sync::{Mutex, SpinLock};
+use focus_os::testing::ShouldPanic;
+
+#[no_mangle]
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    focus_os::init(boot_info);
+    test_main();
+    focus_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    focus_os::testing::test_panic_handler(info)
+}
+
+static FIRST: Mutex<u32> = Mutex::new(0);
+static SECOND: SpinLock<u32> = SpinLock::new(0);
+
+fn inverted_lock_order() {
+    {
+        let _first = FIRST.lock();
+        let _second = SECOND.lock();
+    }
+    let _second = SECOND.lock();
+    let _first = FIRST.lock();
+}
+
+#[test_case]
+static INVERTED_LOCK_ORDER: ShouldPanic =
+    ShouldPanic::new("lock_order::inverted_lock_order", inverted_lock_order);
@@ -0,0 +1,25 @@
+// Checks that a failed assertion ends up in the test panic handler and that the runner
+// counts it as a pass when the test expects it.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(focus_os::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use focus_os::testing::ShouldPanic;
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    focus_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    focus_os::testing::test_panic_handler(info)
+}
+
+#[test_case]
+static FAILED_ASSERTION: ShouldPanic =
+    ShouldPanic::new("should_panic::failed_assertion", || assert_eq!(0, 1));
@@ -0,0 +1,38 @@
+// Overflows the kernel stack. The resulting page fault can't push its frame, so the CPU
+// raises a double fault, which must run on its IST stack and end up in the panic handler
+// instead of triple faulting.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(focus_os::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::BootInfo;
+use core::panic::PanicInfo;
+use focus_os::testing::ShouldPanic;
+
+#[no_mangle]
+pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    focus_os::init(boot_info);
+    test_main();
+    focus_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    focus_os::testing::test_panic_handler(info)
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    // keeps the recursion from being turned into a loop
+    let zero = 0;
+    unsafe {
+        core::ptr::read_volatile(&zero);
+    }
+}
+
+#[test_case]
+static STACK_OVERFLOW: ShouldPanic =
+    ShouldPanic::new("stack_overflow::stack_overflow", stack_overflow);